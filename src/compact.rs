@@ -0,0 +1,388 @@
+use anyhow::{anyhow, Result};
+use miniz_oxide::deflate::compress_to_vec;
+use miniz_oxide::inflate::decompress_to_vec_with_limit;
+
+use crate::flake::Particle;
+use crate::point::Point;
+
+// How hard to compress blocks, from 0 to 10.
+const COMPRESSION_LEVEL: u8 = 6;
+
+// The most bytes a particle can take before compression.
+const MAX_PARTICLE_SIZE: usize = 2 + 10 + 16 + 10 + 8;
+
+// Bits of the flags byte of each particle.
+const HAS_PARENT: u8 = 1;
+// The position is stored as f32 offsets from the parent, rather than f64
+// coordinates.
+const RELATIVE: u8 = 2;
+const HAS_TIME: u8 = 4;
+
+// Particles in the compact encoding, used by newer flake files when asked
+// for. Neighbouring particles are always about a diameter apart, so each
+// particle whose parent came before it stores its position as an f32 offset
+// from its parent. Particles are grouped by field, so that similar values are
+// next to each other, then compressed.
+//
+// Offsets are from the parent's position as decoded, so the error from
+// rounding them doesn't build up along a branch. Only parents in the same
+// block are used, so that each block can be decoded on its own: a particle
+// whose parent is in an earlier block stores its f64 coordinates instead,
+// which makes a grown flake around 40% bigger than offsets from any earlier
+// particle would, but means reading and writing never need the positions of
+// earlier blocks.
+//
+// A block is laid out as:
+// - Each particle's flags byte
+// - Each particle's species byte
+// - For particles with a parent, the difference between its index and the
+//   parent's, as a zigzag varint
+// - Each particle's x, then each particle's y, as a big-endian f32 offset
+//   if relative or an f64 otherwise
+// - Each particle's number of steps, as a varint
+// - For particles with a time, the big-endian f64 time
+
+// Returns the most bytes an encoded block of the given number of particles
+// can take, allowing for data that doesn't compress.
+pub fn max_block_size(num_particles: usize) -> usize {
+    return num_particles * MAX_PARTICLE_SIZE + 1024;
+}
+
+// Encode a block of particles, the first of which has the given index in the
+// flake.
+pub fn encode_block(particles: &[Particle], first_index: u64) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(particles.len() * MAX_PARTICLE_SIZE);
+    let mut parents = Vec::with_capacity(particles.len());
+    for (i, particle) in particles.iter().enumerate() {
+        let index = first_index + i as u64;
+        let parent = particle.parent.filter(|parent| *parent >= first_index && *parent < index);
+        parents.push(parent);
+
+        let mut flags = 0;
+        if particle.parent.is_some() {
+            flags |= HAS_PARENT;
+        }
+        if parent.is_some() {
+            flags |= RELATIVE;
+        }
+        if particle.time.is_some() {
+            flags |= HAS_TIME;
+        }
+        buf.push(flags);
+    }
+    for particle in particles {
+        buf.push(particle.species);
+    }
+    for (i, particle) in particles.iter().enumerate() {
+        if let Option::Some(parent) = particle.parent {
+            let index = first_index + i as u64;
+            write_varint(zigzag(index as i64 - parent as i64), &mut buf);
+        }
+    }
+
+    // Work out where each particle will be decoded to, so later particles
+    // can be stored relative to it
+    let mut positions: Vec<Point> = Vec::with_capacity(particles.len());
+    let mut offsets = Vec::with_capacity(particles.len());
+    for (particle, parent) in particles.iter().zip(&parents) {
+        match parent {
+            Option::Some(parent) => {
+                let origin = positions[(*parent - first_index) as usize];
+                let dx = (particle.point.x - origin.x) as f32;
+                let dy = (particle.point.y - origin.y) as f32;
+                positions.push(Point {
+                    x: origin.x + dx as f64,
+                    y: origin.y + dy as f64,
+                });
+                offsets.push((dx, dy));
+            }
+            Option::None => {
+                positions.push(particle.point);
+                offsets.push((0.0, 0.0));
+            }
+        }
+    }
+    for axis in 0..2 {
+        for ((particle, parent), offset) in particles.iter().zip(&parents).zip(&offsets) {
+            let offset = if axis == 0 { offset.0 } else { offset.1 };
+            let coordinate = if axis == 0 {
+                particle.point.x
+            } else {
+                particle.point.y
+            };
+            if parent.is_some() {
+                buf.extend_from_slice(&offset.to_be_bytes());
+            } else {
+                buf.extend_from_slice(&coordinate.to_be_bytes());
+            }
+        }
+    }
+
+    for particle in particles {
+        write_varint(particle.steps, &mut buf);
+    }
+    for particle in particles {
+        if let Option::Some(time) = particle.time {
+            buf.extend_from_slice(&time.to_be_bytes());
+        }
+    }
+
+    return compress_to_vec(&buf, COMPRESSION_LEVEL);
+}
+
+// Read a block written by encode_block holding the given number of
+// particles, the first of which has the given index, adding them to the
+// given list.
+pub fn decode_block(
+    block: &[u8],
+    num_particles: usize,
+    first_index: u64,
+    particles: &mut Vec<Particle>,
+) -> Result<()> {
+    let buf = decompress_to_vec_with_limit(block, num_particles * MAX_PARTICLE_SIZE)
+        .map_err(|status| anyhow!("Unable to decompress block: {:?}", status))?;
+    let mut reader = Reader {
+        buf: &buf,
+        position: 0,
+    };
+
+    let flags = reader.bytes(num_particles)?;
+    let species = reader.bytes(num_particles)?;
+    let start = particles.len();
+    for i in 0..num_particles {
+        let mut particle = Particle::new(Point::ZERO);
+        particle.species = species[i];
+        if flags[i] & HAS_PARENT != 0 {
+            let index = first_index + i as u64;
+            let parent = index as i64 - unzigzag(reader.varint()?);
+            let in_block = parent as u64 >= first_index && (parent as u64) < index;
+            if parent < 0 || (flags[i] & RELATIVE != 0 && !in_block) {
+                return Result::Err(anyhow!("Invalid parent {} of particle {}", parent, index));
+            }
+            particle.parent = Option::Some(parent as u64);
+        } else if flags[i] & RELATIVE != 0 {
+            return Result::Err(anyhow!(
+                "Particle {} has an offset but no parent",
+                first_index + i as u64
+            ));
+        }
+        particles.push(particle);
+    }
+    // Parents always come first, so each coordinate of a parent is decoded
+    // before those of its children
+    for axis in 0..2 {
+        for i in 0..num_particles {
+            let coordinate = if flags[i] & RELATIVE != 0 {
+                let parent = particles[start + i].parent.unwrap();
+                let origin = particles[start + (parent - first_index) as usize].point;
+                let offset = f32::from_be_bytes(reader.array()?) as f64;
+                if axis == 0 {
+                    origin.x + offset
+                } else {
+                    origin.y + offset
+                }
+            } else {
+                f64::from_be_bytes(reader.array()?)
+            };
+            if axis == 0 {
+                particles[start + i].point.x = coordinate;
+            } else {
+                particles[start + i].point.y = coordinate;
+            }
+        }
+    }
+
+    for i in 0..num_particles {
+        particles[start + i].steps = reader.varint()?;
+    }
+    for i in 0..num_particles {
+        if flags[i] & HAS_TIME != 0 {
+            particles[start + i].time = Option::Some(f64::from_be_bytes(reader.array()?));
+        }
+    }
+    if !reader.is_finished() {
+        return Result::Err(anyhow!("Unexpected data at the end of block"));
+    }
+    return Result::Ok(());
+}
+
+fn zigzag(value: i64) -> u64 {
+    return ((value << 1) ^ (value >> 63)) as u64;
+}
+
+fn unzigzag(value: u64) -> i64 {
+    return (value >> 1) as i64 ^ -((value & 1) as i64);
+}
+
+// Append the value seven bits at a time, lowest first, with the top bit of
+// each byte set if more follow.
+fn write_varint(mut value: u64, buf: &mut Vec<u8>) {
+    while value >= 0x80 {
+        buf.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+// Reads values in turn from a decompressed block.
+struct Reader<'a> {
+    buf: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn is_finished(&self) -> bool {
+        return self.position == self.buf.len();
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.buf.len() - self.position < len {
+            return Result::Err(anyhow!("Block is truncated"));
+        }
+        let bytes = &self.buf[self.position..self.position + len];
+        self.position += len;
+        return Result::Ok(bytes);
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.bytes(N)?);
+        return Result::Ok(array);
+    }
+
+    fn varint(&mut self) -> Result<u64> {
+        let mut value: u64 = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.bytes(1)?[0];
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Result::Ok(value);
+            }
+        }
+        return Result::Err(anyhow!("Varint is too long"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use super::{decode_block, encode_block, unzigzag, write_varint, zigzag, Reader};
+    use crate::flake::Particle;
+    use crate::point::Point;
+
+    // Particles each stuck about a diameter from a random earlier particle,
+    // with a few unattached ones far away.
+    fn random_particles(num_particles: usize, rng: &mut StdRng) -> Vec<Particle> {
+        let mut particles: Vec<Particle> = vec![Particle::new(Point::ZERO)];
+        for i in 1..num_particles {
+            if i % 100 == 0 {
+                particles.push(Particle {
+                    time: Option::Some(i as f64),
+                    ..Particle::new(Point {
+                        x: rng.gen_range(-1.0e6..1.0e6),
+                        y: rng.gen_range(-1.0e6..1.0e6),
+                    })
+                });
+                continue;
+            }
+            let parent = rng.gen_range(0..i);
+            let angle: f64 = rng.gen_range(0.0..std::f64::consts::PI * 2.0);
+            let origin = particles[parent].point;
+            particles.push(Particle {
+                species: rng.gen_range(0..3),
+                parent: Option::Some(parent as u64),
+                steps: rng.gen_range(0..1_000_000),
+                ..Particle::new(Point {
+                    x: origin.x + angle.cos(),
+                    y: origin.y + angle.sin(),
+                })
+            });
+        }
+        return particles;
+    }
+
+    #[test]
+    fn roundtrip_blocks() {
+        let mut rng = StdRng::seed_from_u64(79);
+        let particles = random_particles(3000, &mut rng);
+
+        let blocks: Vec<Vec<u8>> = particles
+            .chunks(1000)
+            .enumerate()
+            .map(|(i, block)| encode_block(block, i as u64 * 1000))
+            .collect();
+
+        let mut decoded = Vec::new();
+        for (i, block) in blocks.iter().enumerate() {
+            decode_block(block, 1000, i as u64 * 1000, &mut decoded).expect("Unable to decode");
+        }
+
+        assert_eq!(particles.len(), decoded.len());
+        for i in 0..particles.len() {
+            let (expected, actual) = (&particles[i], &decoded[i]);
+            assert_eq!(expected.species, actual.species);
+            assert_eq!(expected.parent, actual.parent);
+            assert_eq!(expected.steps, actual.steps);
+            assert_eq!(expected.time, actual.time);
+            // Offsets are rounded to f32, without the error building up
+            assert!(expected.point.distance(&actual.point) < 1e-5);
+        }
+        assert_eq!(particles[100].point.x, decoded[100].point.x);
+
+        // Each block can be decoded without the ones before it
+        let mut later = Vec::new();
+        decode_block(&blocks[2], 1000, 2000, &mut later).expect("Unable to decode");
+        for i in 0..1000 {
+            assert_eq!(decoded[2000 + i].point.x, later[i].point.x);
+            assert_eq!(decoded[2000 + i].point.y, later[i].point.y);
+        }
+
+        // The encoding is well under half of 41 bytes per particle, even with
+        // most parents in earlier blocks
+        let encoded_len: usize = blocks.iter().map(|block| block.len()).sum();
+        assert!(encoded_len * 2 < particles.len() * 41);
+    }
+
+    #[test]
+    fn decode_invalid_blocks() {
+        let mut rng = StdRng::seed_from_u64(83);
+        let particles = random_particles(100, &mut rng);
+        let block = encode_block(&particles, 0);
+
+        // The wrong number of particles, or parents before the first particle
+        assert!(decode_block(&block, 99, 0, &mut Vec::new()).is_err());
+        assert!(decode_block(&block, 101, 0, &mut Vec::new()).is_err());
+        let later = encode_block(&particles[50..], 50);
+        assert!(decode_block(&later, 50, 50, &mut Vec::new()).is_ok());
+        assert!(decode_block(&later, 50, 0, &mut Vec::new()).is_err());
+        assert!(decode_block(&block[..block.len() - 1], 100, 0, &mut Vec::new()).is_err());
+        assert!(decode_block(b"not a block", 100, 0, &mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn varints() {
+        let values = [0, 1, 127, 128, 300, u64::MAX];
+        let mut buf = Vec::new();
+        for value in values.iter() {
+            write_varint(*value, &mut buf);
+        }
+        assert_eq!(1 + 1 + 1 + 2 + 2 + 10, buf.len());
+
+        let mut reader = Reader {
+            buf: &buf,
+            position: 0,
+        };
+        for value in values.iter() {
+            assert_eq!(*value, reader.varint().unwrap());
+        }
+        assert_eq!(true, reader.is_finished());
+        assert!(reader.varint().is_err());
+
+        for value in [0, 1, -1, i64::MAX, i64::MIN].iter() {
+            assert_eq!(*value, unzigzag(zigzag(*value)));
+        }
+        assert_eq!(1, zigzag(-1));
+    }
+}
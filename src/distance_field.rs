@@ -0,0 +1,320 @@
+use std::collections::HashMap;
+use std::hash::{BuildHasherDefault, Hasher};
+
+use anyhow::{anyhow, Result};
+
+use crate::index::Decoder;
+use crate::point::Point;
+
+// The width of each cell of the grid.
+const CELL_SIZE: f64 = 4.0;
+
+// Bounds are only tracked up to this distance. Any cell farther than this
+// from every point holds this value, which keeps updates cheap.
+const MAX_BOUND: f64 = 32.0;
+
+// The width of each tile of cells, in cells.
+const TILE_CELLS: i64 = 16;
+const CELLS_PER_TILE: usize = (TILE_CELLS * TILE_CELLS) as usize;
+
+// A coarse grid holding for each cell a lower bound on the distance from
+// anywhere in that cell to the nearest point. Looking up a bound is much
+// cheaper than a nearest point query, so walkers far from the flake can use
+// it to take large steps. The grid is split into square tiles of cells, and
+// only tiles with a cell within MAX_BOUND of a point are stored, so the field
+// stays small however far apart the points are.
+#[derive(Clone)]
+pub struct DistanceField {
+    // Keyed by the coordinates of the tile. Cells are stored row by row.
+    tiles: HashMap<(i64, i64), Box<[f64; CELLS_PER_TILE]>, BuildHasherDefault<TileHasher>>,
+}
+
+// A hasher for tile keys. Every step of every walk looks up a tile, and the
+// default hasher roughly doubles the time taken to generate a flake.
+#[derive(Default)]
+struct TileHasher {
+    hash: u64,
+}
+
+impl Hasher for TileHasher {
+    fn finish(&self) -> u64 {
+        return self.hash;
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.write_u64(*byte as u64);
+        }
+    }
+
+    fn write_u64(&mut self, value: u64) {
+        self.hash = (self.hash.rotate_left(5) ^ value).wrapping_mul(0x517c_c1b7_2722_0a95);
+    }
+
+    fn write_i64(&mut self, value: i64) {
+        self.write_u64(value as u64);
+    }
+}
+
+impl DistanceField {
+    pub fn new() -> DistanceField {
+        return DistanceField {
+            tiles: HashMap::default(),
+        };
+    }
+
+    // Make a field for many points at once. Points are grouped by the cell
+    // they fall in, then each occupied cell updates the cells around it once
+    // using the bounding box of its points. This is much faster than adding
+    // the points one at a time when cells hold several points, at the cost of
+    // slightly looser bounds.
+    pub fn from_points(points: &[Point]) -> DistanceField {
+        let mut field = DistanceField::new();
+        let mut boxes: HashMap<(i64, i64), (Point, Point)> = HashMap::new();
+        for point in points {
+            let cell = (cell_of(point.x), cell_of(point.y));
+            let (min, max) = boxes.entry(cell).or_insert((*point, *point));
+            min.x = min.x.min(point.x);
+            min.y = min.y.min(point.y);
+            max.x = max.x.max(point.x);
+            max.y = max.y.max(point.y);
+        }
+
+        for ((cell_x, cell_y), (min, max)) in boxes.iter() {
+            field.update_around(*cell_x, *cell_y, min, max);
+        }
+        return field;
+    }
+
+    // Append the field to the given buffer, in the form read by decode.
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        // Write the tiles in order so that the same field is always encoded
+        // the same way
+        let mut keys: Vec<&(i64, i64)> = self.tiles.keys().collect();
+        keys.sort();
+        buf.extend_from_slice(&(keys.len() as u64).to_be_bytes());
+        for key in keys {
+            buf.extend_from_slice(&key.0.to_be_bytes());
+            buf.extend_from_slice(&key.1.to_be_bytes());
+            for bound in self.tiles[key].iter() {
+                buf.extend_from_slice(&bound.to_be_bytes());
+            }
+        }
+    }
+
+    // Read a field written by encode.
+    pub fn decode(decoder: &mut Decoder) -> Result<DistanceField> {
+        let num_tiles = decoder.u64()?;
+        let tile_size = 16 + 8 * CELLS_PER_TILE as u64;
+        if num_tiles > decoder.remaining() as u64 / tile_size {
+            return Result::Err(anyhow!("Invalid distance field size {}", num_tiles));
+        }
+        let mut field = DistanceField::new();
+        for _i in 0..num_tiles {
+            let key = (decoder.u64()? as i64, decoder.u64()? as i64);
+            let mut tile = Box::new([0.0; CELLS_PER_TILE]);
+            for bound in tile.iter_mut() {
+                *bound = decoder.f64()?;
+                if !(0.0..=MAX_BOUND).contains(bound) {
+                    return Result::Err(anyhow!("Invalid distance bound {}", bound));
+                }
+            }
+            if field.tiles.insert(key, tile).is_some() {
+                return Result::Err(anyhow!("Distance field tile {:?} is repeated", key));
+            }
+        }
+        return Result::Ok(field);
+    }
+
+    // Returns the largest bound the field will report.
+    pub fn max_bound(&self) -> f64 {
+        return MAX_BOUND;
+    }
+
+    // Returns a lower bound on the distance from the given point to the
+    // nearest point in the field, up to max_bound.
+    pub fn get_lower_bound(&self, point: &Point) -> f64 {
+        let (key, i) = tile_of(cell_of(point.x), cell_of(point.y));
+        // Tiles are made for every cell within MAX_BOUND of a point, so
+        // anywhere without one is at least that far from them all.
+        return match self.tiles.get(&key) {
+            Option::Some(tile) => tile[i],
+            Option::None => MAX_BOUND,
+        };
+    }
+
+    pub fn add_point(&mut self, point: &Point) {
+        self.update_around(cell_of(point.x), cell_of(point.y), point, point);
+    }
+
+    // Lower the bounds of the cells near the given one, to account for
+    // points somewhere within the given box inside it.
+    fn update_around(&mut self, cell_x: i64, cell_y: i64, min: &Point, max: &Point) {
+        let reach = (MAX_BOUND / CELL_SIZE).ceil() as i64 + 1;
+        for y in cell_y - reach..=cell_y + reach {
+            for x in cell_x - reach..=cell_x + reach {
+                let distance = cell_distance(x, y, min, max);
+                if distance < MAX_BOUND {
+                    let (key, i) = tile_of(x, y);
+                    let tile = self
+                        .tiles
+                        .entry(key)
+                        .or_insert_with(|| Box::new([MAX_BOUND; CELLS_PER_TILE]));
+                    tile[i] = tile[i].min(distance);
+                }
+            }
+        }
+    }
+}
+
+// Returns the coordinate of the cell containing the given coordinate.
+fn cell_of(coordinate: f64) -> i64 {
+    return (coordinate / CELL_SIZE).floor() as i64;
+}
+
+// Returns the key of the tile holding the given cell, and the cell's index
+// within the tile.
+fn tile_of(x: i64, y: i64) -> ((i64, i64), usize) {
+    let key = (x.div_euclid(TILE_CELLS), y.div_euclid(TILE_CELLS));
+    let i = x.rem_euclid(TILE_CELLS) + y.rem_euclid(TILE_CELLS) * TILE_CELLS;
+    return (key, i as usize);
+}
+
+// Returns the distance from the box with the given corners to the nearest
+// part of a cell.
+fn cell_distance(x: i64, y: i64, min: &Point, max: &Point) -> f64 {
+    let left = x as f64 * CELL_SIZE;
+    let bottom = y as f64 * CELL_SIZE;
+    let dx = (left - max.x).max(min.x - (left + CELL_SIZE)).max(0.0);
+    let dy = (bottom - max.y).max(min.y - (bottom + CELL_SIZE)).max(0.0);
+    return (dx * dx + dy * dy).sqrt();
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use super::{DistanceField, CELL_SIZE, MAX_BOUND};
+    use crate::point::Point;
+
+    #[test]
+    fn empty_field() {
+        let field = DistanceField::new();
+        assert_eq!(MAX_BOUND, field.get_lower_bound(&Point::ZERO));
+        assert_eq!(MAX_BOUND, field.get_lower_bound(&Point { x: 1e6, y: -1e6 }));
+    }
+
+    #[test]
+    fn single_point() {
+        let mut field = DistanceField::new();
+        field.add_point(&Point { x: 1.0, y: 1.0 });
+
+        assert_eq!(0.0, field.get_lower_bound(&Point { x: 1.0, y: 1.0 }));
+        assert_eq!(0.0, field.get_lower_bound(&Point { x: 3.0, y: 3.0 }));
+        assert_eq!(
+            3.0 * CELL_SIZE - 1.0,
+            field.get_lower_bound(&Point { x: 13.0, y: 1.0 })
+        );
+        assert_eq!(MAX_BOUND, field.get_lower_bound(&Point { x: 100.0, y: 1.0 }));
+    }
+
+    #[test]
+    fn bounds_are_conservative() {
+        let mut rng = StdRng::seed_from_u64(11);
+        let mut points: Vec<Point> = Vec::new();
+        let mut field = DistanceField::new();
+
+        // Points spread out along random rays, so that they land in many
+        // tiles, some far from any other point. Bounds anywhere, including
+        // in tiles that aren't stored, must never be more than the distance
+        // to the nearest point
+        for i in 0..500 {
+            let radius = i as f64;
+            let angle: f64 = rng.gen_range(0.0..6.3);
+            let point = Point {
+                x: angle.cos() * radius,
+                y: angle.sin() * radius,
+            };
+            field.add_point(&point);
+            points.push(point);
+        }
+
+        for _i in 0..5000 {
+            let query_point = Point {
+                x: rng.gen_range(-600.0..600.0),
+                y: rng.gen_range(-600.0..600.0),
+            };
+            let nearest = points
+                .iter()
+                .map(|point| point.distance(&query_point))
+                .fold(f64::INFINITY, f64::min);
+            let bound = field.get_lower_bound(&query_point);
+            assert!(bound <= nearest);
+            assert!(bound <= MAX_BOUND);
+        }
+    }
+
+    #[test]
+    fn from_points_bounds_are_conservative() {
+        let mut rng = StdRng::seed_from_u64(13);
+        let mut points: Vec<Point> = Vec::new();
+        for _i in 0..2000 {
+            points.push(Point {
+                x: rng.gen_range(-200.0..200.0),
+                y: rng.gen_range(-20.0..20.0),
+            });
+        }
+        let field = DistanceField::from_points(&points);
+
+        let mut single = DistanceField::new();
+        for point in &points {
+            single.add_point(point);
+        }
+
+        for _i in 0..5000 {
+            let query_point = Point {
+                x: rng.gen_range(-300.0..300.0),
+                y: rng.gen_range(-300.0..300.0),
+            };
+            let nearest = points
+                .iter()
+                .map(|point| point.distance(&query_point))
+                .fold(f64::INFINITY, f64::min);
+            let bound = field.get_lower_bound(&query_point);
+            assert!(bound <= nearest);
+            assert!(bound <= single.get_lower_bound(&query_point));
+            // Grouping points by cell loosens bounds by less than the
+            // length of a cell's diagonal
+            assert!(bound >= single.get_lower_bound(&query_point) - CELL_SIZE * 1.5);
+        }
+
+        assert_eq!(MAX_BOUND, DistanceField::from_points(&[]).get_lower_bound(&Point::ZERO));
+    }
+
+    #[test]
+    fn distant_points() {
+        // Only the cells near each point are stored, however far apart
+        // the points are
+        let points = [Point::ZERO, Point { x: 1e6, y: 0.0 }, Point { x: -3e9, y: 7e9 }];
+        let field = DistanceField::from_points(&points);
+        let mut single = DistanceField::new();
+        for point in &points {
+            single.add_point(point);
+        }
+
+        for field in [&field, &single].iter() {
+            assert_eq!(true, field.tiles.len() <= points.len() * 4);
+            for point in &points {
+                assert_eq!(0.0, field.get_lower_bound(point));
+                let nearby = Point {
+                    x: point.x - 10.0,
+                    y: point.y,
+                };
+                assert_eq!(true, field.get_lower_bound(&nearby) > 0.0);
+                assert_eq!(true, field.get_lower_bound(&nearby) <= 10.0);
+            }
+            assert_eq!(MAX_BOUND, field.get_lower_bound(&Point { x: 5e5, y: 0.0 }));
+        }
+    }
+}
@@ -0,0 +1,304 @@
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::flake::{Flake, Particle};
+use crate::point::Point;
+
+// Metadata recording the edits made to a flake, oldest first.
+const HISTORY_KEY: &str = "history";
+
+// A rotation about the origin, then a scaling about the origin, then a
+// translation.
+pub struct Transform {
+    // In degrees, anticlockwise.
+    rotation: f64,
+    scale: f64,
+    translation: Point,
+}
+
+impl Transform {
+    pub fn new(rotation: f64, scale: f64, translation: Point) -> Result<Transform> {
+        if !scale.is_finite() || scale <= 0.0 {
+            return Result::Err(anyhow!("Scale must be a positive number but found {}", scale));
+        }
+        if !rotation.is_finite() || !translation.x.is_finite() || !translation.y.is_finite() {
+            return Result::Err(anyhow!("Rotation and translation must be finite"));
+        }
+        return Result::Ok(Transform {
+            rotation,
+            scale,
+            translation,
+        });
+    }
+
+    fn apply(&self, point: &Point) -> Point {
+        let (sin, cos) = self.rotation.to_radians().sin_cos();
+        return Point {
+            x: (point.x * cos - point.y * sin) * self.scale + self.translation.x,
+            y: (point.x * sin + point.y * cos) * self.scale + self.translation.y,
+        };
+    }
+
+    fn describe(&self) -> String {
+        return format!(
+            "rotate {} degrees, scale by {}, translate by {},{}",
+            self.rotation, self.scale, self.translation.x, self.translation.y
+        );
+    }
+}
+
+// Parse an offset given as <x>,<y>.
+pub fn parse_offset(offset: &str) -> Result<Point> {
+    let (x, y) = offset
+        .split_once(',')
+        .ok_or_else(|| anyhow!("Expected <x>,<y> but found '{}'", offset))?;
+    let x = x
+        .trim()
+        .parse::<f64>()
+        .context(format!("Unable to parse '{}' as a number", x))?;
+    let y = y
+        .trim()
+        .parse::<f64>()
+        .context(format!("Unable to parse '{}' as a number", y))?;
+    return Result::Ok(Point { x, y });
+}
+
+// Make a new flake to write edited particles to, with the given metadata and
+// the edit added to its history.
+fn create_output(
+    output_file: &str,
+    mut metadata: BTreeMap<String, String>,
+    edit: &str,
+    compact: bool,
+) -> Result<Flake> {
+    let mut output = Flake::new(output_file).context("Unable to open output flake")?;
+    output.lock().context("Unable to lock output flake")?;
+    if !output.is_new() {
+        return Result::Err(anyhow!("Output flake file already exists: {}", output_file));
+    }
+    if compact {
+        output.use_compact_encoding()?;
+    }
+
+    let history = match metadata.get(HISTORY_KEY) {
+        Option::Some(history) => format!("{}; {}", history, edit),
+        Option::None => edit.to_string(),
+    };
+    metadata.insert(HISTORY_KEY.to_string(), history);
+    for (key, value) in metadata.iter() {
+        output.set_metadata(key, value)?;
+    }
+    return Result::Ok(output);
+}
+
+// Write the first particles of a flake to a new flake file, to see what the
+// flake looked like part way through being generated.
+pub fn slice(flake: &Flake, num_particles: u64, output_file: &str, compact: bool) -> Result<()> {
+    let edit = format!("first {} particles of {}", num_particles, flake.path());
+    let mut output = create_output(output_file, flake.metadata().clone(), &edit, compact)?;
+
+    let mut count = 0;
+    for particle in flake.iter_particles().context("Unable to read flake particles")? {
+        if count == num_particles {
+            break;
+        }
+        let particle = particle.context("Unable to read flake particles")?;
+        output.add_particle(&particle)?;
+        count += 1;
+    }
+    output.flush()?;
+
+    if count < num_particles {
+        println!("The flake only has {} particles, so all were kept", count);
+    }
+    return Result::Ok(());
+}
+
+// Write the particles of several flakes, one after another, to a new flake
+// file. Each flake's particles are moved by the offset given for it, and
+// the indexes of their parents are moved past the particles before them.
+pub fn merge(flakes: &[Flake], offsets: &[Point], output_file: &str, compact: bool) -> Result<()> {
+    if flakes.len() != offsets.len() {
+        return Result::Err(anyhow!(
+            "Expected an offset for each of the {} flakes but found {}",
+            flakes.len(),
+            offsets.len()
+        ));
+    }
+    let sources: Vec<String> = flakes
+        .iter()
+        .zip(offsets)
+        .map(|(flake, offset)| format!("{} at {},{}", flake.path(), offset.x, offset.y))
+        .collect();
+    // Keep what all the flakes agree on, such as the particle diameter
+    let mut metadata = flakes.first().map_or(BTreeMap::new(), |flake| flake.metadata().clone());
+    metadata.retain(|key, value| {
+        let agree = flakes
+            .iter()
+            .all(|flake| flake.metadata().get(key) == Option::Some(value));
+        return key != HISTORY_KEY && agree;
+    });
+    let edit = format!("merge {}", sources.join(" and "));
+    let mut output = create_output(output_file, metadata, &edit, compact)?;
+
+    let mut first_index = 0;
+    for (flake, offset) in flakes.iter().zip(offsets) {
+        let mut count = 0;
+        for particle in flake.iter_particles().context("Unable to read flake particles")? {
+            let particle = particle.context("Unable to read flake particles")?;
+            output.add_particle(&Particle {
+                point: Point {
+                    x: particle.point.x + offset.x,
+                    y: particle.point.y + offset.y,
+                },
+                parent: particle.parent.map(|parent| parent + first_index),
+                ..particle
+            })?;
+            count += 1;
+        }
+        first_index += count;
+    }
+    output.flush()?;
+    return Result::Ok(());
+}
+
+// Write the particles of a flake, transformed, to a new flake file. Scaling
+// also scales the particle diameter recorded in the metadata.
+pub fn transform(
+    flake: &Flake,
+    transform: &Transform,
+    output_file: &str,
+    compact: bool,
+) -> Result<()> {
+    let mut metadata = flake.metadata().clone();
+    if let Option::Some(diameter) = metadata.get("particle-diameter") {
+        if let Result::Ok(diameter) = diameter.parse::<f64>() {
+            let diameter = diameter * transform.scale;
+            metadata.insert("particle-diameter".to_string(), diameter.to_string());
+        }
+    }
+    let edit = format!("{} of {}", transform.describe(), flake.path());
+    let mut output = create_output(output_file, metadata, &edit, compact)?;
+
+    for particle in flake.iter_particles().context("Unable to read flake particles")? {
+        let particle = particle.context("Unable to read flake particles")?;
+        output.add_particle(&Particle {
+            point: transform.apply(&particle.point),
+            ..particle
+        })?;
+    }
+    output.flush()?;
+    return Result::Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{merge, parse_offset, slice, transform, Transform};
+    use crate::flake::{Flake, Particle};
+    use crate::point::Point;
+    use crate::test_utils::test::with_test_dir;
+
+    fn write_flake(flake_file: &str, num_particles: u64) -> Flake {
+        let mut flake = Flake::new(flake_file).expect("Unable to open flake");
+        flake
+            .set_metadata("particle-diameter", "1")
+            .expect("Unable to set metadata");
+        flake
+            .set_metadata("seed", flake_file)
+            .expect("Unable to set metadata");
+        for i in 0..num_particles {
+            flake
+                .add_particle(&Particle {
+                    parent: if i == 0 { Option::None } else { Option::Some(i - 1) },
+                    steps: i,
+                    ..Particle::new(Point {
+                        x: i as f64,
+                        y: 1.0,
+                    })
+                })
+                .expect("Unable to add particle");
+        }
+        flake.flush().expect("Unable to flush");
+        return flake;
+    }
+
+    #[test]
+    fn slice_flake() {
+        with_test_dir(|test_dir: &str| {
+            let flake = write_flake(&format!("{}/test.flake", test_dir), 2500);
+            let output_file = format!("{}/sliced.flake", test_dir);
+            slice(&flake, 1200, &output_file, false).expect("Unable to slice");
+
+            let sliced = Flake::new(&output_file).expect("Unable to open flake");
+            let particles = sliced.get_particles().expect("Unable to get particles");
+            assert_eq!(1200, particles.len());
+            assert_eq!(1199, particles[1199].steps);
+            assert_eq!(flake.metadata()["seed"], sliced.metadata()["seed"]);
+            assert!(sliced.metadata()["history"].starts_with("first 1200 particles of "));
+
+            // Output files must be new
+            assert!(slice(&flake, 10, &output_file, false).is_err());
+
+            // Slicing again adds to the history, and keeps every particle if
+            // there are too few
+            let output_file = format!("{}/sliced-again.flake", test_dir);
+            slice(&sliced, 5000, &output_file, true).expect("Unable to slice");
+            let sliced_again = Flake::new(&output_file).expect("Unable to open flake");
+            assert_eq!(1200, sliced_again.get_points().expect("Unable to get points").len());
+            assert_eq!(2, sliced_again.metadata()["history"].split("; ").count());
+        });
+    }
+
+    #[test]
+    fn merge_flakes() {
+        with_test_dir(|test_dir: &str| {
+            let first = write_flake(&format!("{}/first.flake", test_dir), 1500);
+            let second = write_flake(&format!("{}/second.flake", test_dir), 10);
+            let output_file = format!("{}/merged.flake", test_dir);
+            let offsets = vec![Point::ZERO, Point { x: -10.0, y: 5.0 }];
+            merge(&[first, second], &offsets, &output_file, false).expect("Unable to merge");
+
+            let merged = Flake::new(&output_file).expect("Unable to open flake");
+            let particles = merged.get_particles().expect("Unable to get particles");
+            assert_eq!(1510, particles.len());
+            assert_eq!(1499.0, particles[1499].point.x);
+            assert_eq!(-10.0, particles[1500].point.x);
+            assert_eq!(6.0, particles[1500].point.y);
+            assert_eq!(true, particles[1500].parent.is_none());
+            assert_eq!(Option::Some(1500), particles[1501].parent);
+
+            // Only metadata the flakes agree on is kept
+            assert_eq!("1", merged.metadata()["particle-diameter"]);
+            assert_eq!(false, merged.metadata().contains_key("seed"));
+            assert_eq!(true, merged.metadata()["history"].starts_with("merge "));
+
+            let first = Flake::new(&format!("{}/first.flake", test_dir)).unwrap();
+            let output_file = format!("{}/merged-again.flake", test_dir);
+            assert!(merge(&[first], &offsets, &output_file, false).is_err());
+        });
+    }
+
+    #[test]
+    fn transform_flake() {
+        with_test_dir(|test_dir: &str| {
+            let flake = write_flake(&format!("{}/test.flake", test_dir), 3);
+            let output_file = format!("{}/transformed.flake", test_dir);
+            let translation = parse_offset("-1, 0.5").expect("Unable to parse offset");
+            let rotate = Transform::new(90.0, 2.0, translation).expect("Unable to make transform");
+            transform(&flake, &rotate, &output_file, false).expect("Unable to transform");
+
+            let transformed = Flake::new(&output_file).expect("Unable to open flake");
+            let particles = transformed.get_particles().expect("Unable to get particles");
+            // (2, 1) is rotated to (-1, 2), scaled to (-2, 4) then translated
+            assert!(particles[2].point.distance(&Point { x: -3.0, y: 4.5 }) < 1e-9);
+            assert_eq!(Option::Some(1), particles[2].parent);
+            assert_eq!("2", transformed.metadata()["particle-diameter"]);
+
+            assert!(Transform::new(0.0, 0.0, Point::ZERO).is_err());
+            assert!(Transform::new(f64::NAN, 1.0, Point::ZERO).is_err());
+            assert!(parse_offset("1").is_err());
+            assert!(parse_offset("1,y").is_err());
+        });
+    }
+}
@@ -0,0 +1,460 @@
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::flake::{Flake, Particle};
+
+// The file formats that flakes can be exported to.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ExportFormat {
+    // Comma-separated values, with a header row naming the columns.
+    Csv,
+    // One JSON object per line, with a field for each column.
+    JsonLines,
+    // A NumPy array with a named field of the natural type for each column.
+    Npy,
+}
+
+impl ExportFormat {
+    pub fn from(name: &str) -> Result<ExportFormat> {
+        return match name {
+            "csv" => Result::Ok(ExportFormat::Csv),
+            "jsonl" => Result::Ok(ExportFormat::JsonLines),
+            "npy" => Result::Ok(ExportFormat::Npy),
+            _ => Result::Err(anyhow!(
+                "Expected 'csv', 'jsonl' or 'npy' but found '{}'",
+                name
+            )),
+        };
+    }
+
+    // Work out the format from the extension of the output file.
+    pub fn from_filename(filename: &str) -> Result<ExportFormat> {
+        let extension = filename.rsplit('.').next().unwrap_or("");
+        return match extension {
+            "json" => Result::Ok(ExportFormat::JsonLines),
+            _ => ExportFormat::from(extension).context(format!(
+                "Unable to tell the export format from the name of '{}'",
+                filename
+            )),
+        };
+    }
+}
+
+// The attributes of a particle that can be exported.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Column {
+    // The order the particle was added in.
+    Index,
+    X,
+    Y,
+    Species,
+    // The index of the particle this one stuck to, if any.
+    Parent,
+    Steps,
+    // When the particle was added, in seconds since the Unix epoch, if recorded.
+    Time,
+}
+
+impl Column {
+    const ALL: [Column; 7] = [
+        Column::Index,
+        Column::X,
+        Column::Y,
+        Column::Species,
+        Column::Parent,
+        Column::Steps,
+        Column::Time,
+    ];
+
+    pub fn from(name: &str) -> Result<Column> {
+        return match Column::ALL.iter().find(|column| column.name() == name) {
+            Option::Some(column) => Result::Ok(*column),
+            Option::None => Result::Err(anyhow!(
+                "Expected one of {} but found '{}'",
+                Column::ALL
+                    .iter()
+                    .map(|column| format!("'{}'", column.name()))
+                    .collect::<Vec<String>>()
+                    .join(", "),
+                name
+            )),
+        };
+    }
+
+    // Parse a comma-separated list of columns.
+    pub fn parse_list(names: &str) -> Result<Vec<Column>> {
+        let columns = names
+            .split(',')
+            .map(|name| Column::from(name.trim()))
+            .collect::<Result<Vec<Column>>>()?;
+        for (i, column) in columns.iter().enumerate() {
+            if columns[..i].contains(column) {
+                return Result::Err(anyhow!("Column '{}' is given twice", column.name()));
+            }
+        }
+        return Result::Ok(columns);
+    }
+
+    // Returns every column that the flake file records.
+    pub fn available(flake: &Flake) -> Vec<Column> {
+        return Column::ALL
+            .iter()
+            .filter(|column| column.is_available(flake))
+            .copied()
+            .collect();
+    }
+
+    fn is_available(&self, flake: &Flake) -> bool {
+        return match self {
+            Column::Index | Column::X | Column::Y => true,
+            Column::Species => flake.has_species(),
+            Column::Parent | Column::Steps | Column::Time => flake.has_provenance(),
+        };
+    }
+
+    pub fn name(&self) -> &'static str {
+        return match self {
+            Column::Index => "index",
+            Column::X => "x",
+            Column::Y => "y",
+            Column::Species => "species",
+            Column::Parent => "parent",
+            Column::Steps => "steps",
+            Column::Time => "time",
+        };
+    }
+
+    // The NumPy type of the column. Particles without a parent have a parent
+    // of -1, and those without a time have a time of NaN.
+    fn npy_type(&self) -> &'static str {
+        return match self {
+            Column::Index | Column::Steps => "<u8",
+            Column::X | Column::Y | Column::Time => "<f8",
+            Column::Species => "|u1",
+            Column::Parent => "<i8",
+        };
+    }
+}
+
+// Writes particles to the output file one at a time.
+struct Exporter {
+    format: ExportFormat,
+    columns: Vec<Column>,
+    // The number of decimal places to give coordinates to, if limited.
+    precision: Option<usize>,
+    writer: BufWriter<File>,
+    num_particles: u64,
+}
+
+impl Exporter {
+    fn new(
+        format: ExportFormat,
+        columns: &[Column],
+        precision: Option<usize>,
+        output_filename: &str,
+    ) -> Result<Exporter> {
+        let f = File::create(output_filename)
+            .context(format!("Unable to create output file: {}", output_filename))?;
+        let mut exporter = Exporter {
+            format,
+            columns: columns.to_vec(),
+            precision,
+            writer: BufWriter::new(f),
+            num_particles: 0,
+        };
+        match format {
+            ExportFormat::Csv => {
+                let names: Vec<&str> = columns.iter().map(|column| column.name()).collect();
+                writeln!(exporter.writer, "{}", names.join(","))?;
+            }
+            ExportFormat::JsonLines => {}
+            // The number of particles isn't known yet, so is filled in later
+            ExportFormat::Npy => exporter.write_npy_header()?,
+        }
+        return Result::Ok(exporter);
+    }
+
+    fn write_particle(&mut self, particle: &Particle) -> Result<()> {
+        let index = self.num_particles;
+        self.num_particles += 1;
+        if self.format == ExportFormat::Npy {
+            for column in &self.columns {
+                match column {
+                    Column::Index => self.writer.write_all(&index.to_le_bytes())?,
+                    Column::X => {
+                        let x = self.round(particle.point.x);
+                        self.writer.write_all(&x.to_le_bytes())?;
+                    }
+                    Column::Y => {
+                        let y = self.round(particle.point.y);
+                        self.writer.write_all(&y.to_le_bytes())?;
+                    }
+                    Column::Species => self.writer.write_all(&[particle.species])?,
+                    Column::Parent => {
+                        let parent = particle.parent.map_or(-1, |parent| parent as i64);
+                        self.writer.write_all(&parent.to_le_bytes())?;
+                    }
+                    Column::Steps => self.writer.write_all(&particle.steps.to_le_bytes())?,
+                    Column::Time => {
+                        let time = particle.time.unwrap_or(f64::NAN);
+                        self.writer.write_all(&time.to_le_bytes())?;
+                    }
+                }
+            }
+            return Result::Ok(());
+        }
+
+        // Text formats leave out missing values
+        let null = if self.format == ExportFormat::Csv { "" } else { "null" };
+        let mut values = Vec::with_capacity(self.columns.len());
+        for column in &self.columns {
+            values.push(match column {
+                Column::Index => index.to_string(),
+                Column::X => self.format_coordinate(particle.point.x),
+                Column::Y => self.format_coordinate(particle.point.y),
+                Column::Species => particle.species.to_string(),
+                Column::Parent => particle.parent.map_or(null.to_string(), |p| p.to_string()),
+                Column::Steps => particle.steps.to_string(),
+                Column::Time => particle.time.map_or(null.to_string(), |t| t.to_string()),
+            });
+        }
+        if self.format == ExportFormat::Csv {
+            writeln!(self.writer, "{}", values.join(","))?;
+        } else {
+            let fields: Vec<String> = self
+                .columns
+                .iter()
+                .zip(&values)
+                .map(|(column, value)| format!("\"{}\":{}", column.name(), value))
+                .collect();
+            writeln!(self.writer, "{{{}}}", fields.join(","))?;
+        }
+        return Result::Ok(());
+    }
+
+    fn round(&self, coordinate: f64) -> f64 {
+        return match self.precision {
+            Option::Some(precision) => {
+                let scale = 10_f64.powi(precision as i32);
+                (coordinate * scale).round() / scale
+            }
+            Option::None => coordinate,
+        };
+    }
+
+    fn format_coordinate(&self, coordinate: f64) -> String {
+        return match self.precision {
+            Option::Some(precision) => format!("{:.*}", precision, coordinate),
+            Option::None => coordinate.to_string(),
+        };
+    }
+
+    // Write the header of a version 1.0 .npy file, describing a 1-dimensional
+    // array of records with a field for each column. The number of records
+    // is padded so that the header stays the same length when it is filled
+    // in at the end.
+    fn write_npy_header(&mut self) -> Result<()> {
+        let fields: Vec<String> = self
+            .columns
+            .iter()
+            .map(|column| format!("('{}', '{}')", column.name(), column.npy_type()))
+            .collect();
+        let mut header = format!(
+            "{{'descr': [{}], 'fortran_order': False, 'shape': ({:<20},), }}",
+            fields.join(", "),
+            self.num_particles
+        );
+        // The whole preamble must be a multiple of 64 bytes, ending in a newline
+        let preamble_len = 10;
+        while (preamble_len + header.len() + 1) % 64 != 0 {
+            header.push(' ');
+        }
+        header.push('\n');
+
+        self.writer.write_all(b"\x93NUMPY\x01\x00")?;
+        self.writer.write_all(&(header.len() as u16).to_le_bytes())?;
+        self.writer.write_all(header.as_bytes())?;
+        return Result::Ok(());
+    }
+
+    fn finish(mut self) -> Result<()> {
+        if self.format == ExportFormat::Npy {
+            self.writer.seek(SeekFrom::Start(0))?;
+            self.write_npy_header()?;
+        }
+        self.writer.flush()?;
+        return Result::Ok(());
+    }
+}
+
+// Export the particles of a flake to a file, with the given columns in the
+// given order. Particles are read from the flake file as they are written,
+// so flakes of any size can be exported.
+pub fn export(
+    flake: &Flake,
+    format: ExportFormat,
+    columns: &[Column],
+    precision: Option<usize>,
+    output_filename: &str,
+) -> Result<()> {
+    for column in columns {
+        if !column.is_available(flake) {
+            return Result::Err(anyhow!(
+                "The flake file doesn't record the '{}' column",
+                column.name()
+            ));
+        }
+    }
+
+    let mut exporter = Exporter::new(format, columns, precision, output_filename)?;
+    for particle in flake.iter_particles().context("Unable to read flake particles")? {
+        let particle = particle.context("Unable to read flake particles")?;
+        exporter
+            .write_particle(&particle)
+            .context("Unable to write to output file")?;
+    }
+    let num_particles = exporter.num_particles;
+    exporter.finish().context("Unable to write to output file")?;
+    println!("Exported {} particles", num_particles);
+    return Result::Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::{export, Column, ExportFormat};
+    use crate::flake::{Flake, Particle};
+    use crate::point::Point;
+    use crate::test_utils::test::with_test_dir;
+
+    fn write_flake(flake_file: &str) -> Flake {
+        let mut flake = Flake::new(flake_file).expect("Unable to open flake");
+        flake
+            .add_point(&Point { x: 0.0, y: -1.5 })
+            .expect("Unable to add point");
+        flake
+            .add_particle(&Particle {
+                species: 2,
+                parent: Option::Some(0),
+                steps: 300,
+                time: Option::Some(1600000000.25),
+                ..Particle::new(Point {
+                    x: 1.0 / 3.0,
+                    y: 0.5,
+                })
+            })
+            .expect("Unable to add particle");
+        flake.flush().expect("Unable to flush");
+        return flake;
+    }
+
+    #[test]
+    fn parse_formats_and_columns() {
+        assert_eq!(ExportFormat::Npy, ExportFormat::from("npy").unwrap());
+        assert_eq!(ExportFormat::Csv, ExportFormat::from_filename("out.csv").unwrap());
+        assert_eq!(ExportFormat::JsonLines, ExportFormat::from_filename("a.b.json").unwrap());
+        assert!(ExportFormat::from_filename("csv").is_ok());
+        assert!(ExportFormat::from_filename("out.txt").is_err());
+
+        assert_eq!(
+            vec![Column::Y, Column::Index],
+            Column::parse_list("y, index").unwrap()
+        );
+        assert!(Column::parse_list("x,x").is_err());
+        assert!(Column::parse_list("x,").is_err());
+        assert!(Column::parse_list("z").is_err());
+    }
+
+    #[test]
+    fn export_text() {
+        with_test_dir(|test_dir: &str| {
+            let flake = write_flake(&format!("{}/test.flake", test_dir));
+            let columns = Column::available(&flake);
+            assert_eq!(7, columns.len());
+
+            let output_file = format!("{}/out.csv", test_dir);
+            export(&flake, ExportFormat::Csv, &columns, Option::None, &output_file)
+                .expect("Unable to export");
+            assert_eq!(
+                "index,x,y,species,parent,steps,time\n\
+                 0,0,-1.5,0,,0,\n\
+                 1,0.3333333333333333,0.5,2,0,300,1600000000.25\n",
+                fs::read_to_string(&output_file).expect("Unable to read output")
+            );
+
+            let output_file = format!("{}/out.jsonl", test_dir);
+            let columns = vec![Column::Y, Column::X, Column::Parent];
+            export(&flake, ExportFormat::JsonLines, &columns, Option::Some(2), &output_file)
+                .expect("Unable to export");
+            assert_eq!(
+                "{\"y\":-1.50,\"x\":0.00,\"parent\":null}\n\
+                 {\"y\":0.50,\"x\":0.33,\"parent\":0}\n",
+                fs::read_to_string(&output_file).expect("Unable to read output")
+            );
+        });
+    }
+
+    #[test]
+    fn export_npy() {
+        with_test_dir(|test_dir: &str| {
+            let flake = write_flake(&format!("{}/test.flake", test_dir));
+            let output_file = format!("{}/out.npy", test_dir);
+            let columns = vec![Column::X, Column::Species, Column::Parent, Column::Time];
+            export(&flake, ExportFormat::Npy, &columns, Option::Some(3), &output_file)
+                .expect("Unable to export");
+
+            let contents = fs::read(&output_file).expect("Unable to read output");
+            assert_eq!(b"\x93NUMPY\x01\x00", &contents[..8]);
+            let header_len = u16::from_le_bytes([contents[8], contents[9]]) as usize;
+            assert_eq!(0, (10 + header_len) % 64);
+            let header = std::str::from_utf8(&contents[10..10 + header_len]).unwrap();
+            assert!(header.starts_with(
+                "{'descr': [('x', '<f8'), ('species', '|u1'), ('parent', '<i8'), \
+                 ('time', '<f8')], 'fortran_order': False, 'shape': (2 "
+            ));
+            assert!(header.ends_with("\n"));
+
+            let records = &contents[10 + header_len..];
+            assert_eq!(2 * 25, records.len());
+            let read_f64 = |offset: usize| {
+                let mut buf = [0; 8];
+                buf.copy_from_slice(&records[offset..offset + 8]);
+                return f64::from_le_bytes(buf);
+            };
+            assert_eq!(0.0, read_f64(0));
+            assert_eq!(0, records[8]);
+            assert_eq!((-1_i64).to_le_bytes(), records[9..17]);
+            assert_eq!(true, read_f64(17).is_nan());
+            assert_eq!(0.333, read_f64(25));
+            assert_eq!(2, records[33]);
+            assert_eq!(0_i64.to_le_bytes(), records[34..42]);
+            assert_eq!(1600000000.25, read_f64(42));
+        });
+    }
+
+    #[test]
+    fn export_columns_flake_records() {
+        with_test_dir(|test_dir: &str| {
+            // A headerless file from before species were recorded
+            let flake_file = format!("{}/test.flake", test_dir);
+            let mut contents = Vec::new();
+            for value in &[1.0_f64, 2.0] {
+                contents.extend_from_slice(&value.to_be_bytes());
+            }
+            fs::write(&flake_file, &contents).expect("Unable to write file");
+
+            let flake = Flake::new(&flake_file).expect("Unable to open flake");
+            assert_eq!(
+                vec![Column::Index, Column::X, Column::Y],
+                Column::available(&flake)
+            );
+            let output_file = format!("{}/out.csv", test_dir);
+            let columns = vec![Column::X, Column::Species];
+            let result = export(&flake, ExportFormat::Csv, &columns, Option::None, &output_file);
+            assert!(result.is_err());
+        });
+    }
+}
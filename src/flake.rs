@@ -1,98 +1,1128 @@
-use std::fs::File;
-use std::io::{Read, Write};
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
 use std::path::Path;
+use std::time::{Duration, Instant};
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
 
+use crate::compact;
 use crate::point::Point;
 
-const MAX_BUFFERED_POINTS: usize = 1000;
+// By default particles are written out once this many have been added.
+const DEFAULT_FLUSH_PARTICLES: usize = 1000;
+
+// Flake files start with these bytes followed by a big-endian u32 version.
+// Files from before the header was introduced are a headerless stream of points.
+const MAGIC: &[u8; 8] = b"SNOWFLAK";
+const HEADER_SIZE: usize = 12;
+// The size of the largest record of any format.
+const MAX_RECORD_SIZE: usize = 41;
+
+// Records are written in blocks of at most this many, each preceded by a
+// big-endian u32 count and followed by a big-endian u32 CRC-32 of the count
+// and the records.
+const MAX_BLOCK_RECORDS: usize = 1000;
+const BLOCK_OVERHEAD: usize = 8;
+
+// How much of each end of the flake file a fingerprint covers.
+const FINGERPRINT_BYTES: u64 = 1 << 16;
+
+// Metadata larger than this is taken to mean the file isn't a flake file.
+const MAX_METADATA_SIZE: u32 = 1 << 20;
+
+// Headerless files whose first points are further than this from the origin,
+// or aren't numbers at all, are taken to mean the file isn't a flake file.
+// Flakes are measured in particle diameters, so never grow anywhere near it.
+const MAX_HEADERLESS_COORDINATE: f64 = 1e9;
+
+// Written in place of the parent of a particle that has none.
+const NO_PARENT: u64 = u64::MAX;
+
+// The layout of the records in a flake file.
+#[derive(Clone, Copy, PartialEq)]
+enum Format {
+    // No header, and each record is a big-endian (x, y) pair.
+    Legacy,
+    // Each record is a big-endian (x, y) pair followed by a species byte.
+    V1,
+    // As V1, followed by the parent index, the number of steps walked,
+    // and the time the particle was added. All are big-endian.
+    V2,
+    // As V2, with a block of metadata after the version. The block is a u32
+    // length in bytes, then a u32 number of entries, then each key and value
+    // as a u32 length followed by UTF-8 text.
+    V3,
+    // As V3, with the records grouped into checksummed blocks.
+    V4,
+    // As V4, with the records of each block in the compact encoding. Each
+    // block is a u32 count, then the u32 length of the encoded records,
+    // then the records, then the CRC-32.
+    V5,
+}
+
+impl Format {
+    const CURRENT: Format = Format::V4;
+    const COMPACT: Format = Format::V5;
+
+    fn version(&self) -> u32 {
+        return match self {
+            Format::Legacy => 0,
+            Format::V1 => 1,
+            Format::V2 => 2,
+            Format::V3 => 3,
+            Format::V4 => 4,
+            Format::V5 => 5,
+        };
+    }
+
+    fn from_version(version: u32) -> Result<Format> {
+        return match version {
+            1 => Result::Ok(Format::V1),
+            2 => Result::Ok(Format::V2),
+            3 => Result::Ok(Format::V3),
+            4 => Result::Ok(Format::V4),
+            5 => Result::Ok(Format::V5),
+            _ => Result::Err(anyhow!("Unsupported flake file version {}", version)),
+        };
+    }
+
+    // The size of each record. Compact records vary in size, so this is the
+    // size they are decoded from.
+    fn record_size(&self) -> usize {
+        return match self {
+            Format::Legacy => 16,
+            Format::V1 => 17,
+            Format::V2 | Format::V3 | Format::V4 | Format::V5 => 41,
+        };
+    }
+
+    fn has_species(&self) -> bool {
+        return *self != Format::Legacy;
+    }
+
+    // Whether records hold the parent, steps and time of each particle.
+    fn has_provenance(&self) -> bool {
+        return *self != Format::Legacy && *self != Format::V1;
+    }
+
+    fn has_metadata(&self) -> bool {
+        return *self == Format::V3 || *self == Format::V4 || *self == Format::V5;
+    }
+
+    fn has_blocks(&self) -> bool {
+        return *self == Format::V4 || *self == Format::V5;
+    }
+
+    fn is_compact(&self) -> bool {
+        return *self == Format::V5;
+    }
+
+    fn encode(&self, particle: &Particle, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&particle.point.x.to_be_bytes());
+        buf.extend_from_slice(&particle.point.y.to_be_bytes());
+        if *self == Format::Legacy {
+            return;
+        }
+        buf.push(particle.species);
+        if *self == Format::V1 {
+            return;
+        }
+        buf.extend_from_slice(&particle.parent.unwrap_or(NO_PARENT).to_be_bytes());
+        buf.extend_from_slice(&particle.steps.to_be_bytes());
+        buf.extend_from_slice(&particle.time.unwrap_or(f64::NAN).to_be_bytes());
+    }
+
+    fn decode(&self, record: &[u8]) -> Particle {
+        let mut particle = Particle::new(Point {
+            x: f64::from_be_bytes(read_bytes(record, 0)),
+            y: f64::from_be_bytes(read_bytes(record, 8)),
+        });
+        if *self == Format::Legacy {
+            return particle;
+        }
+        particle.species = record[16];
+        if *self == Format::V1 {
+            return particle;
+        }
+        let parent = u64::from_be_bytes(read_bytes(record, 17));
+        if parent != NO_PARENT {
+            particle.parent = Option::Some(parent);
+        }
+        particle.steps = u64::from_be_bytes(read_bytes(record, 25));
+        let time = f64::from_be_bytes(read_bytes(record, 33));
+        if !time.is_nan() {
+            particle.time = Option::Some(time);
+        }
+        return particle;
+    }
+}
+
+fn read_bytes(record: &[u8], offset: usize) -> [u8; 8] {
+    let mut buf: [u8; 8] = [0; 8];
+    buf.clone_from_slice(&record[offset..offset + 8]);
+    return buf;
+}
+
+fn read_u32_bytes(record: &[u8], offset: usize) -> [u8; 4] {
+    let mut buf: [u8; 4] = [0; 4];
+    buf.clone_from_slice(&record[offset..offset + 4]);
+    return buf;
+}
+
+fn block_checksum(block: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(block);
+    return hasher.finalize();
+}
+
+// Fill the given buffer from the reader, returning less than its length only
+// at the end of the file. Single reads may return fewer bytes than asked for
+// even before then.
+fn read_fully<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        let bytes_read = reader
+            .read(&mut buf[len..])
+            .context("Unable to read from flake file")?;
+        if bytes_read == 0 {
+            break;
+        }
+        len += bytes_read;
+    }
+    return Result::Ok(len);
+}
+
+// A single particle of a flake. Its index, which is the order it was added
+// to the flake in, is its position in the flake file.
+#[derive(Clone, Copy)]
+pub struct Particle {
+    pub point: Point,
+    // Index into the list of species the flake was generated with.
+    pub species: u8,
+    // The index of the particle this one stuck to, if any.
+    pub parent: Option<u64>,
+    // The number of steps the walker took before sticking.
+    pub steps: u64,
+    // When the particle was added, in seconds since the Unix epoch, if recorded.
+    pub time: Option<f64>,
+}
+
+impl Particle {
+    // Make a particle at the given point with no other information.
+    pub fn new(point: Point) -> Particle {
+        return Particle {
+            point,
+            species: 0,
+            parent: Option::None,
+            steps: 0,
+            time: Option::None,
+        };
+    }
+}
+
+// When buffered particles are written out to the flake file.
+#[derive(Clone, Copy)]
+pub struct FlushPolicy {
+    // Write out once this many particles are buffered.
+    pub max_particles: usize,
+    // Write out once the oldest buffered particle has waited this long.
+    pub max_time: Option<Duration>,
+    // Wait for each write to reach the disk, so that it survives a power
+    // loss. This is much slower.
+    pub sync: bool,
+}
+
+impl Default for FlushPolicy {
+    fn default() -> FlushPolicy {
+        return FlushPolicy {
+            max_particles: DEFAULT_FLUSH_PARTICLES,
+            max_time: Option::None,
+            sync: false,
+        };
+    }
+}
+
+// The result of checking a flake file for damage.
+pub struct Check {
+    // The number of particles before any damage.
+    pub num_particles: u64,
+    // The length of the file up to the end of the last valid record.
+    pub valid_length: u64,
+    pub length: u64,
+    // What is wrong with the rest of the file, if anything.
+    pub problem: Option<String>,
+    // Whether the damage is a block cut short at the end of the file, which
+    // is also how a write still in progress looks.
+    pub cut_short: bool,
+}
+
+// Reads the particles of a flake file in order, a batch of records at a time.
+pub struct Particles {
+    // None once there is nothing left to read.
+    reader: Option<BufReader<File>>,
+    format: Format,
+    buf: Vec<u8>,
+    // The part of the buffer holding records that haven't been returned yet.
+    // For compact files, the part of the decoded particles instead.
+    position: usize,
+    end: usize,
+    // For compact files, the particles of the current block.
+    decoded: Vec<Particle>,
+    check: Check,
+    finished: bool,
+    // The flake file, if a block cut short at its end may be one that
+    // another process is still writing rather than damage.
+    unlocked_file: Option<String>,
+    // Whether to stop quietly at a block cut short by the end of the file, so
+    // as to carry on from there once more has been written.
+    follow: bool,
+}
+
+impl Particles {
+    fn open(flake_file: &str, locked: bool) -> Result<Particles> {
+        let mut particles = Particles {
+            reader: Option::None,
+            format: Format::Legacy,
+            buf: Vec::new(),
+            position: 0,
+            end: 0,
+            decoded: Vec::new(),
+            check: Check {
+                num_particles: 0,
+                valid_length: 0,
+                length: 0,
+                problem: Option::None,
+                cut_short: false,
+            },
+            finished: false,
+            unlocked_file: if locked { Option::None } else { Option::Some(flake_file.to_string()) },
+            follow: false,
+        };
+        if !Path::new(flake_file).exists() {
+            return Result::Ok(particles);
+        }
+
+        let mut f = File::open(flake_file)?;
+        particles.check.length = f.metadata().context("Unable to read flake file metadata")?.len();
+        let (format, _) = read_header(&mut f).context("Unable to read flake file header")?;
+        particles.check.valid_length = f.stream_position()?;
+        particles.format = format;
+        particles.buf = vec![0; MAX_BLOCK_RECORDS * format.record_size() + BLOCK_OVERHEAD];
+        if format.is_compact() {
+            particles.buf = vec![0; compact::max_block_size(MAX_BLOCK_RECORDS) + BLOCK_OVERHEAD + 4];
+        }
+        particles.reader = Option::Some(BufReader::new(f));
+        return Result::Ok(particles);
+    }
+
+    // Read the next batch of valid records into the buffer. Returns false
+    // once there are none left, noting any damage found in the check.
+    fn read_batch(&mut self) -> Result<bool> {
+        let reader = match &mut self.reader {
+            Option::Some(reader) => reader,
+            Option::None => return Result::Ok(false),
+        };
+        let record_size = self.format.record_size();
+
+        if !self.format.has_blocks() {
+            let batch_len = MAX_BLOCK_RECORDS * record_size;
+            let bytes_read = read_fully(reader, &mut self.buf[..batch_len])?;
+            let complete_len = bytes_read - bytes_read % record_size;
+            if complete_len < bytes_read {
+                self.check.problem = Option::Some(format!(
+                    "{} bytes after the last whole record",
+                    bytes_read - complete_len
+                ));
+                self.check.cut_short = true;
+            }
+            if bytes_read < batch_len {
+                self.reader = Option::None;
+            }
+            self.position = 0;
+            self.end = complete_len;
+            self.check.num_particles += (complete_len / record_size) as u64;
+            self.check.valid_length += complete_len as u64;
+            return Result::Ok(complete_len > 0);
+        }
+
+        let bytes_read = read_fully(reader, &mut self.buf[..4])?;
+        if bytes_read == 0 {
+            return self.stop(Option::None);
+        }
+        if bytes_read < 4 {
+            return self.stop_cut_short("the last block header is cut short");
+        }
+        let num_records = u32::from_be_bytes(read_u32_bytes(&self.buf, 0)) as usize;
+        if num_records == 0 || num_records > MAX_BLOCK_RECORDS {
+            return self.stop(Option::Some(format!("invalid block of {} records", num_records)));
+        }
+
+        if self.format.is_compact() {
+            return self.read_compact_block(num_records);
+        }
+
+        let block_len = 4 + num_records * record_size + 4;
+        if read_fully(reader, &mut self.buf[4..block_len])? < block_len - 4 {
+            return self.stop_cut_short("the last block is cut short");
+        }
+        let crc = u32::from_be_bytes(read_u32_bytes(&self.buf, block_len - 4));
+        if crc != block_checksum(&self.buf[..block_len - 4]) {
+            return self.stop(Option::Some("a block's checksum doesn't match".to_string()));
+        }
+
+        self.position = 4;
+        self.end = block_len - 4;
+        self.check.num_particles += num_records as u64;
+        self.check.valid_length += block_len as u64;
+        return Result::Ok(true);
+    }
+
+    // Read and decode the rest of a compact block holding the given number of
+    // records, whose count has already been read.
+    fn read_compact_block(&mut self, num_records: usize) -> Result<bool> {
+        let reader = self.reader.as_mut().unwrap();
+        if read_fully(reader, &mut self.buf[4..8])? < 4 {
+            return self.stop_cut_short("the last block header is cut short");
+        }
+        let encoded_len = u32::from_be_bytes(read_u32_bytes(&self.buf, 4)) as usize;
+        if encoded_len > compact::max_block_size(num_records) {
+            return self.stop(Option::Some(format!("invalid block of {} bytes", encoded_len)));
+        }
+
+        let block_len = 8 + encoded_len + 4;
+        if read_fully(reader, &mut self.buf[8..block_len])? < block_len - 8 {
+            return self.stop_cut_short("the last block is cut short");
+        }
+        let crc = u32::from_be_bytes(read_u32_bytes(&self.buf, block_len - 4));
+        if crc != block_checksum(&self.buf[..block_len - 4]) {
+            return self.stop(Option::Some("a block's checksum doesn't match".to_string()));
+        }
+
+        self.decoded.clear();
+        let encoded = &self.buf[8..block_len - 4];
+        let first_index = self.check.num_particles;
+        if let Result::Err(err) =
+            compact::decode_block(encoded, num_records, first_index, &mut self.decoded)
+        {
+            return self.stop(Option::Some(format!("a block can't be decoded: {:#}", err)));
+        }
+
+        self.position = 0;
+        self.end = num_records;
+        self.check.num_particles += num_records as u64;
+        self.check.valid_length += block_len as u64;
+        return Result::Ok(true);
+    }
+
+    // Stop reading, noting the damage found if any.
+    fn stop(&mut self, problem: Option<String>) -> Result<bool> {
+        self.check.problem = problem;
+        self.reader = Option::None;
+        return Result::Ok(false);
+    }
+
+    // Stop reading at a block cut short by the end of the file.
+    fn stop_cut_short(&mut self, problem: &str) -> Result<bool> {
+        self.check.cut_short = true;
+        return self.stop(Option::Some(problem.to_string()));
+    }
+
+    // Carry on from the given offset in the file instead of the start, where
+    // the given number of particles come before it.
+    fn seek(&mut self, offset: u64, num_particles: u64) -> Result<()> {
+        let reader = match &mut self.reader {
+            Option::Some(reader) => reader,
+            Option::None if offset == 0 => return Result::Ok(()),
+            Option::None => return Result::Err(anyhow!("Flake file doesn't exist")),
+        };
+        let header_length = self.check.valid_length;
+        let record_size = self.format.record_size() as u64;
+        let misaligned = !self.format.has_blocks()
+            && !offset.saturating_sub(header_length).is_multiple_of(record_size);
+        if offset < header_length || offset > self.check.length || misaligned {
+            return Result::Err(anyhow!("Offset {} isn't between records", offset));
+        }
+        reader.seek(SeekFrom::Start(offset))?;
+        self.check.valid_length = offset;
+        self.check.num_particles = num_particles;
+        return Result::Ok(());
+    }
+
+    // Once reading has finished, carry on from the end of the last valid
+    // record to pick up anything written to the file since.
+    fn reopen(&mut self, flake_file: &str) -> Result<()> {
+        let mut f = File::open(flake_file)
+            .context(format!("Unable to open flake file: {}", flake_file))?;
+        let length = f.metadata().context("Unable to read flake file metadata")?.len();
+        if length < self.check.valid_length {
+            return Result::Err(anyhow!(
+                "Flake file has been cut back to {} bytes since it was read",
+                length
+            ));
+        }
+        f.seek(SeekFrom::Start(self.check.valid_length))?;
+        self.reader = Option::Some(BufReader::new(f));
+        self.check.length = length;
+        self.check.problem = Option::None;
+        self.check.cut_short = false;
+        self.finished = false;
+        return Result::Ok(());
+    }
+}
+
+impl Iterator for Particles {
+    type Item = Result<Particle>;
+
+    fn next(&mut self) -> Option<Result<Particle>> {
+        while self.position == self.end {
+            if self.finished {
+                return Option::None;
+            }
+            match self.read_batch() {
+                Result::Ok(true) => {}
+                Result::Ok(false) => {
+                    self.finished = true;
+                    if self.check.cut_short && self.follow {
+                        return Option::None;
+                    }
+                    if self.check.cut_short {
+                        if let Option::Some(flake_file) = &self.unlocked_file {
+                            match is_locked(flake_file) {
+                                // The rest of the block hasn't been written yet
+                                Result::Ok(true) => return Option::None,
+                                Result::Ok(false) => {}
+                                Result::Err(err) => return Option::Some(Result::Err(err)),
+                            }
+                        }
+                    }
+                    return self.check.problem.as_ref().map(|problem| {
+                        Result::Err(anyhow!(
+                            "Flake file is damaged after {} particles: {}. Run fsck with \
+                             --repair to remove the damaged part",
+                            self.check.num_particles,
+                            problem
+                        ))
+                    });
+                }
+                Result::Err(err) => {
+                    self.finished = true;
+                    return Option::Some(Result::Err(err));
+                }
+            }
+        }
+
+        if self.format.is_compact() {
+            self.position += 1;
+            return Option::Some(Result::Ok(self.decoded[self.position - 1]));
+        }
+        let record_size = self.format.record_size();
+        let particle = self.format.decode(&self.buf[self.position..self.position + record_size]);
+        self.position += record_size;
+        return Option::Some(Result::Ok(particle));
+    }
+}
+
+// Reads the particles of a flake file as they are added to it, like tail -f.
+pub struct Follower {
+    flake_file: String,
+    // None until the flake file has a whole header.
+    particles: Option<Particles>,
+}
+
+impl Follower {
+    // Returns the particles added to the flake file since the last call, or
+    // all of them on the first call. Particles still buffered by the writer,
+    // or in a block it is part way through writing, are returned by a later
+    // call once they have been written.
+    pub fn read_new(&mut self) -> Result<Vec<Particle>> {
+        match &mut self.particles {
+            Option::Some(particles) => particles.reopen(&self.flake_file)?,
+            Option::None => self.particles = self.open()?,
+        }
+        let mut new_particles = Vec::new();
+        if let Option::Some(particles) = &mut self.particles {
+            for particle in particles {
+                new_particles.push(particle?);
+            }
+        }
+        return Result::Ok(new_particles);
+    }
+
+    // Returns the number of particles read so far.
+    pub fn num_particles(&self) -> u64 {
+        return self.particles.as_ref().map_or(0, |particles| particles.check.num_particles);
+    }
+
+    fn open(&self) -> Result<Option<Particles>> {
+        if !Path::new(&self.flake_file).exists() {
+            return Result::Ok(Option::None);
+        }
+        let length = fs::metadata(&self.flake_file)
+            .context("Unable to read flake file metadata")?
+            .len();
+        if length == 0 {
+            return Result::Ok(Option::None);
+        }
+        return match Particles::open(&self.flake_file, false) {
+            Result::Ok(mut particles) => {
+                particles.follow = true;
+                Result::Ok(Option::Some(particles))
+            }
+            // The header may not have been written in full yet
+            Result::Err(_) if is_locked(&self.flake_file)? => Result::Ok(Option::None),
+            Result::Err(err) => Result::Err(err),
+        };
+    }
+}
 
 pub struct Flake {
     flake_file: String,
-    buffered_points: Vec<Point>,
+    // The format of the flake file, once it is known.
+    format: Option<Format>,
+    // Free-form information about how the flake was made, such as the
+    // symmetry and the version of the tool that made it.
+    metadata: BTreeMap<String, String>,
+    flush_policy: FlushPolicy,
+    // The format to use if the flake file is new.
+    new_format: Format,
+    // For compact files, the number of particles in the flake file, once it
+    // is needed.
+    num_written: Option<u64>,
+    buffered_particles: Vec<Particle>,
+    // When the oldest buffered particle was added.
+    buffered_since: Instant,
+    // The flake file, held open while this flake has it locked for writing.
+    lock: Option<File>,
 }
 
 impl Flake {
-    // Construct a new flake, using the given file as storage.
-    pub fn new(flake_file: &str) -> Flake {
-        return Flake {
+    // Construct a new flake, using the given file as storage. If the file
+    // already exists its header is read, and it must be a flake file.
+    pub fn new(flake_file: &str) -> Result<Flake> {
+        let mut flake = Flake {
             flake_file: flake_file.to_string(),
-            buffered_points: Vec::new(),
+            format: Option::None,
+            metadata: BTreeMap::new(),
+            flush_policy: FlushPolicy::default(),
+            new_format: Format::CURRENT,
+            num_written: Option::None,
+            buffered_particles: Vec::new(),
+            buffered_since: Instant::now(),
+            lock: Option::None,
         };
+        flake.load_header()?;
+        return Result::Ok(flake);
     }
 
-    // Read any existing points from the flake file.
-    pub fn get_points(&self) -> Result<Vec<Point>> {
-        let mut points = Vec::new();
+    // Read the format and metadata from the header of the flake file, if it
+    // has one.
+    fn load_header(&mut self) -> Result<()> {
         if !Path::new(&self.flake_file).exists() {
-            return Result::Ok(points);
+            return Result::Ok(());
         }
 
-        let mut f = File::open(&self.flake_file)?;
-        let mut buf: [u8; 16 * MAX_BUFFERED_POINTS] = [0; 16 * MAX_BUFFERED_POINTS];
-        let mut f64_buf: [u8; 8] = [0; 8];
-        loop {
-            let bytes_read = f.read(&mut buf).context("Unable to read from flake file")?;
-            if bytes_read == 0 {
-                return Result::Ok(points);
-            }
+        let mut f = File::open(&self.flake_file)
+            .context(format!("Unable to open flake file: {}", self.flake_file))?;
+        if f.metadata().context("Unable to read flake file metadata")?.len() > 0 {
+            let (format, metadata) = read_header(&mut f)
+                .context(format!("Unable to read flake file: {}", self.flake_file))?;
+            self.format = Option::Some(format);
+            self.metadata = metadata;
+        }
+        return Result::Ok(());
+    }
 
-            for i in (0..bytes_read).step_by(16) {
-                f64_buf.clone_from_slice(&buf[i..i + 8]);
-                let x: f64 = f64::from_be_bytes(f64_buf);
-                f64_buf.clone_from_slice(&buf[i + 8..i + 16]);
-                let y: f64 = f64::from_be_bytes(f64_buf);
-                points.push(Point { x, y });
+    // Lock the flake file for writing, creating it if it doesn't exist yet,
+    // so that no other process can add to it while this flake is in use. The
+    // lock is advisory and released when the flake is dropped. Readers don't
+    // take it, but they do treat a block cut short at the end of a locked
+    // file as not yet written rather than damaged.
+    pub fn lock(&mut self) -> Result<()> {
+        if self.lock.is_some() {
+            return Result::Ok(());
+        }
+        let f = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&self.flake_file)
+            .context(format!("Unable to open flake file: {}", self.flake_file))?;
+        match f.try_lock() {
+            Result::Ok(()) => {}
+            Result::Err(TryLockError::WouldBlock) => {
+                return Result::Err(anyhow!(
+                    "Flake file is already being written by another process: {}",
+                    self.flake_file
+                ));
+            }
+            Result::Err(TryLockError::Error(err)) => {
+                return Result::Err(err).context("Unable to lock flake file");
             }
         }
+
+        // Another process may have added to the file since it was opened
+        self.load_header()?;
+        self.lock = Option::Some(f);
+        return Result::Ok(());
+    }
+
+    // Returns true if another process has the flake file locked for writing.
+    pub fn is_being_written(&self) -> Result<bool> {
+        if self.lock.is_some() {
+            return Result::Ok(false);
+        }
+        return is_locked(&self.flake_file);
+    }
+
+    // Returns the location of the flake file.
+    pub fn path(&self) -> &str {
+        return &self.flake_file;
+    }
+
+    // Returns the flake's metadata. Files from before metadata was added
+    // have none.
+    pub fn metadata(&self) -> &BTreeMap<String, String> {
+        return &self.metadata;
+    }
+
+    pub fn set_flush_policy(&mut self, flush_policy: FlushPolicy) {
+        self.flush_policy = flush_policy;
+    }
+
+    // Use the compact encoding for the flake file, which takes much less
+    // space but stores positions relative to parents as f32s. Can only be
+    // used for a new flake.
+    pub fn use_compact_encoding(&mut self) -> Result<()> {
+        if !self.is_new() {
+            return Result::Err(anyhow!(
+                "Unable to change the encoding of existing flake file: {}",
+                self.flake_file
+            ));
+        }
+        self.new_format = Format::COMPACT;
+        return Result::Ok(());
+    }
+
+    // Returns true if the flake file records the species of each particle.
+    // Particles read from files that don't have species 0.
+    pub fn has_species(&self) -> bool {
+        return self.format.unwrap_or(self.new_format).has_species();
+    }
+
+    // Returns true if the flake file records the parent, steps and time of
+    // each particle. Particles read from files that don't have no parent, 0
+    // steps and no time.
+    pub fn has_provenance(&self) -> bool {
+        return self.format.unwrap_or(self.new_format).has_provenance();
+    }
+
+    // Returns true if nothing has been written to the flake file yet.
+    pub fn is_new(&self) -> bool {
+        return self.format.is_none();
+    }
+
+    // Set a metadata entry. Metadata is written along with the first
+    // particles, so can only be set on a new flake.
+    pub fn set_metadata(&mut self, key: &str, value: &str) -> Result<()> {
+        if !self.is_new() {
+            return Result::Err(anyhow!(
+                "Unable to set metadata on existing flake file: {}",
+                self.flake_file
+            ));
+        }
+        if key.is_empty() {
+            return Result::Err(anyhow!("Metadata keys can't be empty"));
+        }
+        self.metadata.insert(key.to_string(), value.to_string());
+        return Result::Ok(());
+    }
+
+    // Returns the length of the flake file. Buffered points that haven't
+    // been flushed aren't included.
+    pub fn length(&self) -> Result<u64> {
+        if !Path::new(&self.flake_file).exists() {
+            return Result::Ok(0);
+        }
+        let metadata = fs::metadata(&self.flake_file).context("Unable to read flake file metadata")?;
+        return Result::Ok(metadata.len());
+    }
+
+    // Returns a CRC-32 checksum of the start and end of the first length
+    // bytes of the flake file, or None if the file is shorter than that.
+    // This takes the same time however long the file is. It can miss changes
+    // to the middle of the file, but does catch the file having been cut
+    // back and written again, or replaced, which are the ways flake files
+    // change other than by being added to.
+    pub fn fingerprint(&self, length: u64) -> Result<Option<u32>> {
+        if self.length()? < length {
+            return Result::Ok(Option::None);
+        }
+        if length == 0 {
+            return Result::Ok(Option::Some(0));
+        }
+
+        let mut f = File::open(&self.flake_file)?;
+        let mut hasher = crc32fast::Hasher::new();
+        let start_len = length.min(FINGERPRINT_BYTES);
+        let end_start = length.saturating_sub(FINGERPRINT_BYTES).max(start_len);
+        for (start, end) in [(0, start_len), (end_start, length)].iter() {
+            let mut buf = vec![0; (end - start) as usize];
+            f.seek(SeekFrom::Start(*start))?;
+            f.read_exact(&mut buf).context("Unable to read from flake file")?;
+            hasher.update(&buf);
+        }
+        return Result::Ok(Option::Some(hasher.finalize()));
+    }
+
+    // Read any existing points from the flake file.
+    pub fn get_points(&self) -> Result<Vec<Point>> {
+        let mut points = Vec::new();
+        for particle in self.iter_particles()? {
+            points.push(particle?.point);
+        }
+        return Result::Ok(points);
+    }
+
+    // Returns an iterator over the particles in the flake file, in order.
+    // Only one block of the file is held in memory at a time, so this can
+    // read flakes of any size. If the file is damaged the iterator returns
+    // an error after the last valid particle, then stops.
+    pub fn iter_particles(&self) -> Result<Particles> {
+        return Particles::open(&self.flake_file, self.lock.is_some());
+    }
+
+    // Returns an iterator over the particles after the first length bytes of
+    // the flake file, without reading those. The first length bytes must
+    // hold the first num_particles particles, as they did when the flake was
+    // last flushed, and the particles returned are numbered after them.
+    pub fn iter_particles_after(&self, length: u64, num_particles: u64) -> Result<Particles> {
+        let mut particles = self.iter_particles()?;
+        particles.seek(length, num_particles)?;
+        return Result::Ok(particles);
+    }
+
+    // Returns a follower that reads the particles of the flake file as they
+    // are added by another process. The file need not exist yet.
+    pub fn follow(&self) -> Follower {
+        return Follower {
+            flake_file: self.flake_file.clone(),
+            particles: Option::None,
+        };
+    }
+
+    // Returns an iterator over the points in the flake file, in order. See
+    // iter_particles.
+    pub fn iter_points(&self) -> Result<impl Iterator<Item = Result<Point>>> {
+        let particles = self.iter_particles()?;
+        return Result::Ok(particles.map(|particle| particle.map(|particle| particle.point)));
+    }
+
+    // Check the flake file for damage, such as a write cut short by a crash.
+    pub fn check(&self) -> Result<Check> {
+        let mut particles = self.iter_particles()?;
+        while particles.read_batch()? {}
+        return Result::Ok(particles.check);
+    }
+
+    // Cut the flake file back to the end of the last valid record found by
+    // the given check.
+    pub fn repair(&mut self, check: &Check) -> Result<()> {
+        let f = OpenOptions::new()
+            .write(true)
+            .open(&self.flake_file)
+            .context(format!("Unable to open flake file: {}", self.flake_file))?;
+        f.set_len(check.valid_length)
+            .context("Unable to truncate flake file")?;
+        return Result::Ok(());
     }
 
     // Add a point to the flake. This may write the data out to the flake
     // file or may buffer internally. See also the flush method.
     pub fn add_point(&mut self, point: &Point) -> Result<()> {
-        self.buffered_points.push(*point);
-        if self.buffered_points.len() >= MAX_BUFFERED_POINTS {
+        return self.add_particle(&Particle::new(*point));
+    }
+
+    // Add a particle to the flake. Flake files written by older versions
+    // only store the parts of the particle that they have room for.
+    pub fn add_particle(&mut self, particle: &Particle) -> Result<()> {
+        if self.buffered_particles.is_empty() {
+            self.buffered_since = Instant::now();
+        }
+        self.buffered_particles.push(*particle);
+
+        let waited_too_long = match self.flush_policy.max_time {
+            Option::Some(max_time) => self.buffered_since.elapsed() >= max_time,
+            Option::None => false,
+        };
+        if self.buffered_particles.len() >= self.flush_policy.max_particles || waited_too_long {
             self.flush().context("Unable to flush")?;
         }
         return Result::Ok(());
     }
 
     // Write to the flake file any points that have been buffered in memory.
+    //
+    // Each flush appends whole blocks in a single write. If the write fails
+    // the file is cut back to where it was, and the particles stay buffered,
+    // so the file always holds a valid prefix of the flake. A crash part way
+    // through a write can still leave a damaged block at the end, which
+    // fsck can remove. The flake file is locked first, if it isn't already,
+    // and stays locked until the flake is dropped.
     pub fn flush(&mut self) -> Result<()> {
-        let mut f = std::fs::OpenOptions::new()
-            .write(true)
+        // Make sure no other process is adding to the file at the same time
+        self.lock()?;
+        let mut f = OpenOptions::new()
+            .read(true)
             .append(true)
             .create(true)
             .open(&self.flake_file)
             .context(format!("Unable to open flake file: {}", self.flake_file))?;
+        let length = f.metadata().context("Unable to read flake file metadata")?.len();
+
+        let mut buf = Vec::with_capacity(
+            HEADER_SIZE + self.buffered_particles.len() * MAX_RECORD_SIZE + BLOCK_OVERHEAD,
+        );
+        let format = match self.format {
+            Option::Some(format) => format,
+            Option::None => {
+                if length == 0 {
+                    buf.extend_from_slice(MAGIC);
+                    buf.extend_from_slice(&self.new_format.version().to_be_bytes());
+                    encode_metadata(&self.metadata, &mut buf);
+                    self.new_format
+                } else {
+                    read_header(&mut f).context("Unable to read flake file header")?.0
+                }
+            }
+        };
+
+        // Compact blocks refer to parents by their index, so the existing
+        // particles have to be counted first when adding to a file
+        if format.is_compact() && self.num_written.is_none() {
+            let mut num_written = 0;
+            if length > 0 {
+                for particle in self.iter_particles()? {
+                    particle?;
+                    num_written += 1;
+                }
+            }
+            self.num_written = Option::Some(num_written);
+        }
 
-        let mut buf = vec![0; self.buffered_points.len() * 16];
-        for (i, point) in self.buffered_points.iter().enumerate() {
-            let index = i * 16;
-            buf[index..index + 8].clone_from_slice(&point.x.to_be_bytes());
-            buf[index + 8..index + 16].clone_from_slice(&point.y.to_be_bytes());
+        if format.is_compact() {
+            let mut first_index = self.num_written.unwrap();
+            for block in self.buffered_particles.chunks(MAX_BLOCK_RECORDS) {
+                let start = buf.len();
+                let encoded = compact::encode_block(block, first_index);
+                first_index += block.len() as u64;
+                buf.extend_from_slice(&(block.len() as u32).to_be_bytes());
+                buf.extend_from_slice(&(encoded.len() as u32).to_be_bytes());
+                buf.extend_from_slice(&encoded);
+                let crc = block_checksum(&buf[start..]);
+                buf.extend_from_slice(&crc.to_be_bytes());
+            }
+        } else if format.has_blocks() {
+            for block in self.buffered_particles.chunks(MAX_BLOCK_RECORDS) {
+                let start = buf.len();
+                buf.extend_from_slice(&(block.len() as u32).to_be_bytes());
+                for particle in block {
+                    format.encode(particle, &mut buf);
+                }
+                let crc = block_checksum(&buf[start..]);
+                buf.extend_from_slice(&crc.to_be_bytes());
+            }
+        } else {
+            for particle in &self.buffered_particles {
+                format.encode(particle, &mut buf);
+            }
         }
-        f.write(&buf).context("Unable to write buf to flake file")?;
 
-        self.buffered_points.truncate(0);
+        if let Result::Err(err) = f.write_all(&buf) {
+            // Leave the file as it was, so the next flush doesn't append
+            // after part of a block
+            f.set_len(length)
+                .context("Unable to undo failed write to flake file")?;
+            return Result::Err(err).context("Unable to write to flake file");
+        }
+        if self.flush_policy.sync {
+            f.sync_data().context("Unable to sync flake file")?;
+            if length == 0 {
+                sync_parent_dir(&self.flake_file)?;
+            }
+        }
+
+        self.format = Option::Some(format);
+        if let Option::Some(num_written) = &mut self.num_written {
+            *num_written += self.buffered_particles.len() as u64;
+        }
+        self.buffered_particles.truncate(0);
         return Result::Ok(());
     }
 }
 
+// Returns true if some flake has the given file locked for writing.
+fn is_locked(flake_file: &str) -> Result<bool> {
+    if !Path::new(flake_file).exists() {
+        return Result::Ok(false);
+    }
+    let f = File::open(flake_file)
+        .context(format!("Unable to open flake file: {}", flake_file))?;
+    return match f.try_lock_shared() {
+        Result::Ok(()) => Result::Ok(false),
+        Result::Err(TryLockError::WouldBlock) => Result::Ok(true),
+        Result::Err(TryLockError::Error(err)) => {
+            Result::Err(err).context("Unable to check lock on flake file")
+        }
+    };
+}
+
+// Wait for the directory entry of a newly created file to reach the disk.
+fn sync_parent_dir(file: &str) -> Result<()> {
+    let parent = match Path::new(file).parent() {
+        Option::Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    File::open(parent)
+        .and_then(|dir| dir.sync_all())
+        .context("Unable to sync flake file directory")?;
+    return Result::Ok(());
+}
+
+// Work out the format of a flake file from its header, and read its metadata.
+// Leaves the reader positioned at the start of the first record.
+fn read_header<R: Read + Seek>(reader: &mut R) -> Result<(Format, BTreeMap<String, String>)> {
+    let mut header: [u8; HEADER_SIZE] = [0; HEADER_SIZE];
+    let mut header_len = 0;
+    while header_len < HEADER_SIZE {
+        let bytes_read = reader.read(&mut header[header_len..])?;
+        if bytes_read == 0 {
+            break;
+        }
+        header_len += bytes_read;
+    }
+
+    if header_len < HEADER_SIZE || &header[0..8] != MAGIC {
+        // Headerless files have no magic number to tell them apart from any
+        // other file, but they must at least hold whole records of plausible
+        // points. Only the first block's worth is checked, as the whole file
+        // is read again each time it is opened.
+        let record_size = Format::Legacy.record_size();
+        let length = reader.seek(SeekFrom::End(0))?;
+        if length % record_size as u64 != 0 {
+            return Result::Err(anyhow!("Not a flake file"));
+        }
+        reader.seek(SeekFrom::Start(0))?;
+        let mut buf = vec![0; MAX_BLOCK_RECORDS * record_size];
+        let len = read_fully(reader, &mut buf)?;
+        for record in buf[..len].chunks_exact(record_size) {
+            let point = Format::Legacy.decode(record).point;
+            let is_plausible = |coordinate: f64| coordinate.abs() <= MAX_HEADERLESS_COORDINATE;
+            if !is_plausible(point.x) || !is_plausible(point.y) {
+                return Result::Err(anyhow!("Not a flake file"));
+            }
+        }
+        reader.seek(SeekFrom::Start(0))?;
+        return Result::Ok((Format::Legacy, BTreeMap::new()));
+    }
+
+    let mut version_buf: [u8; 4] = [0; 4];
+    version_buf.clone_from_slice(&header[8..12]);
+    let format = Format::from_version(u32::from_be_bytes(version_buf))?;
+    if !format.has_metadata() {
+        return Result::Ok((format, BTreeMap::new()));
+    }
+    let metadata = read_metadata(reader).context("Unable to read flake metadata")?;
+    return Result::Ok((format, metadata));
+}
+
+// Append the given metadata to the given buffer, in the form read by
+// read_metadata.
+fn encode_metadata(metadata: &BTreeMap<String, String>, buf: &mut Vec<u8>) {
+    let mut block = Vec::new();
+    block.extend_from_slice(&(metadata.len() as u32).to_be_bytes());
+    for (key, value) in metadata {
+        for text in &[key, value] {
+            block.extend_from_slice(&(text.len() as u32).to_be_bytes());
+            block.extend_from_slice(text.as_bytes());
+        }
+    }
+    buf.extend_from_slice(&(block.len() as u32).to_be_bytes());
+    buf.extend_from_slice(&block);
+}
+
+fn read_metadata<R: Read>(reader: &mut R) -> Result<BTreeMap<String, String>> {
+    let mut length_buf: [u8; 4] = [0; 4];
+    reader.read_exact(&mut length_buf).context("Metadata is truncated")?;
+    let length = u32::from_be_bytes(length_buf);
+    if length > MAX_METADATA_SIZE {
+        return Result::Err(anyhow!("Metadata is too large: {} bytes", length));
+    }
+    let mut block = vec![0; length as usize];
+    reader.read_exact(&mut block).context("Metadata is truncated")?;
+
+    let mut position = 0;
+    let num_entries = u32::from_be_bytes(take(&block, &mut position)?);
+    let mut texts = Vec::new();
+    for _i in 0..num_entries as u64 * 2 {
+        let text_length = u32::from_be_bytes(take(&block, &mut position)?) as usize;
+        if block.len() - position < text_length {
+            return Result::Err(anyhow!("Metadata is corrupt"));
+        }
+        let text = std::str::from_utf8(&block[position..position + text_length])
+            .context("Metadata is not valid UTF-8")?;
+        texts.push(text.to_string());
+        position += text_length;
+    }
+    if position != block.len() {
+        return Result::Err(anyhow!("Metadata is corrupt"));
+    }
+
+    let mut metadata = BTreeMap::new();
+    for entry in texts.chunks_exact(2) {
+        metadata.insert(entry[0].clone(), entry[1].clone());
+    }
+    return Result::Ok(metadata);
+}
+
+// Read N bytes from the given position in a metadata block, moving the
+// position past them.
+fn take<const N: usize>(block: &[u8], position: &mut usize) -> Result<[u8; N]> {
+    if block.len() - *position < N {
+        return Result::Err(anyhow!("Metadata is corrupt"));
+    }
+    let mut bytes = [0; N];
+    bytes.copy_from_slice(&block[*position..*position + N]);
+    *position += N;
+    return Result::Ok(bytes);
+}
+
 #[cfg(test)]
 mod tests {
+    use anyhow::Result;
     use rand::Rng;
 
-    use super::Flake;
+    use std::fs;
+    use std::io::Read;
+    use std::path::Path;
+    use std::time::Duration;
+
+    use super::{read_fully, Flake, FlushPolicy, Format, Particle, MAX_RECORD_SIZE};
     use crate::point::Point;
     use crate::test_utils::test::{time_func, with_test_dir};
 
+    impl Flake {
+        // Read any existing particles from the flake file.
+        pub fn get_particles(&self) -> Result<Vec<Particle>> {
+            return self.iter_particles()?.collect();
+        }
+    }
+
     #[test]
     fn get_points_for_empty_file() {
         with_test_dir(|test_dir: &str| {
             let flake_file = format!("{}/test.flake", test_dir);
-            let flake = Flake::new(&flake_file);
+            let flake = Flake::new(&flake_file).expect("Unable to open flake");
             assert_eq!(0, flake.get_points().expect("Unable to get points").len());
         });
     }
@@ -101,7 +1131,7 @@ mod tests {
     fn write_points_to_new_file() {
         with_test_dir(|test_dir: &str| {
             let flake_file = format!("{}/test.flake", test_dir);
-            let mut flake = Flake::new(&flake_file);
+            let mut flake = Flake::new(&flake_file).expect("Unable to open flake");
             flake
                 .add_point(&Point { x: 0.0, y: 0.0 })
                 .expect("Unable to add point");
@@ -114,18 +1144,22 @@ mod tests {
         with_test_dir(|test_dir: &str| {
             let flake_file = format!("{}/test.flake", test_dir);
 
-            let mut flake = Flake::new(&flake_file);
+            let mut flake = Flake::new(&flake_file).expect("Unable to open flake");
             flake
                 .add_point(&Point { x: 0.0, y: 0.0 })
                 .expect("Unable to add point");
             flake.flush().expect("Unable to flush");
 
-            // Do the same thing but with a new flake object
-            let mut flake = Flake::new(&flake_file);
-            flake
+            // Do the same thing but with a new flake object, which can't
+            // write until the first has released its lock
+            let mut other = Flake::new(&flake_file).expect("Unable to open flake");
+            other
                 .add_point(&Point { x: 1.0, y: 1.0 })
                 .expect("Unable to add point");
-            flake.flush().expect("Unable to flush");
+            assert!(other.flush().is_err());
+            drop(flake);
+            other.flush().expect("Unable to flush");
+            assert_eq!(2, other.get_points().expect("Unable to get points").len());
         });
     }
 
@@ -133,7 +1167,7 @@ mod tests {
     fn roundtrip() {
         with_test_dir(|test_dir: &str| {
             let flake_file = format!("{}/test.flake", test_dir);
-            let mut flake = Flake::new(&flake_file);
+            let mut flake = Flake::new(&flake_file).expect("Unable to open flake");
 
             let num_points = 1500;
             for i in 0..num_points {
@@ -155,21 +1189,653 @@ mod tests {
         });
     }
 
+    #[test]
+    fn roundtrip_particles() {
+        with_test_dir(|test_dir: &str| {
+            let flake_file = format!("{}/test.flake", test_dir);
+            let mut flake = Flake::new(&flake_file).expect("Unable to open flake");
+
+            for i in 0..10 {
+                flake
+                    .add_particle(&Particle {
+                        species: i % 3,
+                        parent: if i == 0 { Option::None } else { Option::Some(i as u64 - 1) },
+                        steps: i as u64 * 100,
+                        time: if i % 2 == 0 { Option::Some(i as f64) } else { Option::None },
+                        ..Particle::new(Point {
+                            x: i as f64,
+                            y: 0.0,
+                        })
+                    })
+                    .expect("Unable to add particle");
+            }
+            flake.flush().expect("Unable to flush");
+
+            let particles = Flake::new(&flake_file).expect("Unable to open flake")
+                .get_particles()
+                .expect("Unable to get particles");
+            assert_eq!(10, particles.len());
+            for (i, particle) in particles.iter().enumerate() {
+                assert_eq!(i as f64, particle.point.x);
+                assert_eq!((i % 3) as u8, particle.species);
+                assert_eq!(i as u64 * 100, particle.steps);
+            }
+            assert_eq!(true, particles[0].parent.is_none());
+            assert_eq!(Option::Some(4), particles[5].parent);
+            assert_eq!(Option::Some(4.0), particles[4].time);
+            assert_eq!(true, particles[5].time.is_none());
+        });
+    }
+
+    #[test]
+    fn read_and_append_to_legacy_file() {
+        with_test_dir(|test_dir: &str| {
+            let flake_file = format!("{}/test.flake", test_dir);
+
+            // Files from before the header existed are just (x, y) pairs
+            let mut legacy = Vec::new();
+            for value in &[1.0_f64, 2.0, 3.0, 4.0] {
+                legacy.extend_from_slice(&value.to_be_bytes());
+            }
+            fs::write(&flake_file, &legacy).expect("Unable to write legacy file");
+
+            let mut flake = Flake::new(&flake_file).expect("Unable to open flake");
+            flake
+                .add_particle(&Particle {
+                    species: 2,
+                    ..Particle::new(Point { x: 5.0, y: 6.0 })
+                })
+                .expect("Unable to add particle");
+            flake.flush().expect("Unable to flush");
+
+            let particles = flake.get_particles().expect("Unable to get particles");
+            assert_eq!(3, particles.len());
+            assert_eq!(1.0, particles[0].point.x);
+            assert_eq!(4.0, particles[1].point.y);
+            assert_eq!(5.0, particles[2].point.x);
+            assert_eq!(0, particles[2].species);
+        });
+    }
+
+    #[test]
+    fn read_version_1_file() {
+        with_test_dir(|test_dir: &str| {
+            let flake_file = format!("{}/test.flake", test_dir);
+
+            let mut contents = b"SNOWFLAK".to_vec();
+            contents.extend_from_slice(&1_u32.to_be_bytes());
+            contents.extend_from_slice(&1.0_f64.to_be_bytes());
+            contents.extend_from_slice(&2.0_f64.to_be_bytes());
+            contents.push(3);
+            fs::write(&flake_file, &contents).expect("Unable to write file");
+
+            let particles = Flake::new(&flake_file).expect("Unable to open flake")
+                .get_particles()
+                .expect("Unable to get particles");
+            assert_eq!(1, particles.len());
+            assert_eq!(2.0, particles[0].point.y);
+            assert_eq!(3, particles[0].species);
+            assert_eq!(true, particles[0].parent.is_none());
+        });
+    }
+
+    #[test]
+    fn roundtrip_metadata() {
+        with_test_dir(|test_dir: &str| {
+            let flake_file = format!("{}/test.flake", test_dir);
+            let mut flake = Flake::new(&flake_file).expect("Unable to open flake");
+            assert_eq!(true, flake.is_new());
+            flake.set_metadata("symmetry", "rotational 6").expect("Unable to set metadata");
+            flake.set_metadata("note", "").expect("Unable to set metadata");
+            assert!(flake.set_metadata("", "value").is_err());
+            flake
+                .add_point(&Point { x: 1.0, y: 2.0 })
+                .expect("Unable to add point");
+            flake.flush().expect("Unable to flush");
+            assert!(flake.set_metadata("seed", "1").is_err());
+
+            let flake = Flake::new(&flake_file).expect("Unable to open flake");
+            assert_eq!(false, flake.is_new());
+            assert_eq!(2, flake.metadata().len());
+            assert_eq!("rotational 6", flake.metadata()["symmetry"]);
+            assert_eq!("", flake.metadata()["note"]);
+            let points = flake.get_points().expect("Unable to get points");
+            assert_eq!(1, points.len());
+            assert_eq!(2.0, points[0].y);
+        });
+    }
+
+    #[test]
+    fn read_version_2_file() {
+        with_test_dir(|test_dir: &str| {
+            let flake_file = format!("{}/test.flake", test_dir);
+
+            let mut contents = b"SNOWFLAK".to_vec();
+            contents.extend_from_slice(&2_u32.to_be_bytes());
+            for value in &[1.0_f64, 2.0] {
+                contents.extend_from_slice(&value.to_be_bytes());
+            }
+            contents.push(1);
+            contents.extend_from_slice(&7_u64.to_be_bytes());
+            contents.extend_from_slice(&100_u64.to_be_bytes());
+            contents.extend_from_slice(&f64::NAN.to_be_bytes());
+            fs::write(&flake_file, &contents).expect("Unable to write file");
+
+            let flake = Flake::new(&flake_file).expect("Unable to open flake");
+            assert_eq!(0, flake.metadata().len());
+            let particles = flake.get_particles().expect("Unable to get particles");
+            assert_eq!(1, particles.len());
+            assert_eq!(Option::Some(7), particles[0].parent);
+            assert_eq!(100, particles[0].steps);
+        });
+    }
+
+    #[test]
+    fn reject_invalid_files() {
+        with_test_dir(|test_dir: &str| {
+            let flake_file = format!("{}/test.flake", test_dir);
+
+            // Whole headerless records, but of text rather than points, and
+            // not a whole number of them
+            fs::write(&flake_file, "not a flake file").expect("Unable to write file");
+            assert!(Flake::new(&flake_file).is_err());
+            fs::write(&flake_file, "not a flake file!").expect("Unable to write file");
+            assert!(Flake::new(&flake_file).is_err());
+
+            // Headerless records that aren't numbers
+            let mut contents = 1.0f64.to_be_bytes().to_vec();
+            contents.extend_from_slice(&f64::NAN.to_be_bytes());
+            fs::write(&flake_file, &contents).expect("Unable to write file");
+            assert!(Flake::new(&flake_file).is_err());
+
+            let header = |version: u32| {
+                let mut contents = b"SNOWFLAK".to_vec();
+                contents.extend_from_slice(&version.to_be_bytes());
+                return contents;
+            };
+            fs::write(&flake_file, header(6)).expect("Unable to write file");
+            assert!(Flake::new(&flake_file).is_err());
+
+            // Metadata missing, too long, with a key running past its end,
+            // and with text that isn't UTF-8
+            let mut contents = header(3);
+            fs::write(&flake_file, &contents).expect("Unable to write file");
+            assert!(Flake::new(&flake_file).is_err());
+
+            contents.extend_from_slice(&u32::MAX.to_be_bytes());
+            fs::write(&flake_file, &contents).expect("Unable to write file");
+            assert!(Flake::new(&flake_file).is_err());
+
+            let mut contents = header(3);
+            for value in &[12_u32, 1, 100, 0] {
+                contents.extend_from_slice(&value.to_be_bytes());
+            }
+            fs::write(&flake_file, &contents).expect("Unable to write file");
+            assert!(Flake::new(&flake_file).is_err());
+
+            let mut contents = header(3);
+            for value in &[14_u32, 1, 1] {
+                contents.extend_from_slice(&value.to_be_bytes());
+            }
+            contents.push(0xff);
+            contents.extend_from_slice(&1_u32.to_be_bytes());
+            contents.push(b'a');
+            fs::write(&flake_file, &contents).expect("Unable to write file");
+            assert!(Flake::new(&flake_file).is_err());
+            contents[24] = b'k';
+            fs::write(&flake_file, &contents).expect("Unable to write file");
+            assert_eq!(
+                "a",
+                Flake::new(&flake_file).expect("Unable to open flake").metadata()["k"]
+            );
+        });
+    }
+
+    // Write a flake with the given number of points and return its file.
+    fn write_flake(test_dir: &str, num_points: usize) -> String {
+        let flake_file = format!("{}/test.flake", test_dir);
+        let mut flake = Flake::new(&flake_file).expect("Unable to open flake");
+        for i in 0..num_points {
+            flake
+                .add_point(&Point {
+                    x: i as f64,
+                    y: 0.0,
+                })
+                .expect("Unable to add point");
+        }
+        flake.flush().expect("Unable to flush");
+        return flake_file;
+    }
+
+    #[test]
+    fn detect_and_repair_truncated_block() {
+        with_test_dir(|test_dir: &str| {
+            // Two whole blocks then the start of a third, as if a flush was
+            // cut short
+            let flake_file = write_flake(test_dir, 2500);
+            let contents = fs::read(&flake_file).expect("Unable to read file");
+            fs::write(&flake_file, &contents[..contents.len() - 100])
+                .expect("Unable to write file");
+
+            let mut flake = Flake::new(&flake_file).expect("Unable to open flake");
+            assert!(flake.get_points().is_err());
+            let check = flake.check().expect("Unable to check flake");
+            assert_eq!(2000, check.num_particles);
+            assert_eq!(true, check.problem.is_some());
+
+            flake.repair(&check).expect("Unable to repair flake");
+            let check = flake.check().expect("Unable to check flake");
+            assert_eq!(true, check.problem.is_none());
+            assert_eq!(check.length, check.valid_length);
+
+            // Generation can carry on from the repaired file
+            flake
+                .add_point(&Point { x: -1.0, y: 0.0 })
+                .expect("Unable to add point");
+            flake.flush().expect("Unable to flush");
+            let points = flake.get_points().expect("Unable to get points");
+            assert_eq!(2001, points.len());
+            assert_eq!(1999.0, points[1999].x);
+            assert_eq!(-1.0, points[2000].x);
+        });
+    }
+
+    #[test]
+    fn detect_corrupt_block() {
+        with_test_dir(|test_dir: &str| {
+            let flake_file = write_flake(test_dir, 1500);
+            let mut contents = fs::read(&flake_file).expect("Unable to read file");
+            let length = contents.len();
+            contents[length - 50] ^= 1;
+            fs::write(&flake_file, &contents).expect("Unable to write file");
+
+            let flake = Flake::new(&flake_file).expect("Unable to open flake");
+            let check = flake.check().expect("Unable to check flake");
+            assert_eq!(1000, check.num_particles);
+            assert_eq!(true, check.problem.is_some());
+            assert!(flake.get_particles().is_err());
+
+            // A block claiming to hold too many records
+            let mut contents = fs::read(&flake_file).expect("Unable to read file");
+            let first_block = check.valid_length as usize;
+            contents[first_block..first_block + 4].copy_from_slice(&u32::MAX.to_be_bytes());
+            fs::write(&flake_file, &contents).expect("Unable to write file");
+            assert_eq!(true, flake.check().expect("Unable to check flake").problem.is_some());
+        });
+    }
+
+    #[test]
+    fn roundtrip_compact() {
+        with_test_dir(|test_dir: &str| {
+            let flake_file = format!("{}/test.flake", test_dir);
+            let mut flake = Flake::new(&flake_file).expect("Unable to open flake");
+            flake.use_compact_encoding().expect("Unable to use compact encoding");
+            let particle = |i: usize| Particle {
+                parent: if i == 0 { Option::None } else { Option::Some(i as u64 - 1) },
+                steps: i as u64,
+                ..Particle::new(Point {
+                    x: i as f64 * 1.1,
+                    y: 0.3,
+                })
+            };
+            for i in 0..1500 {
+                flake.add_particle(&particle(i)).expect("Unable to add particle");
+            }
+            flake.flush().expect("Unable to flush");
+            let compact_length = fs::metadata(&flake_file).expect("Unable to stat").len();
+            drop(flake);
+
+            // Appending continues in the compact encoding, numbering
+            // particles after those already written
+            let mut flake = Flake::new(&flake_file).expect("Unable to open flake");
+            assert!(flake.use_compact_encoding().is_err());
+            for i in 1500..2500 {
+                flake.add_particle(&particle(i)).expect("Unable to add particle");
+            }
+            flake.flush().expect("Unable to flush");
+
+            let flake = Flake::new(&flake_file).expect("Unable to open flake");
+            let check = flake.check().expect("Unable to check flake");
+            assert_eq!(2500, check.num_particles);
+            assert_eq!(true, check.problem.is_none());
+            let particles = flake.get_particles().expect("Unable to get particles");
+            assert_eq!(2500, particles.len());
+            for (i, actual) in particles.iter().enumerate() {
+                let expected = particle(i);
+                assert_eq!(expected.parent, actual.parent);
+                assert_eq!(expected.steps, actual.steps);
+                assert!(expected.point.distance(&actual.point) < 1e-3);
+            }
+
+            // Far smaller than the 41 bytes per particle of the standard
+            // encoding
+            assert!(compact_length * 3 < 1500 * 41);
+        });
+    }
+
+    #[test]
+    fn detect_corrupt_compact_block() {
+        with_test_dir(|test_dir: &str| {
+            let flake_file = format!("{}/test.flake", test_dir);
+            let mut flake = Flake::new(&flake_file).expect("Unable to open flake");
+            flake.use_compact_encoding().expect("Unable to use compact encoding");
+            for i in 0..1500 {
+                flake
+                    .add_point(&Point { x: i as f64, y: 0.0 })
+                    .expect("Unable to add point");
+            }
+            flake.flush().expect("Unable to flush");
+
+            let mut contents = fs::read(&flake_file).expect("Unable to read file");
+            let length = contents.len();
+            contents[length - 10] ^= 1;
+            fs::write(&flake_file, &contents).expect("Unable to write file");
+
+            let flake = Flake::new(&flake_file).expect("Unable to open flake");
+            let check = flake.check().expect("Unable to check flake");
+            assert_eq!(1000, check.num_particles);
+            assert_eq!(true, check.problem.is_some());
+            assert!(flake.get_particles().is_err());
+        });
+    }
+
+    #[test]
+    fn lock_excludes_other_writers() {
+        with_test_dir(|test_dir: &str| {
+            let flake_file = format!("{}/test.flake", test_dir);
+            let mut writer = Flake::new(&flake_file).expect("Unable to open flake");
+            writer.lock().expect("Unable to lock flake");
+            writer.lock().expect("Unable to lock flake again");
+            writer
+                .add_point(&Point { x: 1.0, y: 2.0 })
+                .expect("Unable to add point");
+            writer.flush().expect("Unable to flush");
+
+            let mut other = Flake::new(&flake_file).expect("Unable to open flake");
+            assert!(other.lock().is_err());
+            assert_eq!(true, other.is_being_written().expect("Unable to check lock"));
+            assert_eq!(false, writer.is_being_written().expect("Unable to check lock"));
+            assert_eq!(1, other.get_points().expect("Unable to get points").len());
+
+            // Once the lock is released, the other flake sees what was written
+            drop(writer);
+            assert_eq!(false, other.is_being_written().expect("Unable to check lock"));
+            other.lock().expect("Unable to lock flake");
+            assert_eq!(false, other.is_new());
+        });
+    }
+
+    #[test]
+    fn read_block_being_written() {
+        with_test_dir(|test_dir: &str| {
+            let flake_file = write_flake(test_dir, 1500);
+            let contents = fs::read(&flake_file).expect("Unable to read file");
+            fs::write(&flake_file, &contents[..contents.len() - 100])
+                .expect("Unable to write file");
+
+            // A reader takes the cut short block to be one still being written
+            let mut writer = Flake::new(&flake_file).expect("Unable to open flake");
+            writer.lock().expect("Unable to lock flake");
+            let reader = Flake::new(&flake_file).expect("Unable to open flake");
+            assert_eq!(1000, reader.get_points().expect("Unable to get points").len());
+            let check = reader.check().expect("Unable to check flake");
+            assert_eq!(true, check.cut_short);
+
+            // But the writer itself doesn't, and neither does a reader once
+            // nothing holds the lock
+            assert!(writer.get_points().is_err());
+            drop(writer);
+            assert!(reader.get_points().is_err());
+        });
+    }
+
+    #[test]
+    fn follow_growing_flake() {
+        with_test_dir(|test_dir: &str| {
+            let source_file = write_flake(test_dir, 2500);
+            let contents = fs::read(&source_file).expect("Unable to read file");
+            let flake_file = format!("{}/followed.flake", test_dir);
+            let mut follower = Flake::new(&flake_file).expect("Unable to open flake").follow();
+            assert_eq!(0, follower.read_new().expect("Unable to follow").len());
+
+            // The file is written a few bytes at a time, including part way
+            // through the header and each block
+            let mut x = 0.0;
+            for length in (0..=contents.len()).step_by(997).chain(vec![contents.len()]) {
+                fs::write(&flake_file, &contents[..length]).expect("Unable to write file");
+                for particle in follower.read_new().expect("Unable to follow") {
+                    assert_eq!(x, particle.point.x);
+                    x += 1.0;
+                }
+                assert_eq!(x as u64, follower.num_particles());
+            }
+            assert_eq!(2500.0, x);
+            assert_eq!(0, follower.read_new().expect("Unable to follow").len());
+
+            fs::write(&flake_file, &contents[..100]).expect("Unable to write file");
+            assert!(follower.read_new().is_err());
+        });
+    }
+
+    #[test]
+    fn follow_compact_flake() {
+        with_test_dir(|test_dir: &str| {
+            let flake_file = format!("{}/test.flake", test_dir);
+            let mut flake = Flake::new(&flake_file).expect("Unable to open flake");
+            flake.use_compact_encoding().expect("Unable to use compact encoding");
+            let mut follower = flake.follow();
+            for i in 0..3000 {
+                flake
+                    .add_particle(&Particle {
+                        parent: if i == 0 { Option::None } else { Option::Some(i - 1) },
+                        ..Particle::new(Point { x: i as f64, y: 0.0 })
+                    })
+                    .expect("Unable to add particle");
+                // Buffered particles aren't seen until they are flushed
+                if i % 700 == 0 {
+                    follower.read_new().expect("Unable to follow");
+                    assert_eq!(i / 1000 * 1000, follower.num_particles());
+                }
+            }
+            flake.flush().expect("Unable to flush");
+            let particles = follower.read_new().expect("Unable to follow");
+            assert_eq!(3000, follower.num_particles());
+            assert_eq!(2999.0, particles.last().unwrap().point.x);
+        });
+    }
+
+    #[test]
+    fn detect_and_repair_partial_legacy_record() {
+        with_test_dir(|test_dir: &str| {
+            let flake_file = format!("{}/test.flake", test_dir);
+            let mut contents = b"SNOWFLAK".to_vec();
+            contents.extend_from_slice(&1_u32.to_be_bytes());
+            for _i in 0..3 {
+                contents.extend_from_slice(&1.0_f64.to_be_bytes());
+                contents.extend_from_slice(&2.0_f64.to_be_bytes());
+                contents.push(0);
+            }
+            fs::write(&flake_file, &contents[..contents.len() - 5]).expect("Unable to write file");
+
+            let mut flake = Flake::new(&flake_file).expect("Unable to open flake");
+            let check = flake.check().expect("Unable to check flake");
+            assert_eq!(2, check.num_particles);
+            assert_eq!(12 + 2 * 17, check.valid_length);
+            flake.repair(&check).expect("Unable to repair flake");
+            assert_eq!(2, flake.get_points().expect("Unable to get points").len());
+        });
+    }
+
+    #[test]
+    fn iterate_after_offset() {
+        with_test_dir(|test_dir: &str| {
+            let flake_file = format!("{}/test.flake", test_dir);
+            let mut flake = Flake::new(&flake_file).expect("Unable to open flake");
+            flake.use_compact_encoding().expect("Unable to use compact encoding");
+            let particle = |i: usize| Particle {
+                parent: if i == 0 { Option::None } else { Option::Some(i as u64 - 1) },
+                ..Particle::new(Point {
+                    x: i as f64,
+                    y: 0.0,
+                })
+            };
+            for i in 0..1500 {
+                flake.add_particle(&particle(i)).expect("Unable to add particle");
+            }
+            flake.flush().expect("Unable to flush");
+            let length = flake.length().expect("Unable to get length");
+            let fingerprint = flake.fingerprint(length).expect("Unable to fingerprint");
+            for i in 1500..2000 {
+                flake.add_particle(&particle(i)).expect("Unable to add particle");
+            }
+            flake.flush().expect("Unable to flush");
+
+            // Only the particles after the offset are read
+            let particles: Vec<Particle> = flake
+                .iter_particles_after(length, 1500)
+                .expect("Unable to read particles")
+                .collect::<Result<_>>()
+                .expect("Unable to read particles");
+            assert_eq!(500, particles.len());
+            assert_eq!(1500.0, particles[0].point.x);
+            assert_eq!(Option::Some(1499), particles[0].parent);
+            assert_eq!(true, (particles[499].point.x - 1999.0).abs() < 1e-3);
+            assert_eq!(true, flake.iter_particles_after(4, 0).is_err());
+            assert_eq!(true, flake.iter_particles_after(1 << 30, 0).is_err());
+
+            // Adding to the file doesn't change the fingerprint of what was
+            // there before, but replacing it does
+            assert_eq!(fingerprint, flake.fingerprint(length).expect("Unable to fingerprint"));
+            drop(flake);
+            fs::remove_file(&flake_file).expect("Unable to remove flake");
+            let flake_file = write_flake(test_dir, 3000);
+            let flake = Flake::new(&flake_file).expect("Unable to open flake");
+            assert_ne!(fingerprint, flake.fingerprint(length).expect("Unable to fingerprint"));
+            let contents = fs::read(&flake_file).expect("Unable to read file");
+            fs::write(&flake_file, &contents[..length as usize - 1]).expect("Unable to write file");
+            assert_eq!(true, flake.fingerprint(length).expect("Unable to fingerprint").is_none());
+        });
+    }
+
+    #[test]
+    fn iterate_in_batches() {
+        with_test_dir(|test_dir: &str| {
+            // Several blocks, the last cut short
+            let flake_file = write_flake(test_dir, 3500);
+            let contents = fs::read(&flake_file).expect("Unable to read file");
+            fs::write(&flake_file, &contents[..contents.len() - 1]).expect("Unable to write file");
+
+            let flake = Flake::new(&flake_file).expect("Unable to open flake");
+            let mut points = flake.iter_points().expect("Unable to read points");
+            for i in 0..3000 {
+                assert_eq!(i as f64, points.next().unwrap().expect("Unable to read point").x);
+            }
+            assert!(points.next().unwrap().is_err());
+            assert!(points.next().is_none());
+
+            let flake = Flake::new(&format!("{}/missing.flake", test_dir))
+                .expect("Unable to open flake");
+            assert_eq!(0, flake.iter_particles().expect("Unable to read particles").count());
+        });
+    }
+
+    #[test]
+    fn flush_policy() {
+        with_test_dir(|test_dir: &str| {
+            let flake_file = format!("{}/test.flake", test_dir);
+            let mut flake = Flake::new(&flake_file).expect("Unable to open flake");
+            flake.set_flush_policy(FlushPolicy {
+                max_particles: 10,
+                max_time: Option::None,
+                sync: true,
+            });
+
+            let add = |flake: &mut Flake, n: usize| {
+                for _i in 0..n {
+                    flake.add_point(&Point::ZERO).expect("Unable to add point");
+                }
+            };
+            let num_written = || {
+                let flake = Flake::new(&flake_file).expect("Unable to open flake");
+                return flake.get_points().expect("Unable to get points").len();
+            };
+
+            add(&mut flake, 9);
+            assert_eq!(0, num_written());
+            add(&mut flake, 1);
+            assert_eq!(10, num_written());
+
+            // With no time to wait, every particle is written straight away
+            flake.set_flush_policy(FlushPolicy {
+                max_time: Option::Some(Duration::from_secs(0)),
+                ..FlushPolicy::default()
+            });
+            add(&mut flake, 1);
+            assert_eq!(11, num_written());
+        });
+    }
+
+    #[test]
+    fn failed_flush_keeps_particles() {
+        // Writes to /dev/full always fail as if the disk were full
+        if !Path::new("/dev/full").exists() {
+            return;
+        }
+        let mut flake = Flake::new("/dev/full").expect("Unable to open flake");
+        flake.add_point(&Point::ZERO).expect("Unable to add point");
+        assert!(flake.flush().is_err());
+        assert_eq!(1, flake.buffered_particles.len());
+        assert_eq!(true, flake.is_new());
+    }
+
+    #[test]
+    fn max_record_size_is_largest_record() {
+        let mut formats = vec![Format::Legacy];
+        for version in 1..=Format::COMPACT.version() {
+            formats.push(Format::from_version(version).expect("Unknown version"));
+        }
+        let largest = formats.iter().map(|format| format.record_size()).max();
+        assert_eq!(Option::Some(MAX_RECORD_SIZE), largest);
+    }
+
+    #[test]
+    fn read_fully_across_short_reads() {
+        // Gives out at most three bytes per read
+        struct Dribble<'a>(&'a [u8]);
+        impl<'a> Read for Dribble<'a> {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                let len = buf.len().min(self.0.len()).min(3);
+                buf[..len].copy_from_slice(&self.0[..len]);
+                self.0 = &self.0[len..];
+                return Result::Ok(len);
+            }
+        }
+
+        let contents: Vec<u8> = (0..20).collect();
+        let mut reader = Dribble(&contents);
+        let mut buf = [0; 16];
+        assert_eq!(16, read_fully(&mut reader, &mut buf).unwrap());
+        assert_eq!(15, buf[15]);
+        assert_eq!(4, read_fully(&mut reader, &mut buf).unwrap());
+        assert_eq!(19, buf[3]);
+        assert_eq!(0, read_fully(&mut reader, &mut buf).unwrap());
+    }
+
     /*
      * Last recorded performance:
      *
-     * Time to write 10000000 points: 195.548667ms
-     * Time per point: 19ns
+     * Time to write 10000000 points: 390.103619ms
+     * Time per point: 39ns
      *
-     * Time to read 10000000 points: 97.262104ms
-     * Time per point: 9ns
+     * Time to read 10000000 points: 329.412926ms
+     * Time per point: 32ns
      */
     #[test]
     #[ignore]
     fn roundtrip_perf() {
         with_test_dir(|test_dir: &str| {
             let flake_file = format!("{}/test.flake", test_dir);
-            let mut flake = Flake::new(&flake_file);
+            let mut flake = Flake::new(&flake_file).expect("Unable to open flake");
 
             let num_points = 10000000;
             let mut points: Vec<Point> = Vec::new();
@@ -0,0 +1,45 @@
+use anyhow::{anyhow, Context, Result};
+
+use crate::flake::Flake;
+
+// Check a flake file for damage and report what was found, along with how the
+// flake was made if the file records it. With repair, a damaged file is cut
+// back to its last valid record so that generation can carry on from there,
+// which can't happen while the flake is being generated.
+pub fn fsck(flake: &mut Flake, repair: bool) -> Result<()> {
+    if repair {
+        flake.lock()?;
+    }
+    let check = flake.check().context("Unable to check flake file")?;
+    println!("Particles: {}", check.num_particles);
+    println!("Length: {} bytes", check.length);
+    for (key, value) in flake.metadata() {
+        println!("{}: {}", key, value);
+    }
+
+    let problem = match &check.problem {
+        Option::Some(problem) => problem,
+        Option::None => {
+            println!("No problems found");
+            return Result::Ok(());
+        }
+    };
+    if check.cut_short && flake.is_being_written()? {
+        println!("The last block is still being written by another process");
+        return Result::Ok(());
+    }
+    println!("Damaged after {} bytes: {}", check.valid_length, problem);
+    if !repair {
+        return Result::Err(anyhow!(
+            "Flake file is damaged. Run with --repair to remove the damaged part"
+        ));
+    }
+
+    flake.repair(&check).context("Unable to repair flake file")?;
+    println!(
+        "Removed {} damaged bytes, keeping {} particles",
+        check.length - check.valid_length,
+        check.num_particles
+    );
+    return Result::Ok(());
+}
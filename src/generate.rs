@@ -1,13 +1,35 @@
 use std::f64::consts::PI;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use anyhow::{Context, Result};
-use rand::{Rng, RngCore};
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::rngs::StdRng;
+use rand::{Rng, RngCore, SeedableRng};
 
-use crate::flake::Flake;
+use crate::flake::{Flake, Particle};
+use crate::index::Index;
 use crate::point::Point;
-use crate::quad_tree::QuadTree;
+use crate::schedule::{Parameters, Schedule};
+use crate::shared_index::SharedIndex;
+use crate::spatial_index::{Backend, SpatialIndex};
+use crate::species::Species;
+use crate::stop::{Progress, StopConditions};
 
-const POINT_DIAMETER: f64 = 1.0;
+pub const POINT_DIAMETER: f64 = 1.0;
+
+// Walkers closer to the flake than this many particle diameters find the
+// nearest point exactly, rather than jumping by a lower bound on its distance.
+const EXACT_QUERY_DISTANCE: f64 = 8.0;
+
+// How often to save the index during generation.
+const INDEX_SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+// The number of arms to favour when growth is anisotropic but no symmetry was
+// requested. Ice crystals are hexagonal.
+const DEFAULT_ARMS: u32 = 6;
 
 pub enum Symmetry {
     None,
@@ -25,52 +47,410 @@ impl Symmetry {
         }
         return Symmetry::None;
     }
+
+    // Describes the symmetry for the flake's metadata.
+    fn describe(&self) -> String {
+        return match self {
+            Symmetry::None => "none".to_string(),
+            Symmetry::Rotational(n) => format!("rotational {}", n),
+            Symmetry::Reflectional(n) => format!("reflectional {}", n),
+        };
+    }
+
+    // The number of directions along which anisotropic growth is favoured.
+    fn arms(&self) -> u32 {
+        return match self {
+            Symmetry::None => DEFAULT_ARMS,
+            Symmetry::Rotational(n) => *n,
+            Symmetry::Reflectional(n) => *n * 2,
+        };
+    }
+}
+
+// Settings that change how generation runs, rather than what grows.
+pub struct RunOptions {
+    // Record the wall-clock time each particle was added at.
+    pub record_time: bool,
+    pub backend: Backend,
+    // The seed for the random walks, or None to pick one at random.
+    pub seed: Option<u64>,
+    // The number of walkers to run at once, each on its own thread. With more
+    // than one, the order particles stick in depends on timing, so the run
+    // can't be repeated from its seed.
+    pub threads: usize,
+}
+
+// A particle walking randomly until it sticks to the flake.
+#[derive(Clone, Copy)]
+struct Walker {
+    point: Point,
+    steps: u64,
+    species: usize,
+    parameters: Parameters,
+    construction_radius: f64,
+}
+
+// A walker that has stuck to the flake, waiting to be added to it.
+struct Proposal {
+    walker: Walker,
+    // The index of the particle the walker stuck to.
+    nearest_index: usize,
+    // The number of particles in the flake as far as the walker knew.
+    seen: usize,
+    // Where to send whether the walker was added, for walkers on other
+    // threads.
+    reply: Option<Sender<bool>>,
 }
 
-pub fn generate(flake: &mut Flake, _symmetry: Symmetry, num_points: Option<u32>) -> Result<()> {
-    let mut tree = QuadTree::from_flake(flake)?;
+pub fn generate(
+    flake: &mut Flake,
+    symmetry: Symmetry,
+    schedule: &Schedule,
+    species: &[Species],
+    stop: &StopConditions,
+    options: &RunOptions,
+) -> Result<()> {
+    let mut index =
+        Index::load_or_build(flake, options.backend).context("Unable to load flake index")?;
+
+    // Walks are random, but knowing the seed means a run can be repeated
+    // from the same starting flake. Only a new flake can record it, so it is
+    // printed for every run.
+    let seed = options.seed.unwrap_or_else(|| rand::thread_rng().gen());
+    println!("Using seed {}", seed);
+
+    // Only new flakes record how they were made, as the file's existing
+    // header can't be changed
+    if flake.is_new() {
+        let initial = schedule.evaluate(0, 0.0);
+        let metadata = [
+            ("tool-version", env!("CARGO_PKG_VERSION").to_string()),
+            ("model", "diffusion-limited aggregation".to_string()),
+            ("symmetry", symmetry.describe()),
+            ("particle-diameter", initial.particle_diameter.to_string()),
+            ("seed", seed.to_string()),
+        ];
+        for (key, value) in metadata.iter() {
+            flake.set_metadata(key, value)?;
+        }
+    }
 
-    if tree.is_empty() {
-        tree.add_point(&Point::ZERO);
+    if index.tree.is_empty() {
+        index.add_point(&Point::ZERO);
         flake
             .add_point(&Point::ZERO)
             .context("Unable to add point to flake")?;
     }
 
-    let num_points = num_points.unwrap_or(1000);
-    let mut rng = rand::thread_rng();
+    let species_distribution = WeightedIndex::new(species.iter().map(|species| species.rate))
+        .context("Invalid species rates")?;
+    let walkers = Walkers {
+        shared: SharedIndex::new(index),
+        symmetry,
+        schedule,
+        species,
+        species_distribution,
+    };
 
-    for i in 0..num_points {
-        let construction_radius = tree.get_farthest_distance() + 5.0;
-        let destruction_radius = construction_radius * 2.0;
+    // Extra threads only walk, sending the walkers that stick to this
+    // thread, which adds them to the flake. That way only this thread
+    // writes to the flake, and generation stops after exactly the right
+    // particle.
+    let stopped = AtomicBool::new(false);
+    let (proposals, proposed) = mpsc::channel();
+    return thread::scope(|scope| {
+        if options.threads > 1 {
+            for thread_index in 0..options.threads {
+                let proposals = proposals.clone();
+                let walkers = &walkers;
+                let stopped = &stopped;
+                scope.spawn(move || {
+                    let mut rng = StdRng::seed_from_u64(seed.wrapping_add(thread_index as u64 + 1));
+                    walkers.propose_until_stopped(&proposals, stopped, &mut rng);
+                });
+            }
+        }
+        drop(proposals);
 
-        let mut point = new_point(construction_radius, &mut rng);
-        let mut distance_to_flake = tree.get_nearest(&point).unwrap().1;
+        let mut rng = StdRng::seed_from_u64(seed);
+        let next_proposal = || -> Result<Proposal> {
+            if options.threads > 1 {
+                return proposed.recv().context("Walker threads stopped");
+            }
+            // A walker on this thread always knows about every particle, so
+            // is never turned away
+            return Result::Ok(walkers.propose(&mut rng));
+        };
+        let result = walkers.add_particles(flake, stop, options, next_proposal);
 
-        while distance_to_flake > POINT_DIAMETER {
-            let r = rng.gen_range(0.0..PI * 2.0);
-            point.x += r.sin() * distance_to_flake;
-            point.y += r.cos() * distance_to_flake;
+        // Walkers waiting to hear whether they were added find out that
+        // generation has finished when their proposals are dropped
+        stopped.store(true, Ordering::Release);
+        drop(proposed);
+        return result;
+    });
+}
+
+// Everything walkers need to know to walk and stick to the flake.
+struct Walkers<'a> {
+    shared: SharedIndex,
+    symmetry: Symmetry,
+    schedule: &'a Schedule,
+    species: &'a [Species],
+    species_distribution: WeightedIndex<f64>,
+}
+
+impl<'a> Walkers<'a> {
+    // Add the walkers given by next_proposal to the flake until one of the
+    // stop conditions is met.
+    fn add_particles(
+        &self,
+        flake: &mut Flake,
+        stop: &StopConditions,
+        options: &RunOptions,
+        mut next_proposal: impl FnMut() -> Result<Proposal>,
+    ) -> Result<()> {
+        let start = Instant::now();
+        let mut last_index_save = start;
+        let mut extent = find_extent(self.shared.snapshot().tree.as_ref());
 
-            if point.distance(&Point::ZERO) > destruction_radius {
-                point = new_point(construction_radius, &mut rng);
+        let mut i: u32 = 0;
+        loop {
+            let index = self.shared.snapshot();
+            let progress = Progress {
+                particles_added: i,
+                num_particles: index.tree.len(),
+                radius: index.tree.get_farthest_distance(),
+                elapsed: start.elapsed(),
+                extent,
+            };
+            if stop.should_stop(&progress) {
+                break;
+            }
+
+            // Save the index now and then, so that an interrupted run can
+            // carry on from it rather than rebuilding it. The flake has to be
+            // flushed first so that the index matches the flake file. Saving
+            // writes out the whole index, so it is done by time rather than
+            // after a number of particles.
+            if last_index_save.elapsed() >= INDEX_SAVE_INTERVAL {
+                flake.flush().context("Unable to flush flake")?;
+                index.save(flake).context("Unable to save flake index")?;
+                last_index_save = Instant::now();
+            }
+            drop(index);
+
+            let proposal = next_proposal()?;
+            let point = proposal.walker.point;
+            let added = self.shared.try_add_point(&point, proposal.seen).is_some();
+            if let Option::Some(reply) = &proposal.reply {
+                // The walker's thread only stops once generation has
+                reply.send(added).context("Walker thread stopped")?;
+            }
+            if !added {
+                continue;
             }
 
-            distance_to_flake = tree.get_nearest(&point).unwrap().1;
+            if i % 1000 == 0 {
+                println!("Adding point {}", i);
+            }
+            extent.x = extent.x.max(point.x.abs());
+            extent.y = extent.y.max(point.y.abs());
+            flake
+                .add_particle(&Particle {
+                    point,
+                    species: proposal.walker.species as u8,
+                    parent: Option::Some(proposal.nearest_index as u64),
+                    steps: proposal.walker.steps,
+                    time: if options.record_time { Option::Some(now()?) } else { Option::None },
+                })
+                .context("Unable to add point to flake")?;
+            i += 1;
         }
 
-        if i % 1000 == 0 {
-            println!("Adding point {}/{}", i, num_points);
+        flake.flush().context("Unable to flush flake")?;
+        self.shared.snapshot().save(flake).context("Unable to save flake index")?;
+        return Result::Ok(());
+    }
+
+    // Release walkers one after another, sending each one that sticks to be
+    // added to the flake, until generation stops. A walker that isn't added,
+    // because another has stuck nearer to it in the meantime, walks on.
+    fn propose_until_stopped(
+        &self,
+        proposals: &Sender<Proposal>,
+        stopped: &AtomicBool,
+        rng: &mut StdRng,
+    ) {
+        while !stopped.load(Ordering::Acquire) {
+            let mut walker = self.release(rng);
+            loop {
+                let (nearest_index, seen) = self.walk(&mut walker, rng);
+                let (reply, replies) = mpsc::channel();
+                let proposal = Proposal {
+                    walker,
+                    nearest_index,
+                    seen,
+                    reply: Option::Some(reply),
+                };
+                if proposals.send(proposal).is_err() {
+                    return;
+                }
+                match replies.recv() {
+                    Result::Ok(true) => break,
+                    Result::Ok(false) => {}
+                    Result::Err(_) => return,
+                }
+            }
         }
-        tree.add_point(&point);
-        flake
-            .add_point(&point)
-            .context("Unable to add point to flake")?;
     }
 
-    flake.flush().context("Unable to flush flake")?;
+    // Release a walker and walk it until it sticks.
+    fn propose(&self, rng: &mut StdRng) -> Proposal {
+        let mut walker = self.release(rng);
+        let (nearest_index, seen) = self.walk(&mut walker, rng);
+        return Proposal {
+            walker,
+            nearest_index,
+            seen,
+            reply: Option::None,
+        };
+    }
+
+    // Returns a walker of a random species on the construction circle around
+    // the flake.
+    fn release(&self, rng: &mut StdRng) -> Walker {
+        let species_index = self.species_distribution.sample(rng);
+        let walker_species = &self.species[species_index];
+        let index = self.shared.snapshot();
+        let radius = index.tree.get_farthest_distance();
+        let mut parameters = self.schedule.evaluate(index.tree.len() as u64, radius);
+        parameters.sticking_probability *= walker_species.sticking_probability;
+        parameters.particle_diameter *= walker_species.diameter;
+        let construction_radius = radius + 5.0;
+        return Walker {
+            point: new_point(construction_radius, rng),
+            steps: 0,
+            species: species_index,
+            parameters,
+            construction_radius,
+        };
+    }
 
-    return Result::Ok(());
+    // Walk until touching the flake and sticking to it. Returns the index of
+    // the particle stuck to, and the number of particles the walker knew
+    // about.
+    fn walk(&self, walker: &mut Walker, rng: &mut StdRng) -> (usize, usize) {
+        let parameters = &walker.parameters;
+        let has_drift = parameters.drift.x != 0.0 || parameters.drift.y != 0.0;
+        let mut index = self.shared.snapshot();
+        loop {
+            // Other threads may have added to the flake, which can grow
+            // past the construction circle
+            if index.refresh() {
+                let radius = index.tree.get_farthest_distance();
+                walker.construction_radius = walker.construction_radius.max(radius + 5.0);
+            }
+            let construction_radius = walker.construction_radius;
+            let destruction_radius = construction_radius * 2.0;
+
+            // Away from the flake a cheap lower bound on the distance to it
+            // is enough to jump by, so only find the nearest point when close.
+            // The distance field is cheapest, but only knows about the area
+            // close to the flake.
+            let point = walker.point;
+            let mut distance_to_flake = index.field.get_lower_bound(&point);
+            if distance_to_flake >= index.field.max_bound() {
+                let bound = index.tree.get_distance_lower_bound(&point);
+                distance_to_flake = distance_to_flake.max(bound);
+            }
+            if distance_to_flake <= parameters.particle_diameter * EXACT_QUERY_DISTANCE {
+                let (nearest, distance, nearest_index) = index.tree.get_nearest(&point).unwrap();
+                distance_to_flake = distance;
+                if distance_to_flake <= parameters.particle_diameter {
+                    if sticks(&point, &nearest, parameters, &self.symmetry, rng) {
+                        return (nearest_index, index.tree.len());
+                    }
+                    // Bounce off rather than sticking, so the walker can try
+                    // to attach somewhere else.
+                    distance_to_flake = parameters.particle_diameter;
+                }
+            }
+
+            let r = rng.gen_range(0.0..PI * 2.0);
+            let step = Point {
+                x: r.sin() + parameters.drift.x,
+                y: r.cos() + parameters.drift.y,
+            };
+            // Drift can make a step longer than the distance to the flake,
+            // which would let the walker jump into it, so cap the step.
+            let scale = distance_to_flake / step.distance(&Point::ZERO).max(1.0);
+            walker.point.x += step.x * scale;
+            walker.point.y += step.y * scale;
+            walker.steps += 1;
+
+            let distance_to_center = walker.point.distance(&Point::ZERO);
+            if has_drift {
+                // Drift makes the walk anisotropic, so there's no simple
+                // distribution for where it returns to the construction circle.
+                if distance_to_center > destruction_radius {
+                    walker.point = new_point(construction_radius, rng);
+                }
+            } else if distance_to_center > construction_radius {
+                walker.point = return_to_circle(&walker.point, construction_radius, rng);
+            }
+        }
+    }
+}
+
+// The largest absolute x and y coordinates of the points in the index.
+fn find_extent(tree: &dyn SpatialIndex) -> Point {
+    let mut extent = Point::ZERO;
+    for (point, _) in tree.iter() {
+        extent.x = extent.x.max(point.x.abs());
+        extent.y = extent.y.max(point.y.abs());
+    }
+    return extent;
+}
+
+// The current wall-clock time, in seconds since the Unix epoch.
+fn now() -> Result<f64> {
+    let since_epoch = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .context("System clock is before the Unix epoch")?;
+    return Result::Ok(since_epoch.as_secs_f64());
+}
+
+// Decide whether a walker touching the flake at the given nearest point sticks.
+// Anisotropy reduces the chance of sticking away from the symmetry axes.
+fn sticks(
+    point: &Point,
+    nearest: &Point,
+    parameters: &Parameters,
+    symmetry: &Symmetry,
+    rng: &mut dyn RngCore,
+) -> bool {
+    let angle = (point.y - nearest.y).atan2(point.x - nearest.x);
+    let alignment = (1.0 - (angle * symmetry.arms() as f64).cos()) / 2.0;
+    let probability = parameters.sticking_probability * (1.0 - parameters.anisotropy * alignment);
+    return rng.gen_bool(probability.clamp(0.0, 1.0));
+}
+
+// Move a walker that is outside the circle of the given radius directly to
+// the point where it would first cross back into the circle. Unbiased random
+// walks in 2D always return, and from a distance r the angle at which they
+// hit a circle of radius R, relative to the walker's own angle, follows a
+// wrapped Cauchy distribution with concentration R / r.
+fn return_to_circle(point: &Point, radius: f64, rng: &mut dyn RngCore) -> Point {
+    let concentration = radius / point.distance(&Point::ZERO);
+    let u: f64 = rng.gen_range(0.0..1.0);
+    let offset = 2.0
+        * ((1.0 - concentration) / (1.0 + concentration) * (PI * (u - 0.5)).tan()).atan();
+    let angle = point.y.atan2(point.x) + offset;
+    return Point {
+        x: angle.cos() * radius,
+        y: angle.sin() * radius,
+    };
 }
 
 fn new_point(distance_to_center: f64, rng: &mut dyn RngCore) -> Point {
@@ -79,3 +459,113 @@ fn new_point(distance_to_center: f64, rng: &mut dyn RngCore) -> Point {
     let y = r.cos() * distance_to_center;
     return Point { x, y };
 }
+
+#[cfg(test)]
+mod tests {
+    use std::f64::consts::PI;
+
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use super::{find_extent, generate, return_to_circle, RunOptions, Symmetry};
+    use crate::flake::Flake;
+    use crate::index::Index;
+    use crate::point::Point;
+    use crate::schedule::Schedule;
+    use crate::spatial_index::Backend;
+    use crate::species::Species;
+    use crate::stop::{Combine, StopCondition, StopConditions};
+    use crate::test_utils::test::with_test_dir;
+
+    #[test]
+    fn boundary_includes_earlier_runs() {
+        with_test_dir(|test_dir: &str| {
+            let mut flake =
+                Flake::new(&format!("{}/test.flake", test_dir)).expect("Unable to open flake");
+            let options = RunOptions {
+                record_time: false,
+                backend: Backend::QuadTree,
+                seed: Option::Some(7),
+                threads: 1,
+            };
+            let run = |flake: &mut Flake, stop: StopCondition| {
+                generate(
+                    flake,
+                    Symmetry::None,
+                    &Schedule::default(),
+                    &Species::defaults(),
+                    &StopConditions::new(vec![stop], Combine::Any),
+                    &options,
+                )
+                .expect("Unable to generate flake");
+            };
+            run(&mut flake, StopCondition::NumParticles(300));
+            let index = Index::load_or_build(&flake, Backend::QuadTree).expect("Unable to load");
+            let extent = find_extent(index.tree.as_ref());
+            assert_eq!(301, index.tree.len());
+
+            // The flake already reaches a smaller box, so a second run with
+            // one stops straight away
+            let size = format!("{}x{}", extent.x * 2.0 - 2.0, extent.y * 2.0 - 2.0);
+            run(&mut flake, StopCondition::parse_boundary(&size).expect("Unable to parse box"));
+            assert_eq!(301, flake.get_points().expect("Unable to get points").len());
+        });
+    }
+
+    #[test]
+    fn generate_with_several_threads() {
+        with_test_dir(|test_dir: &str| {
+            let mut flake =
+                Flake::new(&format!("{}/test.flake", test_dir)).expect("Unable to open flake");
+            generate(
+                &mut flake,
+                Symmetry::None,
+                &Schedule::default(),
+                &Species::defaults(),
+                &StopConditions::new(vec![StopCondition::NumParticles(500)], Combine::Any),
+                &RunOptions {
+                    record_time: false,
+                    backend: Backend::QuadTree,
+                    seed: Option::Some(11),
+                    threads: 4,
+                },
+            )
+            .expect("Unable to generate flake");
+
+            // Every particle sticks to one added before it, no matter which
+            // walker proposed it
+            let particles = flake.get_particles().expect("Unable to get particles");
+            assert_eq!(501, particles.len());
+            for (i, particle) in particles.iter().enumerate().skip(1) {
+                let parent = particle.parent.expect("Missing parent") as usize;
+                assert!(parent < i);
+                assert!(particle.point.distance(&particles[parent].point) <= 1.0 + 1e-6);
+            }
+
+            // And the saved index matches the flake
+            let index = Index::load(&flake).expect("Unable to load").expect("Missing index");
+            assert_eq!(particles.len(), index.tree.len());
+        });
+    }
+
+    #[test]
+    fn return_to_circle_lands_on_circle() {
+        let mut rng = StdRng::seed_from_u64(3);
+        let walker = Point { x: 0.0, y: 150.0 };
+
+        let mut near_walker = 0;
+        for _i in 0..1000 {
+            let point = return_to_circle(&walker, 100.0, &mut rng);
+            assert!((point.distance(&Point::ZERO) - 100.0).abs() < 1e-9);
+
+            let angle = (point.y.atan2(point.x) - PI / 2.0).abs();
+            if angle.min(2.0 * PI - angle) < PI / 4.0 {
+                near_walker += 1;
+            }
+        }
+
+        // From 1.5 times the radius away about 71% of walkers return within
+        // 45 degrees of the direction they left in
+        assert!(near_walker > 660 && near_walker < 770);
+    }
+}
@@ -0,0 +1,186 @@
+use std::collections::HashMap;
+
+use crate::point::Point;
+use crate::spatial_index::SpatialIndex;
+
+// The width of each cell of the grid. A few particle diameters keeps cells
+// cheap to search without needing to look at too many of them.
+const CELL_SIZE: f64 = 4.0;
+
+// A uniform grid of square cells, of which only those holding points are
+// stored, in a hash map keyed by cell coordinates. Cheap to add to and to
+// search near the points, but searches far from them have to look at many
+// cells.
+#[derive(Clone)]
+pub struct Grid {
+    cells: HashMap<(i64, i64), Vec<(Point, usize)>>,
+    // The lowest and highest coordinates of any cell holding points.
+    min_cell: (i64, i64),
+    max_cell: (i64, i64),
+    num_points: usize,
+    farthest_distance: f64,
+}
+
+impl Grid {
+    pub fn new() -> Grid {
+        return Grid {
+            cells: HashMap::new(),
+            min_cell: (i64::MAX, i64::MAX),
+            max_cell: (i64::MIN, i64::MIN),
+            num_points: 0,
+            farthest_distance: 0.0,
+        };
+    }
+
+    // Make a grid holding the given points, indexed in the order given.
+    pub fn from_points(points: &[Point]) -> Grid {
+        let mut grid = Grid::new();
+        for point in points {
+            grid.add_point(point);
+        }
+        return grid;
+    }
+
+    // Returns the coordinates of the cell containing the given point.
+    fn cell_of(point: &Point) -> (i64, i64) {
+        return (
+            (point.x / CELL_SIZE).floor() as i64,
+            (point.y / CELL_SIZE).floor() as i64,
+        );
+    }
+
+    // Updates the given nearest point and its squared distance if any point
+    // in the given cell is nearer.
+    fn search_cell(
+        &self,
+        cell: &(i64, i64),
+        point: &Point,
+        nearest: &mut Option<(Point, usize)>,
+        nearest_distance_2: &mut f64,
+    ) {
+        if let Option::Some(points) = self.cells.get(cell) {
+            for (other_point, index) in points {
+                let d2 = point.distance_2(other_point);
+                if d2 < *nearest_distance_2 {
+                    *nearest = Option::Some((*other_point, *index));
+                    *nearest_distance_2 = d2;
+                }
+            }
+        }
+    }
+}
+
+impl SpatialIndex for Grid {
+    fn add_point(&mut self, point: &Point) {
+        if !point.x.is_finite() || !point.y.is_finite() {
+            panic!("Unable to add point {} that is not finite", point);
+        }
+
+        let cell = Grid::cell_of(point);
+        self.cells
+            .entry(cell)
+            .or_default()
+            .push((*point, self.num_points));
+        self.min_cell = (self.min_cell.0.min(cell.0), self.min_cell.1.min(cell.1));
+        self.max_cell = (self.max_cell.0.max(cell.0), self.max_cell.1.max(cell.1));
+        self.num_points += 1;
+        self.farthest_distance = self.farthest_distance.max(point.distance(&Point::ZERO));
+    }
+
+    // Searches rings of cells around the cell containing the point, until
+    // the rings are farther away than the nearest point found so far.
+    fn get_nearest(&self, point: &Point) -> Option<(Point, f64, usize)> {
+        if self.num_points == 0 {
+            return Option::None;
+        }
+
+        let center = Grid::cell_of(point);
+        let max_ring = (center.0 - self.min_cell.0)
+            .abs()
+            .max((center.0 - self.max_cell.0).abs())
+            .max((center.1 - self.min_cell.1).abs())
+            .max((center.1 - self.max_cell.1).abs());
+
+        let mut nearest = Option::None;
+        let mut nearest_distance_2 = f64::INFINITY;
+        for ring in 0..=max_ring {
+            // Every cell in a ring is at least this far from the point
+            let ring_distance = (ring - 1).max(0) as f64 * CELL_SIZE;
+            if ring_distance * ring_distance > nearest_distance_2 {
+                break;
+            }
+
+            // Far from the points it is cheaper to look at every cell
+            if ring * 8 > self.cells.len() as i64 {
+                for cell in self.cells.keys() {
+                    let dx = ((cell.0 - center.0).abs() - 1).max(0) as f64 * CELL_SIZE;
+                    let dy = ((cell.1 - center.1).abs() - 1).max(0) as f64 * CELL_SIZE;
+                    if dx * dx + dy * dy <= nearest_distance_2 {
+                        self.search_cell(cell, point, &mut nearest, &mut nearest_distance_2);
+                    }
+                }
+                break;
+            }
+
+            // Only look at the part of the ring overlapping the occupied cells
+            let min_x = (center.0 - ring).max(self.min_cell.0);
+            let max_x = (center.0 + ring).min(self.max_cell.0);
+            let min_y = (center.1 - ring).max(self.min_cell.1);
+            let max_y = (center.1 + ring).min(self.max_cell.1);
+            for y in min_y..=max_y {
+                if y == center.1 - ring || y == center.1 + ring {
+                    for x in min_x..=max_x {
+                        self.search_cell(&(x, y), point, &mut nearest, &mut nearest_distance_2);
+                    }
+                } else {
+                    for x in [center.0 - ring, center.0 + ring].iter() {
+                        if *x >= min_x && *x <= max_x {
+                            let cell = (*x, y);
+                            self.search_cell(&cell, point, &mut nearest, &mut nearest_distance_2);
+                        }
+                    }
+                }
+            }
+        }
+
+        return nearest.map(|(nearest, index)| (nearest, nearest_distance_2.sqrt(), index));
+    }
+
+    fn get_farthest_distance(&self) -> f64 {
+        return self.farthest_distance;
+    }
+
+    fn len(&self) -> usize {
+        return self.num_points;
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (Point, usize)> + '_> {
+        return Box::new(self.cells.values().flat_map(|points| points.iter().copied()));
+    }
+
+    fn clone_box(&self) -> Box<dyn SpatialIndex> {
+        return Box::new(self.clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Grid;
+    use crate::point::Point;
+    use crate::spatial_index::SpatialIndex;
+
+    #[test]
+    fn get_nearest_far_from_points() {
+        let mut grid = Grid::new();
+        for i in 0..100 {
+            grid.add_point(&Point {
+                x: i as f64,
+                y: 0.0,
+            });
+        }
+
+        assert_eq!(0, grid.get_nearest(&Point { x: -1.0e6, y: 0.0 }).unwrap().2);
+        assert_eq!(1.0e6, grid.get_nearest(&Point { x: 50.0, y: 1.0e6 }).unwrap().1);
+        assert_eq!(99, grid.get_nearest(&Point { x: 99.4, y: -0.5 }).unwrap().2);
+    }
+}
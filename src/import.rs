@@ -0,0 +1,405 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::str::FromStr;
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::export::{Column, ExportFormat};
+use crate::flake::{Flake, Particle};
+use crate::generate::POINT_DIAMETER;
+use crate::point::Point;
+use crate::quad_tree::QuadTree;
+
+// Particles that touch are a diameter apart. Allow for a little rounding in
+// files written by other tools.
+const MIN_SPACING: f64 = POINT_DIAMETER * 0.999;
+
+// Read the particles from a file in the given format, such as one written by
+// export, and add them to a new flake. Each particle needs an x and y, and
+// the other columns are optional. Nothing is written unless every particle is
+// valid: all numbers must be finite, each parent must come before its child,
+// and no two particles may be in the same place. Particles closer together
+// than a diameter are reported, and with strict spacing aren't allowed.
+pub fn import(
+    input_filename: &str,
+    format: ExportFormat,
+    flake: &mut Flake,
+    strict_spacing: bool,
+) -> Result<()> {
+    if !flake.is_new() {
+        return Result::Err(anyhow!("Flake file already exists: {}", flake.path()));
+    }
+    let f = File::open(input_filename)
+        .context(format!("Unable to open input file: {}", input_filename))?;
+    let particles = match format {
+        ExportFormat::Csv => read_csv(BufReader::new(f))?,
+        ExportFormat::JsonLines => read_json_lines(BufReader::new(f))?,
+        ExportFormat::Npy => {
+            return Result::Err(anyhow!("Importing .npy files isn't supported"));
+        }
+    };
+    check_spacing(&particles, strict_spacing)?;
+
+    // Only lock the file once there is something to write, so that it isn't
+    // left behind empty
+    flake.lock()?;
+    if !flake.is_new() {
+        return Result::Err(anyhow!("Flake file already exists: {}", flake.path()));
+    }
+    flake.set_metadata("tool-version", env!("CARGO_PKG_VERSION"))?;
+    flake.set_metadata("imported-from", input_filename)?;
+    for particle in &particles {
+        flake.add_particle(particle)?;
+    }
+    flake.flush()?;
+    println!("Imported {} particles", particles.len());
+    return Result::Ok(());
+}
+
+// Read a CSV file with a header row naming its columns.
+fn read_csv<R: BufRead>(reader: R) -> Result<Vec<Particle>> {
+    let mut columns: Option<Vec<Column>> = Option::None;
+    let mut particles = Vec::new();
+    for (i, line) in reader.lines().enumerate() {
+        let line = line.context("Unable to read input file")?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let values: Vec<&str> = line.split(',').map(|value| value.trim()).collect();
+        let columns = match &columns {
+            Option::Some(columns) => columns,
+            Option::None => {
+                let header = values
+                    .iter()
+                    .map(|name| Column::from(name))
+                    .collect::<Result<Vec<Column>>>()
+                    .context(format!("Invalid header on line {}", i + 1))?;
+                columns = Option::Some(header);
+                continue;
+            }
+        };
+        if values.len() != columns.len() {
+            return Result::Err(anyhow!(
+                "Line {} has {} values but there are {} columns",
+                i + 1,
+                values.len(),
+                columns.len()
+            ));
+        }
+
+        let fields: Vec<(Column, Option<&str>)> = columns
+            .iter()
+            .zip(values)
+            .map(|(column, value)| {
+                (
+                    *column,
+                    Option::Some(value).filter(|value| !value.is_empty()),
+                )
+            })
+            .collect();
+        let particle = parse_particle(particles.len() as u64, &fields)
+            .context(format!("Invalid particle on line {}", i + 1))?;
+        particles.push(particle);
+    }
+    return Result::Ok(particles);
+}
+
+// Read a file of JSON objects, one per line, whose values are all numbers or
+// null.
+fn read_json_lines<R: BufRead>(reader: R) -> Result<Vec<Particle>> {
+    let mut particles = Vec::new();
+    for (i, line) in reader.lines().enumerate() {
+        let line = line.context("Unable to read input file")?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let particle = parse_json_object(&line)
+            .and_then(|fields| parse_particle(particles.len() as u64, &fields))
+            .context(format!("Invalid particle on line {}", i + 1))?;
+        particles.push(particle);
+    }
+    return Result::Ok(particles);
+}
+
+fn parse_json_object(line: &str) -> Result<Vec<(Column, Option<&str>)>> {
+    let body = line
+        .trim()
+        .strip_prefix('{')
+        .and_then(|line| line.strip_suffix('}'))
+        .ok_or_else(|| anyhow!("Expected a JSON object"))?;
+    if body.trim().is_empty() {
+        return Result::Ok(Vec::new());
+    }
+    let mut fields = Vec::new();
+    for field in body.split(',') {
+        let (key, value) = field
+            .split_once(':')
+            .ok_or_else(|| anyhow!("Expected \"key\":value but found '{}'", field))?;
+        let key = key.trim();
+        let name = key
+            .strip_prefix('"')
+            .and_then(|key| key.strip_suffix('"'))
+            .ok_or_else(|| anyhow!("Expected a quoted key but found '{}'", key))?;
+        let value = Option::Some(value.trim()).filter(|value| *value != "null");
+        fields.push((Column::from(name)?, value));
+    }
+    return Result::Ok(fields);
+}
+
+// Make a particle from its values, which should be the particle at the given
+// index in the file.
+fn parse_particle(index: u64, fields: &[(Column, Option<&str>)]) -> Result<Particle> {
+    let mut particle = Particle::new(Point {
+        x: f64::NAN,
+        y: f64::NAN,
+    });
+    for (i, (column, value)) in fields.iter().enumerate() {
+        if fields[..i].iter().any(|(other, _)| other == column) {
+            return Result::Err(anyhow!("Column '{}' is given twice", column.name()));
+        }
+        let value = match value {
+            Option::Some(value) => *value,
+            Option::None => match column {
+                Column::Parent | Column::Time => continue,
+                _ => return Result::Err(anyhow!("Column '{}' has no value", column.name())),
+            },
+        };
+        match column {
+            Column::Index => {
+                let given: u64 = parse_value(*column, value)?;
+                if given != index {
+                    return Result::Err(anyhow!(
+                        "Particles must be in index order, but {} is in place of {}",
+                        given,
+                        index
+                    ));
+                }
+            }
+            Column::X => particle.point.x = parse_finite(*column, value)?,
+            Column::Y => particle.point.y = parse_finite(*column, value)?,
+            Column::Species => particle.species = parse_value(*column, value)?,
+            Column::Parent => {
+                let parent: u64 = parse_value(*column, value)?;
+                if parent >= index {
+                    return Result::Err(anyhow!(
+                        "The parent {} of particle {} doesn't come before it",
+                        parent,
+                        index
+                    ));
+                }
+                particle.parent = Option::Some(parent);
+            }
+            Column::Steps => particle.steps = parse_value(*column, value)?,
+            Column::Time => particle.time = Option::Some(parse_finite(*column, value)?),
+        }
+    }
+    if particle.point.x.is_nan() || particle.point.y.is_nan() {
+        return Result::Err(anyhow!("Particles need both an x and a y"));
+    }
+    return Result::Ok(particle);
+}
+
+fn parse_value<T: FromStr>(column: Column, value: &str) -> Result<T> {
+    return value.parse::<T>().map_err(|_| {
+        anyhow!(
+            "Unable to parse '{}' as a value of '{}'",
+            value,
+            column.name()
+        )
+    });
+}
+
+// Parse a number, which can't be NaN or infinite.
+fn parse_finite(column: Column, value: &str) -> Result<f64> {
+    let number: f64 = parse_value(column, value)?;
+    if !number.is_finite() {
+        return Result::Err(anyhow!(
+            "The '{}' of a particle can't be {}",
+            column.name(),
+            value
+        ));
+    }
+    return Result::Ok(number);
+}
+
+// Check that no two particles are in the same place, and report particles
+// closer than a diameter to an earlier one. Walkers stick once they are
+// within a diameter of the flake, so flakes made by generate have many of
+// these, and they are only an error with strict spacing.
+fn check_spacing(particles: &[Particle], strict_spacing: bool) -> Result<()> {
+    let mut tree = QuadTree::from_points(&[]);
+    let mut num_too_close = 0;
+    for (i, particle) in particles.iter().enumerate() {
+        if let Option::Some((_, distance, nearest)) = tree.get_nearest(&particle.point) {
+            if distance == 0.0 {
+                return Result::Err(anyhow!(
+                    "Particle {} is in the same place as particle {}",
+                    i,
+                    nearest
+                ));
+            }
+            if distance < MIN_SPACING {
+                if strict_spacing {
+                    return Result::Err(anyhow!(
+                        "Particle {} is only {} from particle {}, which is less than a \
+                         particle diameter",
+                        i,
+                        distance,
+                        nearest
+                    ));
+                }
+                num_too_close += 1;
+            }
+        }
+        tree.add_point(&particle.point);
+    }
+    if num_too_close > 0 {
+        println!(
+            "{} particles are closer than a particle diameter to an earlier particle",
+            num_too_close
+        );
+    }
+    return Result::Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use super::import;
+    use crate::export::{export, Column, ExportFormat};
+    use crate::flake::{Flake, Particle};
+    use crate::point::Point;
+    use crate::test_utils::test::with_test_dir;
+
+    #[test]
+    fn roundtrip_through_export() {
+        with_test_dir(|test_dir: &str| {
+            let flake_file = format!("{}/test.flake", test_dir);
+            let mut flake = Flake::new(&flake_file).expect("Unable to open flake");
+            for i in 0..1500 {
+                flake
+                    .add_particle(&Particle {
+                        species: (i % 3) as u8,
+                        parent: if i == 0 {
+                            Option::None
+                        } else {
+                            Option::Some(i - 1)
+                        },
+                        steps: i * 10,
+                        time: if i % 2 == 0 {
+                            Option::Some(i as f64 / 7.0)
+                        } else {
+                            Option::None
+                        },
+                        ..Particle::new(Point {
+                            x: i as f64 / 3.0,
+                            y: i as f64,
+                        })
+                    })
+                    .expect("Unable to add particle");
+            }
+            flake.flush().expect("Unable to flush");
+            let columns = Column::available(&flake);
+
+            for (format, extension) in [
+                (ExportFormat::Csv, "csv"),
+                (ExportFormat::JsonLines, "jsonl"),
+            ]
+            .iter()
+            {
+                let exported_file = format!("{}/out.{}", test_dir, extension);
+                export(&flake, *format, &columns, Option::None, &exported_file)
+                    .expect("Unable to export");
+                let imported_file = format!("{}/imported-{}.flake", test_dir, extension);
+                let mut imported = Flake::new(&imported_file).expect("Unable to open flake");
+                import(&exported_file, *format, &mut imported, true).expect("Unable to import");
+
+                let imported = Flake::new(&imported_file).expect("Unable to open flake");
+                assert_eq!(exported_file, imported.metadata()["imported-from"]);
+                let expected = flake.get_particles().expect("Unable to get particles");
+                let actual = imported.get_particles().expect("Unable to get particles");
+                assert_eq!(expected.len(), actual.len());
+                for (expected, actual) in expected.iter().zip(&actual) {
+                    assert_eq!(expected.point.x, actual.point.x);
+                    assert_eq!(expected.point.y, actual.point.y);
+                    assert_eq!(expected.species, actual.species);
+                    assert_eq!(expected.parent, actual.parent);
+                    assert_eq!(expected.steps, actual.steps);
+                    assert_eq!(expected.time, actual.time);
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn import_points_only() {
+        with_test_dir(|test_dir: &str| {
+            let input_file = format!("{}/points.csv", test_dir);
+            fs::write(&input_file, "y , x\n1,2\n\n-3.5,0\n").expect("Unable to write file");
+            let flake_file = format!("{}/test.flake", test_dir);
+            let mut flake = Flake::new(&flake_file).expect("Unable to open flake");
+            import(&input_file, ExportFormat::Csv, &mut flake, false).expect("Unable to import");
+
+            let particles = flake.get_particles().expect("Unable to get particles");
+            assert_eq!(2, particles.len());
+            assert_eq!(2.0, particles[0].point.x);
+            assert_eq!(-3.5, particles[1].point.y);
+            assert_eq!(true, particles[1].parent.is_none());
+
+            // Only into a new flake file
+            assert!(import(&input_file, ExportFormat::Csv, &mut flake, false).is_err());
+        });
+    }
+
+    #[test]
+    fn reject_invalid_particles() {
+        with_test_dir(|test_dir: &str| {
+            let input_file = format!("{}/points", test_dir);
+            let flake_file = format!("{}/test.flake", test_dir);
+            let try_import = |format: ExportFormat, contents: &str, strict_spacing: bool| {
+                fs::write(&input_file, contents).expect("Unable to write file");
+                let mut flake = Flake::new(&flake_file).expect("Unable to open flake");
+                let result = import(&input_file, format, &mut flake, strict_spacing);
+                // Nothing is written unless every particle is valid
+                assert_eq!(result.is_ok(), Path::new(&flake_file).exists());
+                if result.is_ok() {
+                    fs::remove_file(&flake_file).expect("Unable to remove file");
+                }
+                return result.is_ok();
+            };
+            let csv = |contents: &str| try_import(ExportFormat::Csv, contents, false);
+            let json = |contents: &str| try_import(ExportFormat::JsonLines, contents, false);
+
+            assert_eq!(true, csv("x,y\n0,0\n0.5,0\n"));
+            assert_eq!(
+                false,
+                try_import(ExportFormat::Csv, "x,y\n0,0\n0.5,0\n", true)
+            );
+            assert_eq!(true, try_import(ExportFormat::Csv, "x,y\n0,0\n1,0\n", true));
+            assert_eq!(false, csv("x,y\n0,0\n0,0\n"));
+            assert_eq!(false, csv("x,y\nNaN,0\n"));
+            assert_eq!(false, csv("x,y\n0,inf\n"));
+            assert_eq!(false, csv("x,y\n0,\n"));
+            assert_eq!(false, csv("x,y\n0,0,0\n"));
+            assert_eq!(false, csv("x,z\n0,0\n"));
+            assert_eq!(false, csv("x,x\n0,0\n"));
+            assert_eq!(false, csv("x\n0\n"));
+            assert_eq!(false, csv("x,y,species\n0,0,256\n"));
+            assert_eq!(false, csv("x,y,index\n0,0,1\n"));
+            assert_eq!(true, csv("x,y,parent\n0,0,\n1,0,0\n"));
+            assert_eq!(false, csv("x,y,parent\n0,0,0\n"));
+            assert_eq!(false, csv("x,y,parent\n0,0,\n1,0,-1\n"));
+
+            assert_eq!(
+                true,
+                json("{\"x\":0,\"y\":0,\"time\":null}\n{ \"y\": 1, \"x\": 1e0 }\n")
+            );
+            assert_eq!(false, json("{\"x\":0,\"y\":null}\n"));
+            assert_eq!(false, json("{\"x\":0,\"y\":0,\"time\":\"now\"}\n"));
+            assert_eq!(false, json("{x:0,y:0}\n"));
+            assert_eq!(false, json("[0,0]\n"));
+        });
+    }
+}
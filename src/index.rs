@@ -0,0 +1,401 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::distance_field::DistanceField;
+use crate::flake::Flake;
+use crate::point::Point;
+use crate::quad_tree::QuadTree;
+use crate::spatial_index::{Backend, SpatialIndex};
+
+// Index files start with these bytes followed by a big-endian u32 version,
+// and end with a CRC-32 checksum of everything before it.
+const MAGIC: &[u8; 8] = b"SNOWIDX\0";
+const VERSION: u32 = 4;
+
+// The spatial indexes built from the points of a flake. These are saved to
+// a file alongside the flake file so that resuming generation doesn't have to
+// rebuild them. The index file records the length and fingerprint of the
+// flake file it was built from. If the flake file has only been added to
+// since, the particles after that length are read and added to the loaded
+// indexes, and otherwise they are rebuilt. Only quadtrees are saved, so other kinds of spatial index are
+// always rebuilt.
+pub struct Index {
+    pub tree: Box<dyn SpatialIndex>,
+    pub field: DistanceField,
+}
+
+impl Index {
+    // Build the indexes from the points in the flake file, using the given
+    // kind of spatial index.
+    pub fn build(flake: &Flake, backend: Backend) -> Result<Index> {
+        let points = flake.get_points().context("Unable to get flake points")?;
+        return Result::Ok(Index::from_points(&points, backend));
+    }
+
+    // Build the indexes from the given points, using the given kind of
+    // spatial index. Points are indexed in the order given.
+    pub fn from_points(points: &[Point], backend: Backend) -> Index {
+        return Index {
+            tree: backend.build(points),
+            field: DistanceField::from_points(points),
+        };
+    }
+
+    // Add the point to both indexes.
+    pub fn add_point(&mut self, point: &Point) {
+        self.tree.add_point(point);
+        self.field.add_point(point);
+    }
+
+    // Load the indexes from the flake's index file, or build them from the
+    // flake file if the index file is missing or out of date.
+    pub fn load_or_build(flake: &Flake, backend: Backend) -> Result<Index> {
+        if backend != Backend::QuadTree {
+            return Index::build(flake, backend);
+        }
+        match Index::load(flake) {
+            Result::Ok(Option::Some(index)) => return Result::Ok(index),
+            Result::Ok(Option::None) => {
+                if Path::new(&index_file(flake)).exists() {
+                    println!("Index file is out of date, rebuilding");
+                }
+            }
+            Result::Err(err) => println!("Rebuilding unreadable index file: {:#}", err),
+        };
+        return Index::build(flake, backend);
+    }
+
+    // Load the indexes from the flake's index file, adding any particles that
+    // have been added to the flake file since it was saved. Returns None if
+    // there is no index file or the flake file has been changed in any other
+    // way. The tree is always a quadtree.
+    pub fn load(flake: &Flake) -> Result<Option<Index>> {
+        let index_file = index_file(flake);
+        if !Path::new(&index_file).exists() {
+            return Result::Ok(Option::None);
+        }
+        let contents = fs::read(&index_file).context("Unable to read index file")?;
+        if contents.len() < 4 {
+            return Result::Err(anyhow!("Index file is truncated"));
+        }
+        let (contents, crc) = contents.split_at(contents.len() - 4);
+        if payload_checksum(contents).to_be_bytes() != crc {
+            return Result::Err(anyhow!("Index file checksum doesn't match"));
+        }
+
+        let mut decoder = Decoder::new(contents);
+        if &decoder.bytes::<8>()? != MAGIC {
+            return Result::Err(anyhow!("{} is not an index file", index_file));
+        }
+        let version = decoder.u32()?;
+        if version != VERSION {
+            return Result::Err(anyhow!("Unsupported index file version {}", version));
+        }
+
+        let length = decoder.u64()?;
+        let fingerprint = decoder.u32()?;
+        let num_points = decoder.u64()?;
+        let prefix_fingerprint = flake
+            .fingerprint(length)
+            .context("Unable to fingerprint flake file")?;
+        if prefix_fingerprint != Option::Some(fingerprint) {
+            return Result::Ok(Option::None);
+        }
+
+        let mut tree = QuadTree::decode(&mut decoder)?;
+        let mut field = DistanceField::decode(&mut decoder)?;
+        if tree.len() as u64 != num_points || !decoder.is_finished() {
+            return Result::Err(anyhow!("Index file is corrupt"));
+        }
+
+        // Catch up with any particles added since, such as by a run that was
+        // interrupted before it saved the index again
+        let particles = flake
+            .iter_particles_after(length, num_points)
+            .context("Unable to read flake particles")?;
+        for particle in particles {
+            let point = particle.context("Unable to read flake particles")?.point;
+            tree.add_point(&point);
+            field.add_point(&point);
+        }
+        return Result::Ok(Option::Some(Index {
+            tree: Box::new(tree),
+            field,
+        }));
+    }
+
+    // Save the indexes to the flake's index file. The flake should have been
+    // flushed first, so that the indexes match the flake file. Does nothing
+    // for indexes that aren't quadtrees.
+    pub fn save(&self, flake: &Flake) -> Result<()> {
+        let tree = match self.tree.as_quad_tree() {
+            Option::Some(tree) => tree,
+            Option::None => return Result::Ok(()),
+        };
+        let length = flake.length()?;
+        let fingerprint = flake
+            .fingerprint(length)
+            .context("Unable to fingerprint flake file")?
+            .ok_or_else(|| anyhow!("Flake file was cut back while saving the index"))?;
+
+        let mut buf = Vec::new();
+        buf.extend_from_slice(MAGIC);
+        buf.extend_from_slice(&VERSION.to_be_bytes());
+        buf.extend_from_slice(&length.to_be_bytes());
+        buf.extend_from_slice(&fingerprint.to_be_bytes());
+        buf.extend_from_slice(&(tree.len() as u64).to_be_bytes());
+        tree.encode(&mut buf);
+        self.field.encode(&mut buf);
+        let crc = payload_checksum(&buf);
+        buf.extend_from_slice(&crc.to_be_bytes());
+
+        // Write to a temporary file first so the index file is never left
+        // half written
+        let index_file = index_file(flake);
+        let temp_file = format!("{}.tmp", index_file);
+        fs::write(&temp_file, &buf).context("Unable to write index file")?;
+        fs::rename(&temp_file, &index_file).context("Unable to replace index file")?;
+        return Result::Ok(());
+    }
+}
+
+impl Clone for Index {
+    fn clone(&self) -> Index {
+        return Index {
+            tree: self.tree.clone_box(),
+            field: self.field.clone(),
+        };
+    }
+}
+
+fn payload_checksum(bytes: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(bytes);
+    return hasher.finalize();
+}
+
+// Returns the location of the index file for the given flake.
+fn index_file(flake: &Flake) -> String {
+    return format!("{}.index", flake.path());
+}
+
+// Reads big-endian values in turn from the contents of an index file.
+pub struct Decoder<'a> {
+    buf: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(buf: &'a [u8]) -> Decoder<'a> {
+        return Decoder { buf, position: 0 };
+    }
+
+    pub fn is_finished(&self) -> bool {
+        return self.position == self.buf.len();
+    }
+
+    // Returns the number of bytes left to read.
+    pub fn remaining(&self) -> usize {
+        return self.buf.len() - self.position;
+    }
+
+    fn bytes<const N: usize>(&mut self) -> Result<[u8; N]> {
+        if self.buf.len() - self.position < N {
+            return Result::Err(anyhow!("Index file is truncated"));
+        }
+        let mut bytes = [0; N];
+        bytes.copy_from_slice(&self.buf[self.position..self.position + N]);
+        self.position += N;
+        return Result::Ok(bytes);
+    }
+
+    pub fn u8(&mut self) -> Result<u8> {
+        return Result::Ok(self.bytes::<1>()?[0]);
+    }
+
+    pub fn u32(&mut self) -> Result<u32> {
+        return Result::Ok(u32::from_be_bytes(self.bytes()?));
+    }
+
+    pub fn u64(&mut self) -> Result<u64> {
+        return Result::Ok(u64::from_be_bytes(self.bytes()?));
+    }
+
+    pub fn f64(&mut self) -> Result<f64> {
+        return Result::Ok(f64::from_be_bytes(self.bytes()?));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use super::{index_file, Index};
+    use crate::flake::Flake;
+    use crate::point::Point;
+    use crate::spatial_index::Backend;
+    use crate::test_utils::test::with_test_dir;
+
+    fn add_random_points(flake: &mut Flake, num_points: usize, rng: &mut StdRng) {
+        for _i in 0..num_points {
+            flake
+                .add_point(&Point {
+                    x: rng.gen_range(-300.0..300.0),
+                    y: rng.gen_range(-300.0..300.0),
+                })
+                .expect("Unable to add point");
+        }
+        flake.flush().expect("Unable to flush");
+    }
+
+    #[test]
+    fn save_and_load() {
+        with_test_dir(|test_dir: &str| {
+            let mut rng = StdRng::seed_from_u64(41);
+            let mut flake =
+                Flake::new(&format!("{}/test.flake", test_dir)).expect("Unable to open flake");
+            add_random_points(&mut flake, 3000, &mut rng);
+
+            let built = Index::build(&flake, Backend::QuadTree).expect("Unable to build index");
+            built.save(&flake).expect("Unable to save index");
+            let loaded = Index::load(&flake)
+                .expect("Unable to load index")
+                .expect("Index is out of date");
+
+            assert_eq!(built.tree.len(), loaded.tree.len());
+            assert_eq!(
+                built.tree.get_farthest_distance(),
+                loaded.tree.get_farthest_distance()
+            );
+            for _i in 0..1000 {
+                let query_point = Point {
+                    x: rng.gen_range(-400.0..400.0),
+                    y: rng.gen_range(-400.0..400.0),
+                };
+                let expected = built.tree.get_nearest(&query_point).unwrap();
+                let actual = loaded.tree.get_nearest(&query_point).unwrap();
+                assert_eq!(expected.1, actual.1);
+                assert_eq!(expected.2, actual.2);
+                assert_eq!(
+                    built.field.get_lower_bound(&query_point),
+                    loaded.field.get_lower_bound(&query_point)
+                );
+            }
+
+            // The loaded tree can still be added to
+            let mut tree = loaded.tree;
+            tree.add_point(&Point { x: 1000.0, y: 0.0 });
+            assert_eq!(3000, tree.get_nearest(&Point { x: 1000.0, y: 0.0 }).unwrap().2);
+        });
+    }
+
+    #[test]
+    fn stale_index_is_updated_or_rebuilt() {
+        with_test_dir(|test_dir: &str| {
+            let mut rng = StdRng::seed_from_u64(43);
+            let mut flake =
+                Flake::new(&format!("{}/test.flake", test_dir)).expect("Unable to open flake");
+            add_random_points(&mut flake, 100, &mut rng);
+            Index::build(&flake, Backend::QuadTree)
+                .expect("Unable to build index")
+                .save(&flake)
+                .expect("Unable to save index");
+
+            // Particles added since the index was saved are added to it
+            add_random_points(&mut flake, 1, &mut rng);
+            let index = Index::load(&flake)
+                .expect("Unable to load index")
+                .expect("Index is out of date");
+            assert_eq!(101, index.tree.len());
+            let points = flake.get_points().expect("Unable to get points");
+            assert_eq!(100, index.tree.get_nearest(&points[100]).unwrap().2);
+            assert_eq!(0.0, index.field.get_lower_bound(&points[100]));
+
+            // Only the particles after the saved length are read, so damage
+            // before it, in the middle of the file, goes unnoticed
+            let mut large =
+                Flake::new(&format!("{}/large.flake", test_dir)).expect("Unable to open flake");
+            add_random_points(&mut large, 10000, &mut rng);
+            Index::build(&large, Backend::QuadTree)
+                .expect("Unable to build index")
+                .save(&large)
+                .expect("Unable to save index");
+            add_random_points(&mut large, 10, &mut rng);
+            let mut contents = fs::read(large.path()).expect("Unable to read flake");
+            let middle = contents.len() / 2;
+            contents[middle] ^= 1;
+            fs::write(large.path(), &contents).expect("Unable to write flake");
+            assert_eq!(true, large.check().expect("Unable to check flake").problem.is_some());
+            let index = Index::load(&large)
+                .expect("Unable to load index")
+                .expect("Index is out of date");
+            assert_eq!(10010, index.tree.len());
+
+            // But a flake file that has been changed in other ways isn't used
+            fs::remove_file(flake.path()).expect("Unable to remove flake");
+            let mut flake =
+                Flake::new(&format!("{}/test.flake", test_dir)).expect("Unable to open flake");
+            add_random_points(&mut flake, 101, &mut rng);
+            assert_eq!(true, Index::load(&flake).expect("Unable to load index").is_none());
+            let index =
+                Index::load_or_build(&flake, Backend::QuadTree).expect("Unable to load index");
+            assert_eq!(101, index.tree.len());
+        });
+    }
+
+    #[test]
+    fn corrupt_index_is_rebuilt() {
+        with_test_dir(|test_dir: &str| {
+            let mut rng = StdRng::seed_from_u64(47);
+            let mut flake =
+                Flake::new(&format!("{}/test.flake", test_dir)).expect("Unable to open flake");
+            add_random_points(&mut flake, 100, &mut rng);
+            Index::build(&flake, Backend::QuadTree)
+                .expect("Unable to build index")
+                .save(&flake)
+                .expect("Unable to save index");
+
+            let contents = fs::read(index_file(&flake)).expect("Unable to read index");
+            fs::write(index_file(&flake), &contents[..contents.len() - 1])
+                .expect("Unable to write index");
+            assert_eq!(true, Index::load(&flake).is_err());
+
+            fs::write(index_file(&flake), "not an index").expect("Unable to write index");
+            assert_eq!(true, Index::load(&flake).is_err());
+
+            // Any change to the contents is caught by the checksum
+            let mut changed = contents.clone();
+            changed[contents.len() / 2] ^= 1;
+            fs::write(index_file(&flake), &changed).expect("Unable to write index");
+            assert_eq!(true, Index::load(&flake).is_err());
+
+            let index =
+                Index::load_or_build(&flake, Backend::QuadTree).expect("Unable to load index");
+            assert_eq!(100, index.tree.len());
+        });
+    }
+
+    #[test]
+    fn other_backends_are_not_saved() {
+        with_test_dir(|test_dir: &str| {
+            let mut rng = StdRng::seed_from_u64(59);
+            let mut flake =
+                Flake::new(&format!("{}/test.flake", test_dir)).expect("Unable to open flake");
+            add_random_points(&mut flake, 100, &mut rng);
+
+            for backend in [Backend::Grid, Backend::KdTree].iter() {
+                let index = Index::load_or_build(&flake, *backend).expect("Unable to load index");
+                assert_eq!(100, index.tree.len());
+                assert_eq!(true, index.tree.as_quad_tree().is_none());
+                index.save(&flake).expect("Unable to save index");
+                assert_eq!(false, Path::new(&index_file(&flake)).exists());
+            }
+        });
+    }
+}
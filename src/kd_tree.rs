@@ -0,0 +1,334 @@
+use std::f64::consts::SQRT_2;
+
+use crate::point::Point;
+use crate::spatial_index::SpatialIndex;
+
+// Marks a missing child.
+const NONE: usize = usize::MAX;
+
+// A 2-d tree, splitting on x at even depths and y at odd depths. Each node
+// holds one point. Trees built from a set of points are balanced. Points
+// added afterwards go wherever they fall, but if one lands too deep the
+// subtree that has grown lopsided is rebuilt, as in a scapegoat tree, which
+// keeps the depth within about twice the log of the number of points. Many
+// points sharing a coordinate can still make the tree deeper than that.
+#[derive(Clone)]
+pub struct KdTree {
+    nodes: Vec<KdTreeNode>,
+    root: usize,
+    farthest_distance: f64,
+}
+
+#[derive(Clone)]
+struct KdTreeNode {
+    point: Point,
+    index: usize,
+    // The children with smaller and with larger or equal coordinates.
+    children: [usize; 2],
+}
+
+// Returns the coordinate that nodes at the given depth split on.
+fn coordinate(point: &Point, depth: usize) -> f64 {
+    return if depth % 2 == 1 { point.y } else { point.x };
+}
+
+impl KdTree {
+    pub fn new() -> KdTree {
+        return KdTree {
+            nodes: Vec::new(),
+            root: NONE,
+            farthest_distance: 0.0,
+        };
+    }
+
+    // Make a balanced tree holding the given points, indexed in the order given.
+    pub fn from_points(points: &[Point]) -> KdTree {
+        let mut tree = KdTree::new();
+        let mut entries: Vec<(Point, usize)> = points.iter().copied().zip(0..).collect();
+        tree.nodes.reserve(entries.len());
+        tree.root = tree.build(&mut entries, 0, &mut Vec::new());
+        for point in points {
+            tree.farthest_distance = tree.farthest_distance.max(point.distance(&Point::ZERO));
+        }
+        return tree;
+    }
+
+    // Add the given points as a balanced subtree with its root at the given
+    // depth, returning the root. Nodes are reused from the given list of
+    // unused nodes before any are added.
+    fn build(
+        &mut self,
+        entries: &mut [(Point, usize)],
+        depth: usize,
+        unused: &mut Vec<usize>,
+    ) -> usize {
+        if entries.is_empty() {
+            return NONE;
+        }
+
+        // Points equal to the median on this coordinate have to go to the
+        // right, to match how searches and insertion choose a side
+        let median = entries.len() / 2;
+        entries.select_nth_unstable_by(median, |a, b| {
+            coordinate(&a.0, depth)
+                .partial_cmp(&coordinate(&b.0, depth))
+                .unwrap()
+        });
+        let split = coordinate(&entries[median].0, depth);
+        let mut first = median;
+        while first > 0 && coordinate(&entries[first - 1].0, depth) == split {
+            first -= 1;
+        }
+        entries.swap(first, median);
+
+        let (point, index) = entries[first];
+        let new_node = KdTreeNode {
+            point,
+            index,
+            children: [NONE, NONE],
+        };
+        let node = match unused.pop() {
+            Option::Some(node) => {
+                self.nodes[node] = new_node;
+                node
+            }
+            Option::None => {
+                self.nodes.push(new_node);
+                self.nodes.len() - 1
+            }
+        };
+        let (left, right) = entries.split_at_mut(first);
+        self.nodes[node].children[0] = self.build(left, depth + 1, unused);
+        self.nodes[node].children[1] = self.build(&mut right[1..], depth + 1, unused);
+        return node;
+    }
+
+    // Returns the nodes in the subtree under the given node.
+    fn subtree(&self, node: usize) -> Vec<usize> {
+        let mut nodes = Vec::new();
+        let mut stack = vec![node];
+        while let Option::Some(node) = stack.pop() {
+            if node != NONE {
+                nodes.push(node);
+                stack.extend_from_slice(&self.nodes[node].children);
+            }
+        }
+        return nodes;
+    }
+
+    // Rebuild the lowest subtree on the path to a newly added node that is
+    // too lopsided. The path lists the new node's ancestors from the root.
+    // A subtree is too lopsided if one side holds more than 1 / sqrt(2) of
+    // its nodes, and one on the path must be if the new node is deeper than
+    // twice the log of the number of nodes.
+    fn rebalance(&mut self, path: &[usize], new_node: usize) {
+        let mut size = 1;
+        let mut child = new_node;
+        for (depth, node) in path.iter().enumerate().rev() {
+            let children = self.nodes[*node].children;
+            let sibling = if children[0] == child { children[1] } else { children[0] };
+            let total = size + self.subtree(sibling).len() + 1;
+            if size as f64 * SQRT_2 <= total as f64 {
+                size = total;
+                child = *node;
+                continue;
+            }
+
+            let mut unused = self.subtree(*node);
+            let mut entries: Vec<(Point, usize)> = unused
+                .iter()
+                .map(|node| (self.nodes[*node].point, self.nodes[*node].index))
+                .collect();
+            let rebuilt = self.build(&mut entries, depth, &mut unused);
+            if depth == 0 {
+                self.root = rebuilt;
+            } else {
+                let parent = path[depth - 1];
+                let side = if self.nodes[parent].children[0] == *node { 0 } else { 1 };
+                self.nodes[parent].children[side] = rebuilt;
+            }
+            return;
+        }
+    }
+
+    // Updates the given nearest node and its squared distance if any point
+    // in the subtree under the given node is nearer.
+    fn get_nearest_in(
+        &self,
+        node: usize,
+        depth: usize,
+        point: &Point,
+        nearest: &mut usize,
+        nearest_distance_2: &mut f64,
+    ) {
+        let node_point = &self.nodes[node].point;
+        let d2 = point.distance_2(node_point);
+        if d2 < *nearest_distance_2 {
+            *nearest = node;
+            *nearest_distance_2 = d2;
+        }
+
+        let offset = coordinate(point, depth) - coordinate(node_point, depth);
+        let (near, far) = if offset < 0.0 { (0, 1) } else { (1, 0) };
+        let children = self.nodes[node].children;
+        if children[near] != NONE {
+            self.get_nearest_in(children[near], depth + 1, point, nearest, nearest_distance_2);
+        }
+        if children[far] != NONE && offset * offset < *nearest_distance_2 {
+            self.get_nearest_in(children[far], depth + 1, point, nearest, nearest_distance_2);
+        }
+    }
+}
+
+impl SpatialIndex for KdTree {
+    fn add_point(&mut self, point: &Point) {
+        if !point.x.is_finite() || !point.y.is_finite() {
+            panic!("Unable to add point {} that is not finite", point);
+        }
+
+        let new_node = self.nodes.len();
+        self.nodes.push(KdTreeNode {
+            point: *point,
+            index: new_node,
+            children: [NONE, NONE],
+        });
+        self.farthest_distance = self.farthest_distance.max(point.distance(&Point::ZERO));
+        if self.root == NONE {
+            self.root = new_node;
+            return;
+        }
+
+        let mut path = Vec::new();
+        let mut node = self.root;
+        loop {
+            let depth = path.len();
+            path.push(node);
+            let side = if coordinate(point, depth) < coordinate(&self.nodes[node].point, depth) {
+                0
+            } else {
+                1
+            };
+            let child = self.nodes[node].children[side];
+            if child == NONE {
+                self.nodes[node].children[side] = new_node;
+                break;
+            }
+            node = child;
+        }
+
+        if path.len() as f64 > 2.0 * (self.nodes.len() as f64).log2() {
+            self.rebalance(&path, new_node);
+        }
+    }
+
+    fn get_nearest(&self, point: &Point) -> Option<(Point, f64, usize)> {
+        if self.root == NONE {
+            return Option::None;
+        }
+        let mut nearest = self.root;
+        let mut nearest_distance_2 = f64::INFINITY;
+        self.get_nearest_in(self.root, 0, point, &mut nearest, &mut nearest_distance_2);
+        let node = &self.nodes[nearest];
+        return Option::Some((node.point, nearest_distance_2.sqrt(), node.index));
+    }
+
+    fn get_farthest_distance(&self) -> f64 {
+        return self.farthest_distance;
+    }
+
+    fn len(&self) -> usize {
+        return self.nodes.len();
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (Point, usize)> + '_> {
+        return Box::new(self.nodes.iter().map(|node| (node.point, node.index)));
+    }
+
+    fn clone_box(&self) -> Box<dyn SpatialIndex> {
+        return Box::new(self.clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{KdTree, NONE};
+    use crate::point::Point;
+    use crate::spatial_index::SpatialIndex;
+
+    // Returns the depth of the deepest node under the given node.
+    fn depth(tree: &KdTree, node: usize) -> usize {
+        if node == NONE {
+            return 0;
+        }
+        let children = tree.nodes[node].children;
+        return 1 + depth(tree, children[0]).max(depth(tree, children[1]));
+    }
+
+    #[test]
+    fn from_points_is_balanced() {
+        // Points in a line would make a chain if added one at a time
+        let points: Vec<Point> = (0..1023)
+            .map(|i| Point {
+                x: i as f64,
+                y: (i * 7 % 1023) as f64,
+            })
+            .collect();
+        let tree = KdTree::from_points(&points);
+        assert_eq!(10, depth(&tree, tree.root));
+
+        for (i, point) in points.iter().enumerate() {
+            let nearest = tree.get_nearest(point).unwrap();
+            assert_eq!(0.0, nearest.1);
+            assert_eq!(i, nearest.2);
+        }
+    }
+
+    #[test]
+    fn add_point_rebalances() {
+        // Points added in order along x, as when a flake grows outwards,
+        // would make a chain if the tree were never rebuilt
+        let mut tree = KdTree::new();
+        for i in 0..10000 {
+            tree.add_point(&Point {
+                x: i as f64,
+                y: (i * 7 % 10007) as f64,
+            });
+            if i % 100 == 0 {
+                let max_depth = 2.0 * ((i + 1) as f64).log2() + 1.0;
+                assert_eq!(true, depth(&tree, tree.root) as f64 <= max_depth);
+            }
+        }
+
+        assert_eq!(10000, tree.len());
+        for i in 0..10000 {
+            let point = Point {
+                x: i as f64,
+                y: (i * 7 % 10007) as f64,
+            };
+            let nearest = tree.get_nearest(&point).unwrap();
+            assert_eq!(0.0, nearest.1);
+            assert_eq!(i, nearest.2);
+        }
+        let mut indices: Vec<usize> = tree.iter().map(|(_, index)| index).collect();
+        indices.sort();
+        assert_eq!((0..10000).collect::<Vec<usize>>(), indices);
+    }
+
+    #[test]
+    fn repeated_coordinates() {
+        // Many points sharing coordinates must all be found on the right side
+        let mut points: Vec<Point> = Vec::new();
+        for i in 0..200 {
+            points.push(Point {
+                x: (i % 3) as f64,
+                y: (i % 5) as f64,
+            });
+        }
+        let mut tree = KdTree::from_points(&points);
+        tree.add_point(&Point { x: 1.0, y: 2.5 });
+        for point in &points {
+            assert_eq!(0.0, tree.get_nearest(point).unwrap().1);
+        }
+        assert_eq!(200, tree.get_nearest(&Point { x: 1.0, y: 2.6 }).unwrap().2);
+    }
+}
@@ -3,16 +3,41 @@ extern crate anyhow;
 extern crate image;
 extern crate rand;
 
+mod compact;
+mod distance_field;
+mod edit;
+mod export;
 mod flake;
+mod fsck;
 mod generate;
+mod grid;
+mod import;
+mod index;
+mod kd_tree;
 mod point;
 mod quad_tree;
 mod render;
+mod schedule;
+mod shared_index;
+mod spatial_index;
+mod species;
+mod stop;
 mod test_utils;
 
-use crate::flake::Flake;
-use crate::generate::{generate, Symmetry};
-use crate::render::render;
+use std::time::Duration;
+
+use crate::edit::{merge, parse_offset, slice, transform, Transform};
+use crate::export::{export, Column, ExportFormat};
+use crate::flake::{Flake, FlushPolicy};
+use crate::fsck::fsck;
+use crate::generate::{generate, RunOptions, Symmetry};
+use crate::import::import;
+use crate::point::Point;
+use crate::render::{render, watch, Viewport};
+use crate::schedule::Schedule;
+use crate::spatial_index::Backend;
+use crate::species::Species;
+use crate::stop::{Combine, StopCondition, StopConditions};
 
 fn main() {
     match parse_args().subcommand() {
@@ -20,17 +45,20 @@ fn main() {
             let flake_file = args
                 .value_of("flake-file")
                 .expect("flake-file not passed");
-            let mut flake = Flake::new(&flake_file);
+            let mut flake = Flake::new(&flake_file)
+                .unwrap_or_else(|err| exit_with_error("Unable to open flake", err));
+            flake
+                .lock()
+                .unwrap_or_else(|err| exit_with_error("Unable to lock flake", err));
+            flake.set_flush_policy(parse_flush_policy(args));
+            // Existing flake files keep their encoding
+            if args.is_present("compact") && flake.is_new() {
+                flake
+                    .use_compact_encoding()
+                    .unwrap_or_else(|err| exit_with_error("Unable to use compact encoding", err));
+            }
 
-            let num_points = args
-                .value_of("num-particles")
-                .unwrap_or("0")
-                .parse::<u32>()
-                .expect("num-particles arg could not be parsed");
-            let num_points = match num_points {
-                0 => Option::None,
-                x => Option::Some(x),
-            };
+            let stop = parse_stop_conditions(args);
 
             let rotational = args
                 .value_of("rotational-symmetry")
@@ -44,36 +72,238 @@ fn main() {
                 .expect("reflectional-symmetry arg could not be parsed");
             let symmetry = Symmetry::from(rotational, reflectional);
 
-            match generate(&mut flake, symmetry, num_points) {
-                Err(err) => {
-                    println!("Unable to generate flake");
-                    for cause in err.chain() {
-                        println!("{}", cause);
-                    }
-                    std::process::exit(1);
-                },
-                _ => {}
+            let schedule = match args.value_of("schedule") {
+                Option::Some(schedule_file) => Schedule::from_file(schedule_file)
+                    .unwrap_or_else(|err| exit_with_error("Unable to load schedule", err)),
+                Option::None => Schedule::default(),
+            };
+
+            let species = load_species(args);
+
+            let record_time = args.is_present("record-time");
+
+            let backend = Backend::from(args.value_of("index").unwrap_or("quadtree"))
+                .unwrap_or_else(|err| exit_with_error("Unable to parse index arg", err));
+
+            let seed = args.value_of("seed").map(|seed| {
+                seed.parse::<u64>()
+                    .expect("seed arg could not be parsed")
+            });
+
+            let threads = args
+                .value_of("threads")
+                .unwrap_or("1")
+                .parse::<usize>()
+                .expect("threads arg could not be parsed");
+            if threads == 0 {
+                exit_with_error("Invalid threads arg", anyhow::anyhow!("Expected at least 1"));
+            }
+
+            let options = RunOptions {
+                record_time,
+                backend,
+                seed,
+                threads,
+            };
+            if let Result::Err(err) =
+                generate(&mut flake, symmetry, &schedule, &species, &stop, &options)
+            {
+                exit_with_error("Unable to generate flake", err);
             }
         }
         ("render", Some(args)) => {
             let flake_file = args
                 .value_of("flake-file")
                 .expect("flake-file not passed");
-            let flake = Flake::new(&flake_file);
+            let flake = Flake::new(&flake_file)
+                .unwrap_or_else(|err| exit_with_error("Unable to open flake", err));
+
+            let output_file = args
+                .value_of("output")
+                .expect("output not passed");
+
+            let species = load_species(args);
+
+            let viewport = args.value_of("viewport").map(|viewport| {
+                Viewport::parse(viewport)
+                    .unwrap_or_else(|err| exit_with_error("Unable to parse viewport arg", err))
+            });
+
+            let result = if args.is_present("watch") {
+                let seconds = args
+                    .value_of("interval")
+                    .unwrap_or("1")
+                    .parse::<f64>()
+                    .expect("interval arg could not be parsed");
+                let interval = to_duration(seconds, "interval");
+                watch(&flake, &species, viewport.as_ref(), &output_file, interval)
+            } else {
+                render(&flake, &species, viewport.as_ref(), &output_file)
+            };
+            if let Result::Err(err) = result {
+                exit_with_error("Unable to render flake", err);
+            }
+        }
+        ("fsck", Some(args)) => {
+            let flake_file = args
+                .value_of("flake-file")
+                .expect("flake-file not passed");
+            let mut flake = Flake::new(&flake_file)
+                .unwrap_or_else(|err| exit_with_error("Unable to open flake", err));
+
+            if let Result::Err(err) = fsck(&mut flake, args.is_present("repair")) {
+                exit_with_error("Unable to check flake file", err);
+            }
+        }
+        ("export", Some(args)) => {
+            let flake_file = args
+                .value_of("flake-file")
+                .expect("flake-file not passed");
+            let flake = Flake::new(&flake_file)
+                .unwrap_or_else(|err| exit_with_error("Unable to open flake", err));
 
             let output_file = args
                 .value_of("output")
                 .expect("output not passed");
-            
-            match render(&flake, &output_file) {
-                Err(err) => {
-                    println!("Unable to render flake");
-                    for cause in err.chain() {
-                        println!("{}", cause);
-                    }
-                    std::process::exit(1);
-                },
-                _ => {}
+
+            let format = match args.value_of("format") {
+                Option::Some(format) => ExportFormat::from(format),
+                Option::None => ExportFormat::from_filename(output_file),
+            }
+            .unwrap_or_else(|err| exit_with_error("Unable to parse format arg", err));
+
+            let columns = match args.value_of("columns") {
+                Option::Some(columns) => Column::parse_list(columns)
+                    .unwrap_or_else(|err| exit_with_error("Unable to parse columns arg", err)),
+                Option::None => Column::available(&flake),
+            };
+
+            let precision = args.value_of("precision").map(|precision| {
+                precision
+                    .parse::<usize>()
+                    .expect("precision arg could not be parsed")
+            });
+
+            if let Result::Err(err) = export(&flake, format, &columns, precision, output_file) {
+                exit_with_error("Unable to export flake", err);
+            }
+        }
+        ("import", Some(args)) => {
+            let input_file = args
+                .value_of("input")
+                .expect("input not passed");
+
+            let flake_file = args
+                .value_of("flake-file")
+                .expect("flake-file not passed");
+            let mut flake = Flake::new(&flake_file)
+                .unwrap_or_else(|err| exit_with_error("Unable to open flake", err));
+            if args.is_present("compact") && flake.is_new() {
+                flake
+                    .use_compact_encoding()
+                    .unwrap_or_else(|err| exit_with_error("Unable to use compact encoding", err));
+            }
+
+            let format = match args.value_of("format") {
+                Option::Some(format) => ExportFormat::from(format),
+                Option::None => ExportFormat::from_filename(input_file),
+            }
+            .unwrap_or_else(|err| exit_with_error("Unable to parse format arg", err));
+
+            let strict_spacing = args.is_present("strict-spacing");
+
+            if let Result::Err(err) = import(input_file, format, &mut flake, strict_spacing) {
+                exit_with_error("Unable to import flake", err);
+            }
+        }
+        ("slice", Some(args)) => {
+            let flake_file = args
+                .value_of("flake-file")
+                .expect("flake-file not passed");
+            let flake = Flake::new(&flake_file)
+                .unwrap_or_else(|err| exit_with_error("Unable to open flake", err));
+
+            let output_file = args
+                .value_of("output")
+                .expect("output not passed");
+
+            let num_particles = args
+                .value_of("num-particles")
+                .expect("num-particles not passed")
+                .parse::<u64>()
+                .expect("num-particles arg could not be parsed");
+
+            if let Result::Err(err) =
+                slice(&flake, num_particles, output_file, args.is_present("compact"))
+            {
+                exit_with_error("Unable to slice flake", err);
+            }
+        }
+        ("merge", Some(args)) => {
+            let flakes: Vec<Flake> = args
+                .values_of("input")
+                .expect("input not passed")
+                .map(|flake_file| {
+                    Flake::new(flake_file)
+                        .unwrap_or_else(|err| exit_with_error("Unable to open flake", err))
+                })
+                .collect();
+
+            let output_file = args
+                .value_of("output")
+                .expect("output not passed");
+
+            // Without offsets, the flakes are merged where they are
+            let offsets: Vec<Point> = match args.values_of("offset") {
+                Option::Some(offsets) => offsets
+                    .map(|offset| {
+                        parse_offset(offset).unwrap_or_else(|err| {
+                            exit_with_error("Unable to parse offset arg", err)
+                        })
+                    })
+                    .collect(),
+                Option::None => vec![Point::ZERO; flakes.len()],
+            };
+
+            if let Result::Err(err) =
+                merge(&flakes, &offsets, output_file, args.is_present("compact"))
+            {
+                exit_with_error("Unable to merge flakes", err);
+            }
+        }
+        ("transform", Some(args)) => {
+            let flake_file = args
+                .value_of("flake-file")
+                .expect("flake-file not passed");
+            let flake = Flake::new(&flake_file)
+                .unwrap_or_else(|err| exit_with_error("Unable to open flake", err));
+
+            let output_file = args
+                .value_of("output")
+                .expect("output not passed");
+
+            let rotation = args
+                .value_of("rotate")
+                .unwrap_or("0")
+                .parse::<f64>()
+                .expect("rotate arg could not be parsed");
+            let scale = args
+                .value_of("scale")
+                .unwrap_or("1")
+                .parse::<f64>()
+                .expect("scale arg could not be parsed");
+            let translation = match args.value_of("translate") {
+                Option::Some(translation) => parse_offset(translation)
+                    .unwrap_or_else(|err| exit_with_error("Unable to parse translate arg", err)),
+                Option::None => Point::ZERO,
+            };
+            let transformation = Transform::new(rotation, scale, translation)
+                .unwrap_or_else(|err| exit_with_error("Unable to parse transform args", err));
+
+            if let Result::Err(err) =
+                transform(&flake, &transformation, output_file, args.is_present("compact"))
+            {
+                exit_with_error("Unable to transform flake", err);
             }
         }
         (command, _)  => {
@@ -83,6 +313,88 @@ fn main() {
     }
 }
 
+fn parse_stop_conditions(args: &clap::ArgMatches) -> StopConditions {
+    let mut conditions = Vec::new();
+
+    let num_points = args
+        .value_of("num-particles")
+        .unwrap_or("0")
+        .parse::<u32>()
+        .expect("num-particles arg could not be parsed");
+    if num_points > 0 {
+        conditions.push(StopCondition::NumParticles(num_points));
+    }
+    if let Option::Some(radius) = args.value_of("target-radius") {
+        let radius = radius
+            .parse::<f64>()
+            .expect("target-radius arg could not be parsed");
+        conditions.push(StopCondition::Radius(radius));
+    }
+    if let Option::Some(seconds) = args.value_of("time-budget") {
+        let seconds = seconds
+            .parse::<f64>()
+            .expect("time-budget arg could not be parsed");
+        conditions.push(StopCondition::Time(to_duration(seconds, "time-budget")));
+    }
+    if let Option::Some(dimension) = args.value_of("target-dimension") {
+        let dimension = dimension
+            .parse::<f64>()
+            .expect("target-dimension arg could not be parsed");
+        conditions.push(StopCondition::FractalDimension(dimension));
+    }
+    if let Option::Some(size) = args.value_of("boundary-box") {
+        let boundary = StopCondition::parse_boundary(size)
+            .unwrap_or_else(|err| exit_with_error("Unable to parse boundary-box arg", err));
+        conditions.push(boundary);
+    }
+
+    let combine = Combine::from(args.value_of("stop-when").unwrap_or("any"))
+        .unwrap_or_else(|err| exit_with_error("Unable to parse stop-when arg", err));
+
+    return StopConditions::new(conditions, combine);
+}
+
+fn parse_flush_policy(args: &clap::ArgMatches) -> FlushPolicy {
+    let mut flush_policy = FlushPolicy::default();
+    if let Option::Some(particles) = args.value_of("flush-particles") {
+        flush_policy.max_particles = particles
+            .parse::<usize>()
+            .expect("flush-particles arg could not be parsed");
+    }
+    if let Option::Some(seconds) = args.value_of("flush-seconds") {
+        let seconds = seconds
+            .parse::<f64>()
+            .expect("flush-seconds arg could not be parsed");
+        flush_policy.max_time = Option::Some(to_duration(seconds, "flush-seconds"));
+    }
+    flush_policy.sync = args.is_present("sync");
+    return flush_policy;
+}
+
+// Convert a number of seconds passed as an arg to a duration, which must
+// not be negative.
+fn to_duration(seconds: f64, arg: &str) -> Duration {
+    return Duration::try_from_secs_f64(seconds).unwrap_or_else(|err| {
+        exit_with_error(&format!("Invalid {} arg: {}", arg, seconds), err.into())
+    });
+}
+
+fn load_species(args: &clap::ArgMatches) -> Vec<Species> {
+    return match args.value_of("species") {
+        Option::Some(species_file) => Species::from_file(species_file)
+            .unwrap_or_else(|err| exit_with_error("Unable to load species", err)),
+        Option::None => Species::defaults(),
+    };
+}
+
+fn exit_with_error(message: &str, err: anyhow::Error) -> ! {
+    println!("{}", message);
+    for cause in err.chain() {
+        println!("{}", cause);
+    }
+    std::process::exit(1);
+}
+
 fn parse_args() -> clap::ArgMatches<'static> {
     return clap::App::new("snowflake-rs")
         .about("Generates snowflakes through random motion")
@@ -100,7 +412,33 @@ fn parse_args() -> clap::ArgMatches<'static> {
                     .long("num-particles")
                     .number_of_values(1)
                     .value_name("NUM")
-                    .help("The number of particles to add to the flake, or omit to add indefinitely until stopped"))
+                    .help("The number of particles to add to the flake"))
+                .arg(clap::Arg::with_name("target-radius")
+                    .long("target-radius")
+                    .number_of_values(1)
+                    .value_name("RADIUS")
+                    .help("Stop once the flake has grown to this radius"))
+                .arg(clap::Arg::with_name("time-budget")
+                    .long("time-budget")
+                    .number_of_values(1)
+                    .value_name("SECONDS")
+                    .help("Stop once generation has been running for this long"))
+                .arg(clap::Arg::with_name("target-dimension")
+                    .long("target-dimension")
+                    .number_of_values(1)
+                    .value_name("DIMENSION")
+                    .help("Stop once the estimated fractal dimension of the flake falls to this value"))
+                .arg(clap::Arg::with_name("boundary-box")
+                    .long("boundary-box")
+                    .number_of_values(1)
+                    .value_name("WIDTHxHEIGHT")
+                    .help("Stop once a particle, including those from earlier runs, reaches the edge of a box of this size centred on the flake"))
+                .arg(clap::Arg::with_name("stop-when")
+                    .long("stop-when")
+                    .number_of_values(1)
+                    .possible_values(&["any", "all"])
+                    .value_name("any|all")
+                    .help("Whether to stop when any or all of the stop conditions are met, default any. With no stop conditions, 1000 particles are added"))
                 .arg(clap::Arg::with_name("rotational-symmetry")
                     .long("rotational-symmetry")
                     .number_of_values(1)
@@ -111,7 +449,52 @@ fn parse_args() -> clap::ArgMatches<'static> {
                     .number_of_values(1)
                     .value_name("NUM")
                     .help("The number of axis of reflectional symmetry to use, or omit to have no reflectional symmetry")
-                    .conflicts_with("rotational-symmetry")))
+                    .conflicts_with("rotational-symmetry"))
+                .arg(clap::Arg::with_name("schedule")
+                    .long("schedule")
+                    .number_of_values(1)
+                    .value_name("FILE")
+                    .help("Config file giving growth parameters as functions of particle index or flake radius"))
+                .arg(clap::Arg::with_name("species")
+                    .long("species")
+                    .number_of_values(1)
+                    .value_name("FILE")
+                    .help("Config file defining the species of particle to release, or omit to use a single species"))
+                .arg(clap::Arg::with_name("record-time")
+                    .long("record-time")
+                    .help("Record the wall-clock time each particle was added at"))
+                .arg(clap::Arg::with_name("flush-particles")
+                    .long("flush-particles")
+                    .number_of_values(1)
+                    .value_name("NUM")
+                    .help("Write particles to the flake file once this many have been added, default 1000"))
+                .arg(clap::Arg::with_name("flush-seconds")
+                    .long("flush-seconds")
+                    .number_of_values(1)
+                    .value_name("SECONDS")
+                    .help("Also write particles to the flake file once they have waited this long"))
+                .arg(clap::Arg::with_name("compact")
+                    .long("compact")
+                    .help("Store a new flake file in the compact encoding, which is several times smaller but stores positions less precisely"))
+                .arg(clap::Arg::with_name("sync")
+                    .long("sync")
+                    .help("Wait for each write to reach the disk, so particles written survive a power loss"))
+                .arg(clap::Arg::with_name("index")
+                    .long("index")
+                    .number_of_values(1)
+                    .possible_values(&["quadtree", "grid", "kd-tree"])
+                    .value_name("quadtree|grid|kd-tree")
+                    .help("The kind of spatial index to find nearby particles with, default quadtree. Only quadtrees are saved for resuming"))
+                .arg(clap::Arg::with_name("seed")
+                    .long("seed")
+                    .number_of_values(1)
+                    .value_name("NUM")
+                    .help("Seed for the random walks, to repeat an earlier run from the same starting flake, or omit to pick one at random"))
+                .arg(clap::Arg::with_name("threads")
+                    .long("threads")
+                    .number_of_values(1)
+                    .value_name("NUM")
+                    .help("The number of walkers to run at once, each on its own thread, default 1. Runs with more than one can't be repeated with --seed")))
         .subcommand(clap::App::new("render")
             .about("Render a flake file to an image")
             .arg(clap::Arg::with_name("flake-file")
@@ -127,6 +510,185 @@ fn parse_args() -> clap::ArgMatches<'static> {
                     .number_of_values(1)
                     .value_name("FILE")
                     .required(true)
-                    .help("Location of file to output image to")))
+                    .help("Location of file to output image to"))
+            .arg(clap::Arg::with_name("species")
+                    .long("species")
+                    .number_of_values(1)
+                    .value_name("FILE")
+                    .help("Config file defining the colour of each species, or omit to draw everything in white"))
+            .arg(clap::Arg::with_name("viewport")
+                    .long("viewport")
+                    .number_of_values(1)
+                    .value_name("LEFT,BOTTOM,RIGHT,TOP")
+                    .allow_hyphen_values(true)
+                    .help("Only render the region of the flake with the given corners, or omit to render the whole flake"))
+            .arg(clap::Arg::with_name("watch")
+                    .long("watch")
+                    .help("Keep rendering the flake as particles are added to it by generate, until interrupted"))
+            .arg(clap::Arg::with_name("interval")
+                    .long("interval")
+                    .number_of_values(1)
+                    .value_name("SECONDS")
+                    .requires("watch")
+                    .help("How often to check for new particles when watching, defaulting to 1")))
+        .subcommand(clap::App::new("fsck")
+            .about("Check a flake file for damage, such as a write cut short by a crash")
+            .arg(clap::Arg::with_name("flake-file")
+                    .short("f")
+                    .long("flake-file")
+                    .number_of_values(1)
+                    .value_name("FILE")
+                    .required(true)
+                    .help("Location of file to check"))
+            .arg(clap::Arg::with_name("repair")
+                    .long("repair")
+                    .help("Remove everything after the last valid record of a damaged file")))
+        .subcommand(clap::App::new("export")
+            .about("Export the particles of a flake file for use by other tools")
+            .arg(clap::Arg::with_name("flake-file")
+                    .short("f")
+                    .long("flake-file")
+                    .number_of_values(1)
+                    .value_name("FILE")
+                    .required(true)
+                    .help("Location of file to read flake information from"))
+            .arg(clap::Arg::with_name("output")
+                    .short("o")
+                    .long("output")
+                    .number_of_values(1)
+                    .value_name("FILE")
+                    .required(true)
+                    .help("Location of file to export to"))
+            .arg(clap::Arg::with_name("format")
+                    .long("format")
+                    .number_of_values(1)
+                    .value_name("csv|jsonl|npy")
+                    .help("Format to export to, or omit to tell from the output file's extension"))
+            .arg(clap::Arg::with_name("columns")
+                    .long("columns")
+                    .number_of_values(1)
+                    .value_name("COLUMNS")
+                    .help("Comma-separated columns to export, from index, x, y, species, parent, steps and time, or omit for all that the flake file records"))
+            .arg(clap::Arg::with_name("precision")
+                    .long("precision")
+                    .number_of_values(1)
+                    .value_name("DIGITS")
+                    .help("Round coordinates to this many decimal places, or omit to export them exactly")))
+        .subcommand(clap::App::new("import")
+            .about("Make a new flake file from particles exported by this or another tool")
+            .arg(clap::Arg::with_name("input")
+                    .short("i")
+                    .long("input")
+                    .number_of_values(1)
+                    .value_name("FILE")
+                    .required(true)
+                    .help("Location of file to import particles from"))
+            .arg(clap::Arg::with_name("flake-file")
+                    .short("f")
+                    .long("flake-file")
+                    .number_of_values(1)
+                    .value_name("FILE")
+                    .required(true)
+                    .help("Location of new file to write flake information to"))
+            .arg(clap::Arg::with_name("format")
+                    .long("format")
+                    .number_of_values(1)
+                    .value_name("csv|jsonl")
+                    .help("Format to import from, or omit to tell from the input file's extension"))
+            .arg(clap::Arg::with_name("strict-spacing")
+                    .long("strict-spacing")
+                    .help("Refuse to import particles closer together than a particle diameter, rather than only reporting them"))
+            .arg(clap::Arg::with_name("compact")
+                    .long("compact")
+                    .help("Store the flake file in the compact encoding, which is several times smaller but stores positions less precisely")))
+        .subcommand(clap::App::new("slice")
+            .about("Write the first particles of a flake to a new flake file")
+            .arg(clap::Arg::with_name("flake-file")
+                    .short("f")
+                    .long("flake-file")
+                    .number_of_values(1)
+                    .value_name("FILE")
+                    .required(true)
+                    .help("Location of file to read flake information from"))
+            .arg(clap::Arg::with_name("output")
+                    .short("o")
+                    .long("output")
+                    .number_of_values(1)
+                    .value_name("FILE")
+                    .required(true)
+                    .help("Location of new flake file to write to"))
+            .arg(clap::Arg::with_name("num-particles")
+                    .short("n")
+                    .long("num-particles")
+                    .number_of_values(1)
+                    .value_name("NUM")
+                    .required(true)
+                    .help("Number of particles to keep"))
+            .arg(clap::Arg::with_name("compact")
+                    .long("compact")
+                    .help("Store the new flake file in the compact encoding, which is several times smaller but stores positions less precisely")))
+        .subcommand(clap::App::new("merge")
+            .about("Write the particles of several flakes, one after another, to a new flake file")
+            .arg(clap::Arg::with_name("input")
+                    .short("i")
+                    .long("input")
+                    .number_of_values(1)
+                    .multiple(true)
+                    .value_name("FILE")
+                    .required(true)
+                    .help("Location of a flake file to merge, given once for each"))
+            .arg(clap::Arg::with_name("output")
+                    .short("o")
+                    .long("output")
+                    .number_of_values(1)
+                    .value_name("FILE")
+                    .required(true)
+                    .help("Location of new flake file to write to"))
+            .arg(clap::Arg::with_name("offset")
+                    .long("offset")
+                    .number_of_values(1)
+                    .multiple(true)
+                    .value_name("X,Y")
+                    .allow_hyphen_values(true)
+                    .help("Amount to move the particles of each input flake by, given once for each in the same order, or omit to leave them where they are"))
+            .arg(clap::Arg::with_name("compact")
+                    .long("compact")
+                    .help("Store the new flake file in the compact encoding, which is several times smaller but stores positions less precisely")))
+        .subcommand(clap::App::new("transform")
+            .about("Write the particles of a flake, rotated, scaled and translated in that order, to a new flake file")
+            .arg(clap::Arg::with_name("flake-file")
+                    .short("f")
+                    .long("flake-file")
+                    .number_of_values(1)
+                    .value_name("FILE")
+                    .required(true)
+                    .help("Location of file to read flake information from"))
+            .arg(clap::Arg::with_name("output")
+                    .short("o")
+                    .long("output")
+                    .number_of_values(1)
+                    .value_name("FILE")
+                    .required(true)
+                    .help("Location of new flake file to write to"))
+            .arg(clap::Arg::with_name("rotate")
+                    .long("rotate")
+                    .number_of_values(1)
+                    .value_name("DEGREES")
+                    .allow_hyphen_values(true)
+                    .help("Angle to rotate the particles anticlockwise about the origin by"))
+            .arg(clap::Arg::with_name("scale")
+                    .long("scale")
+                    .number_of_values(1)
+                    .value_name("FACTOR")
+                    .help("Factor to scale the particles about the origin by"))
+            .arg(clap::Arg::with_name("translate")
+                    .long("translate")
+                    .number_of_values(1)
+                    .value_name("X,Y")
+                    .allow_hyphen_values(true)
+                    .help("Amount to move the particles by"))
+            .arg(clap::Arg::with_name("compact")
+                    .long("compact")
+                    .help("Store the new flake file in the compact encoding, which is several times smaller but stores positions less precisely")))
         .get_matches();
 }
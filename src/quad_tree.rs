@@ -1,293 +1,941 @@
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Result};
 
-use crate::flake::Flake;
+use crate::index::Decoder;
 use crate::point::Point;
+use crate::spatial_index::SpatialIndex;
 
 const MAX_POINTS_NODE_SIZE: usize = 50;
 
-pub struct QuadTree {
-    root: QuadTreeNode,
-    farthest_distance: f64,
-}
-
-enum QuadTreeNode {
-    Points(QuadTreePointsNode),
-    Split(QuadTreeSplitNode),
-}
+// The root is always the first node in the arena.
+const ROOT: usize = 0;
 
-struct QuadTreePointsNode {
-    center: Point,
-    radius: f64,
+// Nodes are kept in a single arena and refer to each other by index, and the
+// points of every points node are packed into shared buffers, so queries
+// touch a few contiguous allocations rather than chasing pointers.
+#[derive(Clone)]
+pub struct QuadTree {
+    // The four children of a split node are stored next to each other, in
+    // the order north west, north east, south west, south east.
+    nodes: Vec<QuadTreeNode>,
+    // The first of each group of four nodes that is no longer in use.
+    free_nodes: Vec<usize>,
+    // Each points node owns a block of MAX_POINTS_NODE_SIZE entries in these.
     points: Vec<Point>,
+    // The index of each point in insertion order, in the same order as points.
+    indices: Vec<usize>,
+    // The start of each block of points that is no longer in use.
+    free_blocks: Vec<usize>,
+    num_points: usize,
+    // The index given to the next point added. Unlike num_points this never
+    // goes down, so indices stay unique after points are removed.
+    next_index: usize,
 }
 
-struct QuadTreeSplitNode {
+#[derive(Clone, Copy)]
+struct QuadTreeNode {
     center: Point,
     radius: f64,
-    north_west: Box<QuadTreeNode>,
-    north_east: Box<QuadTreeNode>,
-    south_west: Box<QuadTreeNode>,
-    south_east: Box<QuadTreeNode>,
+    // The distance from the origin to the farthest point in the node, or 0
+    // if it has none. Keeping this for every node means that removing the
+    // farthest point only has to look along the path to it.
+    farthest: f64,
+    contents: Contents,
 }
 
-impl QuadTreeSplitNode {
-    fn quadrants(&self) -> [&Box<QuadTreeNode>; 4] {
-        return [
-            &self.north_west,
-            &self.north_east,
-            &self.south_west,
-            &self.south_east,
-        ];
-    }
+#[derive(Clone, Copy)]
+enum Contents {
+    // The node's points are the first len entries of the block at start.
+    Points { start: usize, len: usize },
+    // The node's children are the four nodes from first_child.
+    Split { first_child: usize },
 }
 
 impl QuadTreeNode {
-    fn center(&self) -> Point {
-        return match self {
-            QuadTreeNode::Points(node) => node.center,
-            QuadTreeNode::Split(node) => node.center,
-        };
+    // Returns if the given point is within the bounds of this node.
+    fn point_is_in_bounds(&self, point: &Point) -> bool {
+        return point.x >= self.center.x - self.radius
+            && point.x < self.center.x + self.radius
+            && point.y >= self.center.y - self.radius
+            && point.y < self.center.y + self.radius;
+    }
+
+    // Returns which of this node's children the given point falls in.
+    fn quadrant(&self, point: &Point) -> usize {
+        let east = (point.x >= self.center.x) as usize;
+        let south = (point.y < self.center.y) as usize;
+        return east + south * 2;
     }
 
-    fn radius(&self) -> f64 {
-        return match self {
-            QuadTreeNode::Points(node) => node.radius,
-            QuadTreeNode::Split(node) => node.radius,
+    // Returns the center of the child in the given quadrant.
+    fn quadrant_center(&self, quadrant: usize) -> Point {
+        let child_radius = self.radius / 2.0;
+        return Point {
+            x: if quadrant == 0 || quadrant == 2 {
+                self.center.x - child_radius
+            } else {
+                self.center.x + child_radius
+            },
+            y: if quadrant < 2 {
+                self.center.y + child_radius
+            } else {
+                self.center.y - child_radius
+            },
         };
     }
 
-    // Returns if the given point is within the bounds of this node.
-    fn point_is_in_bounds(&self, point: &Point) -> bool {
-        let center = self.center();
-        let radius = self.radius();
-        return point.x >= center.x - radius
-            && point.x < center.x + radius
-            && point.y >= center.y - radius
-            && point.y < center.y + radius;
+    // Returns the distance from the given point to the bounds of this node.
+    // Returns 0 if the point is within the bounds of this node.
+    fn distance(&self, point: &Point) -> f64 {
+        return self.distance_2(point).sqrt();
     }
 
-    // Make a new and empty points node
-    fn empty_points_node(center: Point, radius: f64) -> QuadTreeNode {
-        return QuadTreeNode::Points(QuadTreePointsNode {
-            center,
-            radius,
-            points: Vec::new(),
-        });
+    // Returns the square of the distance to the bounds of this node.
+    fn distance_2(&self, point: &Point) -> f64 {
+        let dx = ((self.center.x - point.x).abs() - self.radius).max(0.0);
+        let dy = ((self.center.y - point.y).abs() - self.radius).max(0.0);
+        return dx * dx + dy * dy;
+    }
+}
+
+impl QuadTree {
+    // Make a tree holding the given points, indexed in the order given.
+    // This builds the whole tree in one pass, which is much faster than
+    // adding the points one at a time.
+    pub fn from_points(points: &[Point]) -> QuadTree {
+        let mut farthest_distance: f64 = 0.0;
+        for point in points {
+            if !point.x.is_finite() || !point.y.is_finite() {
+                panic!("Unable to add point {} that is not finite", point);
+            }
+            farthest_distance = farthest_distance.max(point.distance(&Point::ZERO));
+        }
+
+        let mut tree = QuadTree::new(Point::ZERO, farthest_distance.max(500.0) * 2.0);
+        let mut entries: Vec<(Point, usize)> = points.iter().copied().zip(0..).collect();
+        let mut other = entries.clone();
+        tree.build(ROOT, &mut entries, &mut other);
+        tree.num_points = points.len();
+        tree.next_index = points.len();
+
+        return tree;
     }
 
-    // Make a new and empty split node
-    fn empty_split_node(center: Point, radius: f64) -> QuadTreeNode {
-        let child_radius = radius / 2.0;
-        return QuadTreeNode::Split(QuadTreeSplitNode {
+    // Fill an empty points node with the given points and their indices,
+    // splitting it as many times as needed. The points are reordered into
+    // Z-order as they are distributed between quadrants, so the points of
+    // every node are contiguous and each node is only built once. The other
+    // buffer must be the same length as the points, and is used to hold them
+    // while distributing them, swapping roles at each level.
+    fn build(
+        &mut self,
+        node: usize,
+        entries: &mut [(Point, usize)],
+        other: &mut [(Point, usize)],
+    ) {
+        if entries.len() <= MAX_POINTS_NODE_SIZE {
+            if let Contents::Points { start, .. } = self.nodes[node].contents {
+                for (i, (point, index)) in entries.iter().enumerate() {
+                    self.points[start + i] = *point;
+                    self.indices[start + i] = *index;
+                }
+                self.nodes[node].contents = Contents::Points {
+                    start,
+                    len: entries.len(),
+                };
+                self.nodes[node].farthest = farthest(entries.iter().map(|(point, _)| point));
+            }
+            return;
+        }
+
+        self.split(node);
+        let parent = self.nodes[node];
+        let first_child = match parent.contents {
+            Contents::Split { first_child } => first_child,
+            Contents::Points { .. } => unreachable!(),
+        };
+
+        // Distribute the points between quadrants, keeping them in order
+        let mut ends = [0; 4];
+        for (point, _) in entries.iter() {
+            ends[parent.quadrant(point)] += 1;
+        }
+        for quadrant in 1..4 {
+            ends[quadrant] += ends[quadrant - 1];
+        }
+        let mut next = [0, ends[0], ends[1], ends[2]];
+        for entry in entries.iter() {
+            let quadrant = parent.quadrant(&entry.0);
+            other[next[quadrant]] = *entry;
+            next[quadrant] += 1;
+        }
+
+        let mut quadrant_start = 0;
+        for (quadrant, end) in ends.iter().enumerate() {
+            self.build(
+                first_child + quadrant,
+                &mut other[quadrant_start..*end],
+                &mut entries[quadrant_start..*end],
+            );
+            quadrant_start = *end;
+        }
+        self.update_farthest(node);
+    }
+
+    // Make an empty tree whose root covers the given bounds.
+    fn new(center: Point, radius: f64) -> QuadTree {
+        let mut tree = QuadTree {
+            nodes: Vec::new(),
+            free_nodes: Vec::new(),
+            points: Vec::new(),
+            indices: Vec::new(),
+            free_blocks: Vec::new(),
+            num_points: 0,
+            next_index: 0,
+        };
+        let start = tree.allocate_block();
+        tree.nodes.push(QuadTreeNode {
             center,
             radius,
-            north_west: Box::new(QuadTreeNode::empty_points_node(
-                Point {
-                    x: center.x - child_radius,
-                    y: center.y + child_radius,
-                },
-                child_radius,
-            )),
-            north_east: Box::new(QuadTreeNode::empty_points_node(
-                Point {
-                    x: center.x + child_radius,
-                    y: center.y + child_radius,
-                },
-                child_radius,
-            )),
-            south_west: Box::new(QuadTreeNode::empty_points_node(
-                Point {
-                    x: center.x - child_radius,
-                    y: center.y - child_radius,
-                },
-                child_radius,
-            )),
-            south_east: Box::new(QuadTreeNode::empty_points_node(
-                Point {
-                    x: center.x + child_radius,
-                    y: center.y - child_radius,
-                },
-                child_radius,
-            )),
+            farthest: 0.0,
+            contents: Contents::Points { start, len: 0 },
         });
+        return tree;
     }
 
-    fn is_empty(&self) -> bool {
-        return match self {
-            QuadTreeNode::Points(node) => node.points.len() == 0,
-            QuadTreeNode::Split(node) => {
-                node.north_east.is_empty()
-                    && node.north_west.is_empty()
-                    && node.south_east.is_empty()
-                    && node.south_west.is_empty()
+    // Append the tree to the given buffer, in the form read by decode.
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&(self.next_index as u64).to_be_bytes());
+        buf.extend_from_slice(&self.get_farthest_distance().to_be_bytes());
+        buf.extend_from_slice(&(self.free_nodes.len() as u64).to_be_bytes());
+        for first_child in &self.free_nodes {
+            buf.extend_from_slice(&(*first_child as u64).to_be_bytes());
+        }
+
+        buf.extend_from_slice(&(self.nodes.len() as u64).to_be_bytes());
+        for node in &self.nodes {
+            buf.extend_from_slice(&node.center.x.to_be_bytes());
+            buf.extend_from_slice(&node.center.y.to_be_bytes());
+            buf.extend_from_slice(&node.radius.to_be_bytes());
+            match node.contents {
+                Contents::Points { start, len } => {
+                    buf.push(0);
+                    buf.extend_from_slice(&(len as u64).to_be_bytes());
+                    for i in start..start + len {
+                        buf.extend_from_slice(&self.points[i].x.to_be_bytes());
+                        buf.extend_from_slice(&self.points[i].y.to_be_bytes());
+                        buf.extend_from_slice(&(self.indices[i] as u64).to_be_bytes());
+                    }
+                }
+                Contents::Split { first_child } => {
+                    buf.push(1);
+                    buf.extend_from_slice(&(first_child as u64).to_be_bytes());
+                }
+            };
+        }
+    }
+
+    // Read a tree written by encode.
+    pub fn decode(decoder: &mut Decoder) -> Result<QuadTree> {
+        let mut tree = QuadTree {
+            nodes: Vec::new(),
+            free_nodes: Vec::new(),
+            points: Vec::new(),
+            indices: Vec::new(),
+            free_blocks: Vec::new(),
+            num_points: 0,
+            next_index: decoder.u64()? as usize,
+        };
+        let farthest_distance = decoder.f64()?;
+
+        let num_free_nodes = decoder.u64()?;
+        for _i in 0..num_free_nodes {
+            tree.free_nodes.push(decoder.u64()? as usize);
+        }
+
+        // Every node takes at least 33 bytes
+        let num_nodes = decoder.u64()? as usize;
+        if num_nodes > decoder.remaining() / 33 {
+            return Result::Err(anyhow!("Invalid number of nodes {}", num_nodes));
+        }
+        let mut is_free = vec![false; num_nodes];
+        for first_child in &tree.free_nodes {
+            if *first_child + 4 > num_nodes {
+                return Result::Err(anyhow!("Invalid free node {}", first_child));
             }
+            is_free[*first_child..*first_child + 4].fill(true);
+        }
+
+        for is_free in is_free.iter() {
+            let center = Point {
+                x: decoder.f64()?,
+                y: decoder.f64()?,
+            };
+            let radius = decoder.f64()?;
+            let contents = match decoder.u8()? {
+                0 => {
+                    let len = decoder.u64()? as usize;
+                    if len > MAX_POINTS_NODE_SIZE || (*is_free && len > 0) {
+                        return Result::Err(anyhow!("Invalid number of points {}", len));
+                    }
+                    // Unused nodes don't own a block of points
+                    let start = if *is_free { 0 } else { tree.allocate_block() };
+                    for j in start..start + len {
+                        tree.points[j] = Point {
+                            x: decoder.f64()?,
+                            y: decoder.f64()?,
+                        };
+                        tree.indices[j] = decoder.u64()? as usize;
+                    }
+                    tree.num_points += len;
+                    Contents::Points { start, len }
+                }
+                1 => {
+                    let first_child = decoder.u64()? as usize;
+                    if first_child == ROOT || first_child + 4 > num_nodes {
+                        return Result::Err(anyhow!("Invalid child node {}", first_child));
+                    }
+                    Contents::Split { first_child }
+                }
+                tag => return Result::Err(anyhow!("Invalid node type {}", tag)),
+            };
+            tree.nodes.push(QuadTreeNode {
+                center,
+                radius,
+                farthest: 0.0,
+                contents,
+            });
+        }
+
+        if tree.nodes.is_empty() {
+            return Result::Err(anyhow!("Tree has no root"));
+        }
+        // Queries rely on every point being within its node, and every node
+        // being a quadrant of its parent. Nodes can come before or after
+        // their parents, as growing the root moves the old root after its
+        // children, so the tree is walked down from the root.
+        let mut is_visited = vec![false; tree.nodes.len()];
+        let mut visited = Vec::with_capacity(tree.nodes.len());
+        let mut stack = vec![ROOT];
+        while let Option::Some(node_index) = stack.pop() {
+            if is_free[node_index] || is_visited[node_index] {
+                return Result::Err(anyhow!("Node {} is not a quadrant", node_index));
+            }
+            is_visited[node_index] = true;
+            visited.push(node_index);
+            let node = &tree.nodes[node_index];
+            match node.contents {
+                Contents::Points { start, len } => {
+                    for point in &tree.points[start..start + len] {
+                        if !node.point_is_in_bounds(point) {
+                            return Result::Err(anyhow!("Point {} is outside its node", point));
+                        }
+                    }
+                }
+                Contents::Split { first_child } => {
+                    for quadrant in 0..4 {
+                        let child = first_child + quadrant;
+                        // Growing the root can leave centers off by rounding
+                        let center = node.quadrant_center(quadrant);
+                        let offset = tree.nodes[child].center.distance(&center);
+                        if offset.is_nan()
+                            || offset > node.radius * 1e-9
+                            || tree.nodes[child].radius != node.radius / 2.0
+                        {
+                            return Result::Err(anyhow!("Node {} is not a quadrant", child));
+                        }
+                        stack.push(child);
+                    }
+                }
+            };
+        }
+        // Every node in use has to be in the tree, or its points would be
+        // counted but never found
+        for (node, (node_is_free, node_is_visited)) in is_free.iter().zip(&is_visited).enumerate() {
+            if !node_is_free && !node_is_visited {
+                return Result::Err(anyhow!("Node {} is not in the tree", node));
+            }
+        }
+        // Each node is visited before its children
+        for node in visited.into_iter().rev() {
+            tree.update_farthest(node);
+        }
+        if tree.get_farthest_distance() != farthest_distance {
+            return Result::Err(anyhow!("Farthest distance {} is wrong", farthest_distance));
+        }
+        return Result::Ok(tree);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.num_points == 0;
+    }
+
+    // Returns the number of points in the tree.
+    pub fn len(&self) -> usize {
+        return self.num_points;
+    }
+
+    pub fn add_point(&mut self, point: &Point) {
+        self.insert_point(point, self.next_index);
+        self.next_index += 1;
+    }
+
+    // Removes a point at exactly the given position, returning the index it
+    // was added with, or None if there is no point there.
+    #[allow(dead_code)] // Not used by generation yet
+    pub fn remove_point(&mut self, point: &Point) -> Option<usize> {
+        if !self.nodes[ROOT].point_is_in_bounds(point) {
+            return Option::None;
+        }
+
+        // Find the points node holding the point, remembering the way down
+        let mut path = Vec::new();
+        let mut node = ROOT;
+        while let Contents::Split { first_child } = self.nodes[node].contents {
+            path.push(node);
+            node = first_child + self.nodes[node].quadrant(point);
+        }
+
+        let (start, len) = match self.nodes[node].contents {
+            Contents::Points { start, len } => (start, len),
+            Contents::Split { .. } => unreachable!(),
+        };
+        let position = self.points[start..start + len]
+            .iter()
+            .position(|other| other.x == point.x && other.y == point.y)?;
+        let index = self.indices[start + position];
+        self.points[start + position] = self.points[start + len - 1];
+        self.indices[start + position] = self.indices[start + len - 1];
+        self.nodes[node].contents = Contents::Points {
+            start,
+            len: len - 1,
         };
+        self.num_points -= 1;
+
+        // Only the nodes on the path to the point can have lost their
+        // farthest point
+        self.update_farthest(node);
+        for parent in path.iter().rev() {
+            self.update_farthest(*parent);
+        }
+
+        // Merge split nodes left with few enough points back into points
+        // nodes, from the bottom up
+        while let Option::Some(parent) = path.pop() {
+            if !self.merge(parent) {
+                break;
+            }
+        }
+        return Option::Some(index);
+    }
+
+    // Moves a point at exactly the given position to a new position, keeping
+    // its index. Returns the index, or None if there is no point to move.
+    #[allow(dead_code)] // Not used by generation yet
+    pub fn move_point(&mut self, from: &Point, to: &Point) -> Option<usize> {
+        let index = self.remove_point(from)?;
+        self.insert_point(to, index);
+        return Option::Some(index);
     }
 
-    fn add_point(&mut self, new_point: &Point) {
-        if !self.point_is_in_bounds(new_point) {
+    fn insert_point(&mut self, point: &Point, index: usize) {
+        if !point.x.is_finite() || !point.y.is_finite() {
+            panic!("Unable to add point {} that is not finite", point);
+        }
+
+        // Grow the tree until the root is large enough to hold the point
+        while !self.nodes[ROOT].point_is_in_bounds(point) {
+            self.grow_root(point);
+        }
+
+        let distance = point.distance(&Point::ZERO);
+        let mut node = ROOT;
+        loop {
+            self.nodes[node].farthest = self.nodes[node].farthest.max(distance);
+            match self.nodes[node].contents {
+                Contents::Split { first_child } => {
+                    node = first_child + self.nodes[node].quadrant(point);
+                }
+                Contents::Points { start, len } => {
+                    if len < MAX_POINTS_NODE_SIZE {
+                        self.points[start + len] = *point;
+                        self.indices[start + len] = index;
+                        self.nodes[node].contents = Contents::Points {
+                            start,
+                            len: len + 1,
+                        };
+                        break;
+                    }
+                    // Split the full node, then carry on down into it
+                    self.split(node);
+                }
+            };
+        }
+
+        self.num_points += 1;
+    }
+
+    // Replace the root with a split node twice its size, with the old root
+    // as the quadrant that extends away from the given point.
+    fn grow_root(&mut self, towards: &Point) {
+        let root = self.nodes[ROOT];
+        let radius = root.radius;
+        let center = Point {
+            x: if towards.x < root.center.x { root.center.x - radius } else { root.center.x + radius },
+            y: if towards.y < root.center.y { root.center.y - radius } else { root.center.y + radius },
+        };
+
+        let mut new_root = QuadTreeNode {
+            center,
+            radius: radius * 2.0,
+            farthest: root.farthest,
+            contents: Contents::Split { first_child: 0 },
+        };
+        let first_child = self.allocate_children(&new_root);
+        let old_root = first_child + new_root.quadrant(&root.center);
+        if let Contents::Points { start, .. } = self.nodes[old_root].contents {
+            self.free_blocks.push(start);
+        }
+        self.nodes[old_root] = root;
+        new_root.contents = Contents::Split { first_child };
+        self.nodes[ROOT] = new_root;
+    }
+
+    // Turn a full points node into a split node, moving its points into its
+    // new children.
+    fn split(&mut self, node: usize) {
+        let (start, len) = match self.nodes[node].contents {
+            Contents::Points { start, len } => (start, len),
+            Contents::Split { .. } => return,
+        };
+        // Points that all share a position can never be split apart
+        let parent = self.nodes[node];
+        if parent.radius / 2.0 <= f64::EPSILON * parent.center.x.abs().max(parent.center.y.abs()) {
             panic!(
-                "Unable to add point {} that is outside of node boundaries {} / {}",
-                new_point,
-                self.center(),
-                self.radius()
+                "Unable to add more than {} points at {}",
+                MAX_POINTS_NODE_SIZE, parent.center
             );
         }
 
-        match self {
-            QuadTreeNode::Points(node) => {
-                // Ignore the size of the points array here.
-                // Due to the checks in the other branch this should never get too large.
-                node.points.push(*new_point);
-            }
-            QuadTreeNode::Split(node) => {
-                let quadrant: &mut Box<QuadTreeNode> =
-                    if new_point.x < node.center.x && new_point.y >= node.center.y {
-                        &mut node.north_west
-                    } else if new_point.x >= node.center.x && new_point.y >= node.center.y {
-                        &mut node.north_east
-                    } else if new_point.x < node.center.x && new_point.y < node.center.y {
-                        &mut node.south_west
-                    } else {
-                        &mut node.south_east
-                    };
-                match &**quadrant {
-                    QuadTreeNode::Points(quadrant_node) => {
-                        if quadrant_node.points.len() >= MAX_POINTS_NODE_SIZE {
-                            let mut new_quadrant = QuadTreeNode::empty_split_node(
-                                quadrant_node.center,
-                                quadrant_node.radius,
-                            );
-                            for point in &quadrant_node.points {
-                                new_quadrant.add_point(point);
-                            }
-                            new_quadrant.add_point(new_point);
-                            **quadrant = new_quadrant;
-                        } else {
-                            quadrant.add_point(new_point);
-                        }
-                    }
-                    QuadTreeNode::Split(_) => {
-                        quadrant.add_point(new_point);
-                    }
+        let first_child = self.allocate_children(&parent);
+        for i in start..start + len {
+            let child = first_child + parent.quadrant(&self.points[i]);
+            if let Contents::Points {
+                start: child_start,
+                len: child_len,
+            } = self.nodes[child].contents
+            {
+                self.points[child_start + child_len] = self.points[i];
+                self.indices[child_start + child_len] = self.indices[i];
+                self.nodes[child].contents = Contents::Points {
+                    start: child_start,
+                    len: child_len + 1,
                 };
             }
         }
+
+        for child in first_child..first_child + 4 {
+            self.update_farthest(child);
+        }
+        self.free_blocks.push(start);
+        self.nodes[node].contents = Contents::Split { first_child };
     }
 
-    fn get_nearest(&self, point: &Point) -> Option<(Point, f64)> {
-        match self {
-            QuadTreeNode::Points(node) => {
-                if node.points.len() == 0 {
-                    return Option::None;
+    // Turn a split node whose children are all points nodes back into a
+    // points node, if they hold few enough points between them. Returns if
+    // the node was merged.
+    fn merge(&mut self, node: usize) -> bool {
+        let first_child = match self.nodes[node].contents {
+            Contents::Split { first_child } => first_child,
+            Contents::Points { .. } => return false,
+        };
+
+        let mut total = 0;
+        for child in first_child..first_child + 4 {
+            match self.nodes[child].contents {
+                Contents::Points { len, .. } => total += len,
+                Contents::Split { .. } => return false,
+            };
+        }
+        if total > MAX_POINTS_NODE_SIZE {
+            return false;
+        }
+
+        let start = self.allocate_block();
+        let mut len = 0;
+        for child in first_child..first_child + 4 {
+            if let Contents::Points {
+                start: child_start,
+                len: child_len,
+            } = self.nodes[child].contents
+            {
+                self.points.copy_within(child_start..child_start + child_len, start + len);
+                self.indices.copy_within(child_start..child_start + child_len, start + len);
+                len += child_len;
+                self.free_blocks.push(child_start);
+                self.nodes[child].contents = Contents::Points { start: 0, len: 0 };
+            }
+        }
+
+        self.free_nodes.push(first_child);
+        self.nodes[node].contents = Contents::Points { start, len };
+        return true;
+    }
+
+    // Add four empty points nodes as the children of the given node,
+    // returning the index of the first.
+    fn allocate_children(&mut self, parent: &QuadTreeNode) -> usize {
+        let first_child = match self.free_nodes.pop() {
+            Option::Some(first_child) => first_child,
+            Option::None => {
+                let first_child = self.nodes.len();
+                for _i in 0..4 {
+                    self.nodes.push(QuadTreeNode {
+                        center: Point::ZERO,
+                        radius: 0.0,
+                        farthest: 0.0,
+                        contents: Contents::Points { start: 0, len: 0 },
+                    });
                 }
-                let mut nearest = node.points[0];
-                let mut nearest_distance_2 = point.distance_2(&nearest);
-                for other_point in &node.points[1..] {
+                first_child
+            }
+        };
+
+        for quadrant in 0..4 {
+            let start = self.allocate_block();
+            self.nodes[first_child + quadrant] = QuadTreeNode {
+                center: parent.quadrant_center(quadrant),
+                radius: parent.radius / 2.0,
+                farthest: 0.0,
+                contents: Contents::Points { start, len: 0 },
+            };
+        }
+        return first_child;
+    }
+
+    // Work out the farthest distance of the given node again, from its
+    // points or from the farthest distances of its children.
+    fn update_farthest(&mut self, node: usize) {
+        self.nodes[node].farthest = match self.nodes[node].contents {
+            Contents::Points { start, len } => farthest(self.points[start..start + len].iter()),
+            Contents::Split { first_child } => self.nodes[first_child..first_child + 4]
+                .iter()
+                .map(|child| child.farthest)
+                .fold(0.0, f64::max),
+        };
+    }
+
+    // Returns the start of an unused block of points.
+    fn allocate_block(&mut self) -> usize {
+        if let Option::Some(start) = self.free_blocks.pop() {
+            return start;
+        }
+        let start = self.points.len();
+        self.points.resize(start + MAX_POINTS_NODE_SIZE, Point::ZERO);
+        self.indices.resize(start + MAX_POINTS_NODE_SIZE, 0);
+        return start;
+    }
+
+    // Returns the children of a split node ordered nearest first to the
+    // given point, each with the square of its distance.
+    fn children_by_distance(&self, first_child: usize, point: &Point) -> [(usize, f64); 4] {
+        let mut children = [(0, 0.0); 4];
+        for (i, child) in children.iter_mut().enumerate() {
+            *child = (
+                first_child + i,
+                self.nodes[first_child + i].distance_2(point),
+            );
+        }
+        // A sorting network is much cheaper than a general sort for four items
+        for (a, b) in [(0, 1), (2, 3), (0, 2), (1, 3), (1, 2)] {
+            if children[b].1 < children[a].1 {
+                children.swap(a, b);
+            }
+        }
+        return children;
+    }
+
+    // Returns the nearest point, its distance, and its index in insertion order.
+    pub fn get_nearest(&self, point: &Point) -> Option<(Point, f64, usize)> {
+        let mut nearest = Option::None;
+        let mut nearest_distance_2 = f64::INFINITY;
+        self.get_nearest_in(ROOT, point, &mut nearest, &mut nearest_distance_2);
+        return nearest.map(|i| (self.points[i], nearest_distance_2.sqrt(), self.indices[i]));
+    }
+
+    // Updates the given position in the point buffers and squared distance
+    // if any point in the given node is nearer.
+    fn get_nearest_in(
+        &self,
+        node: usize,
+        point: &Point,
+        nearest: &mut Option<usize>,
+        nearest_distance_2: &mut f64,
+    ) {
+        match self.nodes[node].contents {
+            Contents::Points { start, len } => {
+                for (i, other_point) in self.points[start..start + len].iter().enumerate() {
                     let d2 = point.distance_2(other_point);
-                    if d2 < nearest_distance_2 {
-                        nearest = *other_point;
-                        nearest_distance_2 = d2;
+                    if d2 < *nearest_distance_2 {
+                        *nearest = Option::Some(start + i);
+                        *nearest_distance_2 = d2;
                     }
                 }
-                return Option::Some((nearest, nearest_distance_2.sqrt()));
             }
-            QuadTreeNode::Split(node) => {
-                let mut result = Option::None;
-                for quadrant in node.quadrants() {
-                    if quadrant.point_is_in_bounds(point) {
-                        result = quadrant.get_nearest(point);
+            Contents::Split { first_child } => {
+                for (child, d2) in self.children_by_distance(first_child, point) {
+                    if d2 < *nearest_distance_2 {
+                        self.get_nearest_in(child, point, nearest, nearest_distance_2);
                     }
                 }
+            }
+        };
+    }
 
-                for quadrant in node.quadrants() {
-                    if !quadrant.point_is_in_bounds(point) {
-                        let d = quadrant.distance(point);
-                        if result.is_none() || d < result.unwrap().1 {
-                            let r = quadrant.get_nearest(point);
-                            if !r.is_none() && (result.is_none() || r.unwrap().1 < result.unwrap().1) {
-                                result = r;
-                            }
-                        }
+    // Returns a lower bound on the distance from the given point to the
+    // nearest point in the tree, or infinity if the tree is empty.
+    pub fn get_distance_lower_bound(&self, point: &Point) -> f64 {
+        if self.num_points == 0 {
+            return f64::INFINITY;
+        }
+        let bound = point.distance(&Point::ZERO) - self.get_farthest_distance();
+        return bound.max(self.get_distance_lower_bound_in(ROOT, point, f64::INFINITY));
+    }
+
+    // Returns a lower bound on the distance from the given point to the
+    // nearest point in the given node, mostly using only the bounds of nodes.
+    // This is much cheaper than get_nearest as it stops descending once a
+    // node is far away compared to its size. Points nodes the point is inside
+    // or near are searched exactly, as in a sparse tree they can be large and
+    // their bounds would say little. Any bound of at least the given limit is
+    // returned as the limit.
+    fn get_distance_lower_bound_in(&self, node: usize, point: &Point, limit: f64) -> f64 {
+        let distance = self.nodes[node].distance(point);
+        match self.nodes[node].contents {
+            Contents::Points { start, len } => {
+                if distance >= limit {
+                    return limit;
+                }
+                let nearest_2 = self.points[start..start + len]
+                    .iter()
+                    .map(|other_point| point.distance_2(other_point))
+                    .fold(limit * limit, f64::min);
+                return nearest_2.sqrt();
+            }
+            Contents::Split { first_child } => {
+                // Split nodes almost always contain points, and assuming
+                // they do can only make the bound smaller
+                if distance >= self.nodes[node].radius {
+                    return distance.min(limit);
+                }
+                let mut bound = limit;
+                for (child, d2) in self.children_by_distance(first_child, point) {
+                    if d2 < bound * bound {
+                        bound = self.get_distance_lower_bound_in(child, point, bound);
                     }
                 }
-
-                return result;
+                return bound;
             }
         };
     }
 
-    // Returns the distance from the given point to the bounds of this node.
-    // Returns 0 if the point is within the bounds of this node.
-    fn distance(&self, point: &Point) -> f64 {
-        let center = self.center();
-        let radius = self.radius();
-        let dx = (center.x - point.x).abs();
-        let dy = (center.y - point.y).abs();
-        if dx <= radius && dy <= radius {
-            return 0.0;
-        }
-        if dx <= radius {
-            return dy - radius;
-        }
-        if dy <= radius {
-            return dx - radius;
+    // Returns up to k of the points nearest to the given point, each with its
+    // distance and its index in insertion order, sorted nearest first.
+    #[allow(dead_code)] // Not used by generation yet
+    pub fn get_k_nearest(&self, point: &Point, k: usize) -> Vec<(Point, f64, usize)> {
+        let mut result = Vec::with_capacity(k + 1);
+        if k > 0 {
+            self.get_k_nearest_in(ROOT, point, k, &mut result);
         }
-        return ((dx - radius) * (dx - radius) + (dy - radius) * (dy - radius)).sqrt();
+        return result;
     }
-}
 
-impl QuadTree {
-    pub fn from_flake(flake: &Flake) -> Result<QuadTree> {
-        let points = flake.get_points().context("Unable to get flake points")?;
+    // Adds to the given list any points in the given node that are among the
+    // k nearest to the given point. The list is kept sorted by distance and
+    // holds at most k entries.
+    fn get_k_nearest_in(
+        &self,
+        node: usize,
+        point: &Point,
+        k: usize,
+        result: &mut Vec<(Point, f64, usize)>,
+    ) {
+        match self.nodes[node].contents {
+            Contents::Points { start, len } => {
+                for i in start..start + len {
+                    let d = point.distance(&self.points[i]);
+                    if result.len() < k || d < result[result.len() - 1].1 {
+                        let position = result.iter().position(|r| r.1 > d).unwrap_or(result.len());
+                        result.insert(position, (self.points[i], d, self.indices[i]));
+                        result.truncate(k);
+                    }
+                }
+            }
+            Contents::Split { first_child } => {
+                for (child, d2) in self.children_by_distance(first_child, point) {
+                    if result.len() < k || d2.sqrt() < result[result.len() - 1].1 {
+                        self.get_k_nearest_in(child, point, k, result);
+                    }
+                }
+            }
+        };
+    }
 
-        let mut farthest_distance: f64 = 500.0;
-        for point in &points {
-            farthest_distance = farthest_distance.max(point.distance(&Point::ZERO));
-        }
+    // Returns all points within the given distance of the given point, each
+    // with its distance and its index in insertion order, in no particular order.
+    #[allow(dead_code)] // Not used by generation yet
+    pub fn points_within(&self, point: &Point, distance: f64) -> Vec<(Point, f64, usize)> {
+        let mut result = Vec::new();
+        self.points_within_in(ROOT, point, distance, &mut result);
+        return result;
+    }
 
-        let mut tree = QuadTree {
-            root: QuadTreeNode::empty_split_node(Point::ZERO, farthest_distance * 2.0),
-            farthest_distance: 0.0,
+    // Adds to the given list all points in the given node that are within
+    // the given distance of the given point.
+    fn points_within_in(
+        &self,
+        node: usize,
+        point: &Point,
+        distance: f64,
+        result: &mut Vec<(Point, f64, usize)>,
+    ) {
+        if self.nodes[node].distance(point) > distance {
+            return;
+        }
+        match self.nodes[node].contents {
+            Contents::Points { start, len } => {
+                for i in start..start + len {
+                    let d = point.distance(&self.points[i]);
+                    if d <= distance {
+                        result.push((self.points[i], d, self.indices[i]));
+                    }
+                }
+            }
+            Contents::Split { first_child } => {
+                for child in first_child..first_child + 4 {
+                    self.points_within_in(child, point, distance, result);
+                }
+            }
         };
+    }
 
-        for point in &points {
-            tree.add_point(point);
+    // Returns all points within the rectangle with the given corners,
+    // including its edges, each with its index in insertion order.
+    pub fn points_in_rect(&self, min: &Point, max: &Point) -> Vec<(Point, usize)> {
+        let mut result = Vec::new();
+        self.points_in_rect_in(ROOT, min, max, &mut result);
+        return result;
+    }
+
+    // Adds to the given list all points in the given node that lie within
+    // the rectangle with the given corners, including its edges.
+    fn points_in_rect_in(
+        &self,
+        node: usize,
+        min: &Point,
+        max: &Point,
+        result: &mut Vec<(Point, usize)>,
+    ) {
+        let center = self.nodes[node].center;
+        let radius = self.nodes[node].radius;
+        if center.x + radius < min.x
+            || center.x - radius > max.x
+            || center.y + radius < min.y
+            || center.y - radius > max.y
+        {
+            return;
         }
+        match self.nodes[node].contents {
+            Contents::Points { start, len } => {
+                for i in start..start + len {
+                    let point = self.points[i];
+                    if point.x >= min.x && point.x <= max.x && point.y >= min.y && point.y <= max.y
+                    {
+                        result.push((point, self.indices[i]));
+                    }
+                }
+            }
+            Contents::Split { first_child } => {
+                for child in first_child..first_child + 4 {
+                    self.points_in_rect_in(child, min, max, result);
+                }
+            }
+        };
+    }
 
-        return Result::Ok(tree);
+    // Returns an iterator over all points in the tree, each with its index in
+    // insertion order. Points are visited in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = (Point, usize)> + '_ {
+        return self.nodes.iter().flat_map(move |node| {
+            let (start, len) = match node.contents {
+                Contents::Points { start, len } => (start, len),
+                Contents::Split { .. } => (0, 0),
+            };
+            return self.points[start..start + len]
+                .iter()
+                .copied()
+                .zip(self.indices[start..start + len].iter().copied());
+        });
     }
 
-    pub fn is_empty(&self) -> bool {
-        return self.root.is_empty();
+    pub fn get_farthest_distance(&self) -> f64 {
+        return self.nodes[ROOT].farthest;
     }
+}
 
-    pub fn add_point(&mut self, point: &Point) {
-        if !self.root.point_is_in_bounds(point) {
-            panic!("Unable to add point {} that is outside of root node", point);
-        }
+impl SpatialIndex for QuadTree {
+    fn add_point(&mut self, point: &Point) {
+        QuadTree::add_point(self, point);
+    }
 
-        self.root.add_point(point);
-        self.farthest_distance = self.farthest_distance.max(point.distance(&Point::ZERO));
+    fn get_nearest(&self, point: &Point) -> Option<(Point, f64, usize)> {
+        return QuadTree::get_nearest(self, point);
     }
 
-    pub fn get_nearest(&self, point: &Point) -> Option<(Point, f64)> {
-        return self.root.get_nearest(point);
+    fn get_distance_lower_bound(&self, point: &Point) -> f64 {
+        return QuadTree::get_distance_lower_bound(self, point);
     }
 
-    pub fn get_farthest_distance(&self) -> f64 {
-        return self.farthest_distance;
+    fn get_farthest_distance(&self) -> f64 {
+        return QuadTree::get_farthest_distance(self);
     }
+
+    fn len(&self) -> usize {
+        return QuadTree::len(self);
+    }
+
+    fn is_empty(&self) -> bool {
+        return QuadTree::is_empty(self);
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (Point, usize)> + '_> {
+        return Box::new(QuadTree::iter(self));
+    }
+
+    fn as_quad_tree(&self) -> Option<&QuadTree> {
+        return Option::Some(self);
+    }
+
+    fn clone_box(&self) -> Box<dyn SpatialIndex> {
+        return Box::new(self.clone());
+    }
+}
+
+// Returns the distance from the origin to the farthest of the given points,
+// or 0 if there are none.
+fn farthest<'a, I: Iterator<Item = &'a Point>>(points: I) -> f64 {
+    return points.map(|point| point.distance(&Point::ZERO)).fold(0.0, f64::max);
 }
 
 #[cfg(test)]
 mod tests {
     use rand::rngs::StdRng;
+    use anyhow::{Context, Result};
     use rand::{Rng, SeedableRng};
 
-    use super::QuadTree;
-    use super::QuadTreeNode;
+    use super::{Contents, QuadTree, QuadTreeNode, ROOT};
+    use crate::distance_field::DistanceField;
     use crate::flake::Flake;
+    use crate::index::Decoder;
     use crate::point::Point;
     use crate::test_utils::test::{time_func, with_test_dir};
 
@@ -297,56 +945,46 @@ mod tests {
         assert!(expected + 0.01 > actual);
     }
 
-    impl QuadTreeNode {
-        // Returns all points in the node.
-        fn points(&self) -> Vec<Point> {
-            match self {
-                QuadTreeNode::Points(node) => {
-                    return node.points.clone();
-                }
-                QuadTreeNode::Split(node) => {
-                    let mut points = node.north_west.points();
-                    points.append(&mut node.north_east.points());
-                    points.append(&mut node.south_west.points());
-                    points.append(&mut node.south_east.points());
-                    return points;
-                }
-            };
+    impl QuadTree {
+        fn from_flake(flake: &Flake) -> Result<QuadTree> {
+            let points = flake.get_points().context("Unable to get flake points")?;
+            return Result::Ok(QuadTree::from_points(&points));
         }
 
-        fn print(&self, indent: usize) {
-            match self {
-                QuadTreeNode::Points(node) => {
-                    println!(
-                        "{}Points({}, {}) (",
-                        " ".repeat(indent),
-                        node.center,
-                        node.radius
-                    );
-                    for point in &node.points {
+        // Make an empty tree whose root is a split node with the given bounds.
+        fn with_split_root(center: Point, radius: f64) -> QuadTree {
+            let mut tree = QuadTree::new(center, radius);
+            tree.split(ROOT);
+            return tree;
+        }
+
+        // Returns all points in the tree.
+        fn all_points(&self) -> Vec<Point> {
+            return self.iter().map(|(point, _)| point).collect();
+        }
+
+        fn print(&self, node: usize, indent: usize) {
+            let QuadTreeNode {
+                center,
+                radius,
+                contents,
+                ..
+            } = self.nodes[node];
+            match contents {
+                Contents::Points { start, len } => {
+                    println!("{}Points({}, {}) (", " ".repeat(indent), center, radius);
+                    for point in &self.points[start..start + len] {
                         println!("{}{}", " ".repeat(indent + 2), point);
                     }
                     println!("{})", " ".repeat(indent));
                 }
-                QuadTreeNode::Split(node) => {
-                    println!(
-                        "{}Split({}, {}) (",
-                        " ".repeat(indent),
-                        node.center,
-                        node.radius
-                    );
-                    println!("{}NW (", " ".repeat(indent + 2));
-                    node.north_west.print(indent + 4);
-                    println!("{})", " ".repeat(indent + 2));
-                    println!("{}NE (", " ".repeat(indent + 2));
-                    node.north_east.print(indent + 4);
-                    println!("{})", " ".repeat(indent + 2));
-                    println!("{}SW (", " ".repeat(indent + 2));
-                    node.south_west.print(indent + 4);
-                    println!("{})", " ".repeat(indent + 2));
-                    println!("{}SE (", " ".repeat(indent + 2));
-                    node.south_east.print(indent + 4);
-                    println!("{})", " ".repeat(indent + 2));
+                Contents::Split { first_child } => {
+                    println!("{}Split({}, {}) (", " ".repeat(indent), center, radius);
+                    for (i, name) in ["NW", "NE", "SW", "SE"].iter().enumerate() {
+                        println!("{}{} (", " ".repeat(indent + 2), name);
+                        self.print(first_child + i, indent + 4);
+                        println!("{})", " ".repeat(indent + 2));
+                    }
                     println!("{})", " ".repeat(indent));
                 }
             };
@@ -355,17 +993,17 @@ mod tests {
 
     #[test]
     fn node_points_print() {
-        let mut node = QuadTreeNode::empty_points_node(Point::ZERO, 10.0);
+        let mut node = QuadTree::new(Point::ZERO, 10.0);
         node.add_point(&Point { x: -1.0, y: -1.0 });
         node.add_point(&Point { x: 1.0, y: -1.0 });
         node.add_point(&Point { x: 2.0, y: -2.0 });
 
-        node.print(0);
+        node.print(ROOT, 0);
     }
 
     #[test]
     fn node_split_print() {
-        let mut node = QuadTreeNode::empty_split_node(Point::ZERO, 10.0);
+        let mut node = QuadTree::with_split_root(Point::ZERO, 10.0);
         node.add_point(&Point { x: -1.0, y: -1.0 });
         node.add_point(&Point { x: 1.0, y: -1.0 });
         node.add_point(&Point { x: 2.0, y: -2.0 });
@@ -377,12 +1015,12 @@ mod tests {
         node.add_point(&Point { x: -3.0, y: 3.0 });
         node.add_point(&Point { x: -4.0, y: 4.0 });
 
-        node.print(0);
+        node.print(ROOT, 0);
     }
 
     #[test]
     fn node_points_is_empty() {
-        let mut node = QuadTreeNode::empty_points_node(Point::ZERO, 10.0);
+        let mut node = QuadTree::new(Point::ZERO, 10.0);
         assert_eq!(true, node.is_empty());
 
         node.add_point(&Point::ZERO);
@@ -391,7 +1029,7 @@ mod tests {
 
     #[test]
     fn node_split_is_empty() {
-        let mut node = QuadTreeNode::empty_split_node(Point::ZERO, 10.0);
+        let mut node = QuadTree::with_split_root(Point::ZERO, 10.0);
         assert_eq!(true, node.is_empty());
 
         node.add_point(&Point::ZERO);
@@ -400,7 +1038,7 @@ mod tests {
 
     #[test]
     fn node_point_is_in_bounds() {
-        let node = QuadTreeNode::empty_points_node(Point::ZERO, 10.0);
+        let node = QuadTree::new(Point::ZERO, 10.0).nodes[ROOT];
 
         assert_eq!(true, node.point_is_in_bounds(&Point::ZERO));
         assert_eq!(true, node.point_is_in_bounds(&Point { x: 5.0, y: 5.0 }));
@@ -417,7 +1055,7 @@ mod tests {
 
     #[test]
     fn node_distance() {
-        let node = QuadTreeNode::empty_points_node(Point::ZERO, 10.0);
+        let node = QuadTree::new(Point::ZERO, 10.0).nodes[ROOT];
 
         assert_eq!(0.0, node.distance(&Point::ZERO));
         assert_eq!(0.0, node.distance(&Point { x: 10.0, y: 0.0 }));
@@ -431,8 +1069,8 @@ mod tests {
 
     #[test]
     fn node_points_points() {
-        let mut node = QuadTreeNode::empty_points_node(Point::ZERO, 10.0);
-        assert_eq!(true, node.points().is_empty());
+        let mut node = QuadTree::new(Point::ZERO, 10.0);
+        assert_eq!(true, node.all_points().is_empty());
 
         for i in 0..8 {
             node.add_point(&Point {
@@ -440,13 +1078,13 @@ mod tests {
                 y: i as f64,
             });
         }
-        let points = node.points();
+        let points = node.all_points();
         assert_eq!(8, points.len());
     }
 
     #[test]
     fn node_split_points() {
-        let mut node = QuadTreeNode::empty_split_node(Point::ZERO, 10.0);
+        let mut node = QuadTree::with_split_root(Point::ZERO, 10.0);
         node.add_point(&Point { x: -1.0, y: -1.0 });
         node.add_point(&Point { x: 1.0, y: -1.0 });
         node.add_point(&Point { x: 2.0, y: -2.0 });
@@ -457,13 +1095,13 @@ mod tests {
         node.add_point(&Point { x: -2.0, y: 2.0 });
         node.add_point(&Point { x: -3.0, y: 3.0 });
         node.add_point(&Point { x: -4.0, y: 4.0 });
-        let points = node.points();
+        let points = node.all_points();
         assert_eq!(10, points.len());
     }
 
     #[test]
     fn node_points_get_nearest() {
-        let mut node = QuadTreeNode::empty_points_node(Point::ZERO, 10.0);
+        let mut node = QuadTree::new(Point::ZERO, 10.0);
         assert_eq!(true, node.get_nearest(&Point::ZERO).is_none());
 
         node.add_point(&Point::ZERO);
@@ -480,7 +1118,7 @@ mod tests {
 
     #[test]
     fn node_split_get_nearest_one_zone() {
-        let mut node = QuadTreeNode::empty_split_node(Point::ZERO, 10.0);
+        let mut node = QuadTree::with_split_root(Point::ZERO, 10.0);
         node.add_point(&Point { x: 1.0, y: 0.0 });
 
         assert_eq!(1.0, node.get_nearest(&Point { x: 1.0, y: 1.0 }).unwrap().1);
@@ -490,7 +1128,7 @@ mod tests {
 
     #[test]
     fn node_split_get_nearest_two_zones() {
-        let mut node = QuadTreeNode::empty_split_node(Point::ZERO, 10.0);
+        let mut node = QuadTree::with_split_root(Point::ZERO, 10.0);
         node.add_point(&Point { x: 4.0, y: 0.0 });
         node.add_point(&Point { x: -8.0, y: 0.0 });
 
@@ -504,7 +1142,7 @@ mod tests {
 
     #[test]
     fn node_split_get_nearest_all_zones() {
-        let mut node = QuadTreeNode::empty_split_node(Point::ZERO, 10.0);
+        let mut node = QuadTree::with_split_root(Point::ZERO, 10.0);
         node.add_point(&Point { x: 5.0, y: 5.0 });
         node.add_point(&Point { x: 5.0, y: -5.0 });
         node.add_point(&Point { x: -5.0, y: -5.0 });
@@ -548,19 +1186,22 @@ mod tests {
     #[test]
     fn flake_is_empty() {
         with_test_dir(|test_dir: &str| {
-            let flake = Flake::new(&format!("{}/test.flake", test_dir));
+            let flake =
+                Flake::new(&format!("{}/test.flake", test_dir)).expect("Unable to open flake");
             let mut tree = QuadTree::from_flake(&flake).expect("Unable to make flake");
             assert_eq!(true, tree.is_empty());
 
             tree.add_point(&Point::ZERO);
             assert_eq!(false, tree.is_empty());
+            assert_eq!(1, tree.len());
         });
     }
 
     #[test]
     fn flake_get_nearest() {
         with_test_dir(|test_dir: &str| {
-            let flake = Flake::new(&format!("{}/test.flake", test_dir));
+            let flake =
+                Flake::new(&format!("{}/test.flake", test_dir)).expect("Unable to open flake");
             let mut tree = QuadTree::from_flake(&flake).expect("Unable to make flake");
 
             tree.add_point(&Point::ZERO);
@@ -580,10 +1221,77 @@ mod tests {
         });
     }
 
+    #[test]
+    fn node_get_distance_lower_bound() {
+        let mut node = QuadTree::with_split_root(Point::ZERO, 16.0);
+        assert_eq!(
+            f64::INFINITY,
+            node.get_distance_lower_bound_in(ROOT, &Point::ZERO, f64::INFINITY)
+        );
+
+        node.add_point(&Point { x: 4.0, y: 4.0 });
+
+        // Points nodes that can't be ruled out are searched exactly, but
+        // ones beyond the limit are not
+        assert_eq!(18.0_f64.sqrt(), node.get_distance_lower_bound_in(ROOT, &Point { x: 1.0, y: 1.0 }, f64::INFINITY));
+        assert_eq!(58.0_f64.sqrt(), node.get_distance_lower_bound_in(ROOT, &Point { x: -3.0, y: 1.0 }, f64::INFINITY));
+        assert_eq!(2.0, node.get_distance_lower_bound_in(ROOT, &Point { x: -3.0, y: 1.0 }, 2.0));
+        assert_eq!(3.0, node.get_distance_lower_bound_in(ROOT, &Point { x: 1.0, y: 1.0 }, 3.0));
+    }
+
+    #[test]
+    fn flake_get_distance_lower_bound() {
+        with_test_dir(|test_dir: &str| {
+            let flake =
+                Flake::new(&format!("{}/test.flake", test_dir)).expect("Unable to open flake");
+            let mut tree = QuadTree::from_flake(&flake).expect("Unable to make flake");
+
+            let mut rng = StdRng::seed_from_u64(5);
+            for _i in 0..2000 {
+                tree.add_point(&Point {
+                    x: rng.gen_range(-100.0..100.0),
+                    y: rng.gen_range(-20.0..20.0),
+                });
+            }
+
+            for _i in 0..2000 {
+                let query_point = Point {
+                    x: rng.gen_range(-400.0..400.0),
+                    y: rng.gen_range(-400.0..400.0),
+                };
+                let bound = tree.get_distance_lower_bound(&query_point);
+                assert!(bound <= tree.get_nearest(&query_point).unwrap().1);
+            }
+
+            assert!(tree.get_distance_lower_bound(&Point { x: 0.0, y: 300.0 }) > 150.0);
+        });
+    }
+
+    #[test]
+    fn flake_get_nearest_index() {
+        with_test_dir(|test_dir: &str| {
+            let flake =
+                Flake::new(&format!("{}/test.flake", test_dir)).expect("Unable to open flake");
+            let mut tree = QuadTree::from_flake(&flake).expect("Unable to make flake");
+
+            for i in 0..200 {
+                tree.add_point(&Point {
+                    x: i as f64,
+                    y: 0.0,
+                });
+            }
+
+            assert_eq!(0, tree.get_nearest(&Point { x: -5.0, y: 0.0 }).unwrap().2);
+            assert_eq!(37, tree.get_nearest(&Point { x: 37.2, y: 1.0 }).unwrap().2);
+            assert_eq!(199, tree.get_nearest(&Point { x: 500.0, y: 0.0 }).unwrap().2);
+        });
+    }
+
     #[test]
     fn flake_get_nearest_grid() {
         with_test_dir(|test_dir: &str| {
-            let flake = Flake::new(&format!("{}/test.flake", test_dir));
+            let flake =
+                Flake::new(&format!("{}/test.flake", test_dir)).expect("Unable to open flake");
             let mut tree = QuadTree::from_flake(&flake).expect("Unable to make flake");
 
             for x in -100..100 {
@@ -595,7 +1303,7 @@ mod tests {
                 }
             }
 
-            tree.root.print(0);
+            tree.print(ROOT, 0);
 
             for x in -100..100 {
                 for y in -100..100 {
@@ -614,10 +1322,453 @@ mod tests {
         });
     }
 
+    #[test]
+    fn node_wrap_node() {
+        let mut node = QuadTree::with_split_root(Point::ZERO, 10.0);
+        node.add_point(&Point { x: 1.0, y: 1.0 });
+
+        node.grow_root(&Point { x: -15.0, y: 15.0 });
+        let root = node.nodes[ROOT];
+        assert_eq!(-10.0, root.center.x);
+        assert_eq!(10.0, root.center.y);
+        assert_eq!(20.0, root.radius);
+        assert_eq!(true, root.point_is_in_bounds(&Point { x: -15.0, y: 15.0 }));
+        assert_eq!(1, node.all_points().len());
+        assert_eq!(0.0, node.get_nearest(&Point { x: 1.0, y: 1.0 }).unwrap().1);
+    }
+
+    #[test]
+    fn flake_add_points_outside_root() {
+        with_test_dir(|test_dir: &str| {
+            let flake =
+                Flake::new(&format!("{}/test.flake", test_dir)).expect("Unable to open flake");
+            let mut tree = QuadTree::from_flake(&flake).expect("Unable to make flake");
+
+            let far_points = [
+                Point { x: 5000.0, y: 0.0 },
+                Point { x: -1.0e6, y: 3.0 },
+                Point { x: 2.0, y: -1.0e7 },
+                Point { x: 1.0e8, y: 1.0e8 },
+            ];
+            tree.add_point(&Point::ZERO);
+            for point in &far_points {
+                tree.add_point(point);
+            }
+
+            assert_eq!(5, tree.len());
+            assert_eq!(5, tree.all_points().len());
+            for (i, point) in far_points.iter().enumerate() {
+                let nearest = tree.get_nearest(point).unwrap();
+                assert_eq!(0.0, nearest.1);
+                assert_eq!(i + 1, nearest.2);
+            }
+            assert_eq!(1.0, tree.get_nearest(&Point { x: 1.0, y: 0.0 }).unwrap().1);
+        });
+    }
+
+    #[test]
+    fn flake_get_k_nearest() {
+        with_test_dir(|test_dir: &str| {
+            let flake =
+                Flake::new(&format!("{}/test.flake", test_dir)).expect("Unable to open flake");
+            let mut tree = QuadTree::from_flake(&flake).expect("Unable to make flake");
+            assert_eq!(true, tree.get_k_nearest(&Point::ZERO, 3).is_empty());
+
+            let mut rng = StdRng::seed_from_u64(23);
+            let mut points: Vec<Point> = Vec::new();
+            for _i in 0..1000 {
+                let point = Point {
+                    x: rng.gen_range(-100.0..100.0),
+                    y: rng.gen_range(-100.0..100.0),
+                };
+                tree.add_point(&point);
+                points.push(point);
+            }
+
+            assert_eq!(true, tree.get_k_nearest(&Point::ZERO, 0).is_empty());
+            assert_eq!(1000, tree.get_k_nearest(&Point::ZERO, 5000).len());
+
+            for _i in 0..100 {
+                let query_point = Point {
+                    x: rng.gen_range(-150.0..150.0),
+                    y: rng.gen_range(-150.0..150.0),
+                };
+                let mut expected: Vec<f64> =
+                    points.iter().map(|p| p.distance(&query_point)).collect();
+                expected.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+                let result = tree.get_k_nearest(&query_point, 7);
+                assert_eq!(7, result.len());
+                for (i, (point, distance, index)) in result.iter().enumerate() {
+                    assert_eq!(expected[i], *distance);
+                    assert_eq!(point.x, points[*index].x);
+                }
+                assert_eq!(
+                    tree.get_nearest(&query_point).unwrap().2,
+                    result[0].2
+                );
+            }
+        });
+    }
+
+    #[test]
+    fn flake_points_within() {
+        with_test_dir(|test_dir: &str| {
+            let flake =
+                Flake::new(&format!("{}/test.flake", test_dir)).expect("Unable to open flake");
+            let mut tree = QuadTree::from_flake(&flake).expect("Unable to make flake");
+
+            for x in -20..20 {
+                for y in -20..20 {
+                    tree.add_point(&Point {
+                        x: x as f64,
+                        y: y as f64,
+                    });
+                }
+            }
+
+            // The point itself and its four direct neighbours
+            let result = tree.points_within(&Point { x: 3.0, y: 3.0 }, 1.0);
+            assert_eq!(5, result.len());
+            assert_eq!(true, result.iter().all(|r| r.1 <= 1.0));
+
+            // Diagonal neighbours are just over 1.4 away
+            assert_eq!(9, tree.points_within(&Point { x: 3.0, y: 3.0 }, 1.5).len());
+            assert_eq!(4, tree.points_within(&Point { x: 19.0, y: 19.0 }, 1.5).len());
+            assert_eq!(0, tree.points_within(&Point { x: 50.0, y: 0.0 }, 10.0).len());
+            assert_eq!(1600, tree.points_within(&Point::ZERO, 100.0).len());
+        });
+    }
+
+    #[test]
+    fn flake_iter() {
+        with_test_dir(|test_dir: &str| {
+            let flake =
+                Flake::new(&format!("{}/test.flake", test_dir)).expect("Unable to open flake");
+            let mut tree = QuadTree::from_flake(&flake).expect("Unable to make flake");
+            assert_eq!(0, tree.iter().count());
+
+            let mut rng = StdRng::seed_from_u64(29);
+            let mut points: Vec<Point> = Vec::new();
+            for _i in 0..1000 {
+                let point = Point {
+                    x: rng.gen_range(-2000.0..2000.0),
+                    y: rng.gen_range(-2000.0..2000.0),
+                };
+                tree.add_point(&point);
+                points.push(point);
+            }
+
+            let mut seen = vec![false; points.len()];
+            for (point, index) in tree.iter() {
+                assert_eq!(false, seen[index]);
+                assert_eq!(points[index].x, point.x);
+                assert_eq!(points[index].y, point.y);
+                seen[index] = true;
+            }
+            assert_eq!(true, seen.iter().all(|seen| *seen));
+        });
+    }
+
+    #[test]
+    fn flake_points_in_rect() {
+        with_test_dir(|test_dir: &str| {
+            let flake =
+                Flake::new(&format!("{}/test.flake", test_dir)).expect("Unable to open flake");
+            let mut tree = QuadTree::from_flake(&flake).expect("Unable to make flake");
+
+            for x in -20..20 {
+                for y in -20..20 {
+                    tree.add_point(&Point {
+                        x: x as f64,
+                        y: y as f64,
+                    });
+                }
+            }
+
+            // Edges are included
+            let result = tree.points_in_rect(&Point { x: 0.0, y: 0.0 }, &Point { x: 2.0, y: 3.0 });
+            assert_eq!(12, result.len());
+            assert_eq!(
+                true,
+                result
+                    .iter()
+                    .all(|(p, _)| p.x >= 0.0 && p.x <= 2.0 && p.y >= 0.0 && p.y <= 3.0)
+            );
+
+            assert_eq!(
+                1,
+                tree.points_in_rect(&Point { x: 19.0, y: -20.0 }, &Point { x: 100.0, y: -19.5 })
+                    .len()
+            );
+            assert_eq!(
+                0,
+                tree.points_in_rect(&Point { x: 0.1, y: 0.1 }, &Point { x: 0.9, y: 0.9 })
+                    .len()
+            );
+            assert_eq!(
+                1600,
+                tree.points_in_rect(&Point { x: -50.0, y: -50.0 }, &Point { x: 50.0, y: 50.0 })
+                    .len()
+            );
+        });
+    }
+
+    #[test]
+    fn node_remove_point_merges() {
+        let mut node = QuadTree::with_split_root(Point::ZERO, 100.0);
+        for i in 0..200 {
+            node.add_point(&Point {
+                x: 10.0 + (i % 20) as f64,
+                y: 10.0 + (i / 20) as f64,
+            });
+        }
+        if let Contents::Split { first_child } = node.nodes[ROOT].contents {
+            let north_east = node.nodes[first_child + 1].contents;
+            assert_eq!(true, matches!(north_east, Contents::Split { .. }));
+        }
+
+        assert_eq!(true, node.remove_point(&Point { x: 1000.0, y: 0.0 }).is_none());
+        assert_eq!(true, node.remove_point(&Point { x: 10.5, y: 10.0 }).is_none());
+
+        for i in 0..150 {
+            let point = Point {
+                x: 10.0 + (i % 20) as f64,
+                y: 10.0 + (i / 20) as f64,
+            };
+            assert_eq!(Option::Some(i), node.remove_point(&point));
+        }
+
+        // Once few enough points are left the whole tree is merged into the root
+        assert_eq!(50, node.all_points().len());
+        assert_eq!(true, matches!(node.nodes[ROOT].contents, Contents::Points { len: 50, .. }));
+        assert_eq!(0.0, node.get_nearest(&Point { x: 29.0, y: 19.0 }).unwrap().1);
+    }
+
+    #[test]
+    fn flake_remove_and_move_points() {
+        with_test_dir(|test_dir: &str| {
+            let flake =
+                Flake::new(&format!("{}/test.flake", test_dir)).expect("Unable to open flake");
+            let mut tree = QuadTree::from_flake(&flake).expect("Unable to make flake");
+
+            let mut rng = StdRng::seed_from_u64(31);
+            let mut points: Vec<Option<Point>> = Vec::new();
+            for _i in 0..1000 {
+                let point = Point {
+                    x: rng.gen_range(-100.0..100.0),
+                    y: rng.gen_range(-100.0..100.0),
+                };
+                tree.add_point(&point);
+                points.push(Option::Some(point));
+            }
+
+            for _i in 0..500 {
+                let index = rng.gen_range(0..points.len());
+                if let Option::Some(point) = points[index] {
+                    if rng.gen_bool(0.5) {
+                        assert_eq!(Option::Some(index), tree.remove_point(&point));
+                        points[index] = Option::None;
+                    } else {
+                        let to = Point {
+                            x: rng.gen_range(-300.0..300.0),
+                            y: rng.gen_range(-300.0..300.0),
+                        };
+                        assert_eq!(Option::Some(index), tree.move_point(&point, &to));
+                        points[index] = Option::Some(to);
+                    }
+                }
+            }
+
+            let remaining: Vec<(usize, Point)> = points
+                .iter()
+                .enumerate()
+                .filter_map(|(i, point)| point.map(|point| (i, point)))
+                .collect();
+            assert_eq!(remaining.len(), tree.len());
+            assert_eq!(remaining.len(), tree.iter().count());
+            assert_eq!(
+                remaining
+                    .iter()
+                    .map(|(_, point)| point.distance(&Point::ZERO))
+                    .fold(0.0, f64::max),
+                tree.get_farthest_distance()
+            );
+
+            for _i in 0..100 {
+                let query_point = Point {
+                    x: rng.gen_range(-300.0..300.0),
+                    y: rng.gen_range(-300.0..300.0),
+                };
+                let expected = remaining
+                    .iter()
+                    .map(|(_, point)| point.distance(&query_point))
+                    .fold(f64::INFINITY, f64::min);
+                let (point, distance, index) = tree.get_nearest(&query_point).unwrap();
+                assert_eq!(expected, distance);
+                assert_eq!(point.x, points[index].unwrap().x);
+            }
+
+            // New points get fresh indices
+            tree.add_point(&Point::ZERO);
+            assert_eq!(1000, tree.get_nearest(&Point::ZERO).unwrap().2);
+
+            for (_, point) in &remaining {
+                assert_eq!(true, tree.remove_point(point).is_some());
+            }
+            assert_eq!(1, tree.len());
+            assert_eq!(0.0, tree.get_farthest_distance());
+        });
+    }
+
+    #[test]
+    fn remove_farthest_points() {
+        let mut rng = StdRng::seed_from_u64(67);
+        let mut points: Vec<Point> = Vec::new();
+        for _i in 0..20000 {
+            points.push(Point {
+                x: rng.gen_range(-500.0..500.0),
+                y: rng.gen_range(-500.0..500.0),
+            });
+        }
+        let mut tree = QuadTree::from_points(&points);
+
+        // Erode the points from the outside in, as removing the farthest
+        // point shouldn't need to look at every other point
+        points.sort_by(|a, b| {
+            let a = a.distance(&Point::ZERO);
+            let b = b.distance(&Point::ZERO);
+            return a.partial_cmp(&b).unwrap();
+        });
+        while let Option::Some(point) = points.pop() {
+            assert_eq!(point.distance(&Point::ZERO), tree.get_farthest_distance());
+            assert_eq!(true, tree.remove_point(&point).is_some());
+            if let Option::Some(next) = points.last() {
+                assert_eq!(next.distance(&Point::ZERO), tree.get_farthest_distance());
+            }
+        }
+        assert_eq!(true, tree.is_empty());
+        assert_eq!(0.0, tree.get_farthest_distance());
+    }
+
+    #[test]
+    fn decode_checks_bounds() {
+        let mut tree = QuadTree::new(Point::ZERO, 8.0);
+        tree.add_point(&Point { x: 1.0, y: 2.0 });
+        let mut buf = Vec::new();
+        tree.encode(&mut buf);
+        let decoded = QuadTree::decode(&mut Decoder::new(&buf)).expect("Unable to decode tree");
+        assert_eq!(1, decoded.len());
+        assert_eq!(tree.get_farthest_distance(), decoded.get_farthest_distance());
+
+        // Move the point outside the root, after the header and root node
+        let x = 8 * 4 + 8 * 3 + 1 + 8;
+        buf[x..x + 8].copy_from_slice(&100.0_f64.to_be_bytes());
+        let err = QuadTree::decode(&mut Decoder::new(&buf)).err().expect("Decoded bad tree");
+        assert_eq!(true, err.to_string().contains("outside its node"));
+
+        // Children must be quadrants of their parents
+        let mut tree = QuadTree::with_split_root(Point::ZERO, 8.0);
+        tree.add_point(&Point { x: 1.0, y: 2.0 });
+        let mut buf = Vec::new();
+        tree.encode(&mut buf);
+        assert_eq!(true, QuadTree::decode(&mut Decoder::new(&buf)).is_ok());
+        let child_radius = 8 * 4 + (8 * 3 + 1 + 8) + 8 * 2;
+        buf[child_radius..child_radius + 8].copy_from_slice(&8.0_f64.to_be_bytes());
+        let err = QuadTree::decode(&mut Decoder::new(&buf)).err().expect("Decoded bad tree");
+        assert_eq!(true, err.to_string().contains("not a quadrant"));
+
+        // The farthest distance must match the points
+        let mut buf = Vec::new();
+        tree.encode(&mut buf);
+        buf[8..16].copy_from_slice(&1.0_f64.to_be_bytes());
+        assert_eq!(true, QuadTree::decode(&mut Decoder::new(&buf)).is_err());
+    }
+
+    #[test]
+    fn decode_grown_tree() {
+        // Growing the root several times moves the old root after its own
+        // children in the arena
+        let mut rng = StdRng::seed_from_u64(41);
+        let mut tree = QuadTree::new(Point::ZERO, 1.0);
+        let mut points = Vec::new();
+        for i in 0..2000 {
+            let point = Point {
+                x: rng.gen_range(-1.0..1.0) * i as f64,
+                y: rng.gen_range(-1.0..1.0) * i as f64,
+            };
+            tree.add_point(&point);
+            points.push(point);
+        }
+        assert_eq!(true, tree.nodes[ROOT].radius > 1000.0);
+
+        let mut buf = Vec::new();
+        tree.encode(&mut buf);
+        let decoded = QuadTree::decode(&mut Decoder::new(&buf)).expect("Unable to decode tree");
+        assert_eq!(tree.len(), decoded.len());
+        assert_eq!(tree.get_farthest_distance(), decoded.get_farthest_distance());
+        for point in &points {
+            let (_, distance, _) = decoded.get_nearest(point).expect("No nearest point");
+            assert_eq!(0.0, distance);
+        }
+    }
+
+    #[test]
+    fn flake_from_points() {
+        let mut rng = StdRng::seed_from_u64(37);
+        let mut points: Vec<Point> = Vec::new();
+        for _i in 0..5000 {
+            points.push(Point {
+                x: rng.gen_range(-2000.0..2000.0),
+                y: rng.gen_range(-50.0..50.0),
+            });
+        }
+        // Enough points in one place to need several levels of splitting
+        for i in 0..500 {
+            points.push(Point {
+                x: 0.001 * i as f64,
+                y: 0.0,
+            });
+        }
+
+        let mut tree = QuadTree::from_points(&points);
+        assert_eq!(points.len(), tree.len());
+        assert_eq!(points.len(), tree.iter().count());
+        assert_eq!(
+            points.iter().map(|p| p.distance(&Point::ZERO)).fold(0.0, f64::max),
+            tree.get_farthest_distance()
+        );
+        for (point, index) in tree.iter() {
+            assert_eq!(points[index].x, point.x);
+            assert_eq!(points[index].y, point.y);
+        }
+
+        for _i in 0..1000 {
+            let query_point = Point {
+                x: rng.gen_range(-3000.0..3000.0),
+                y: rng.gen_range(-3000.0..3000.0),
+            };
+            let expected = points
+                .iter()
+                .map(|p| p.distance(&query_point))
+                .fold(f64::INFINITY, f64::min);
+            assert_eq!(expected, tree.get_nearest(&query_point).unwrap().1);
+        }
+
+        // The tree can still be added to afterwards
+        tree.add_point(&Point { x: 1.0e5, y: 0.0 });
+        assert_eq!(
+            points.len(),
+            tree.get_nearest(&Point { x: 1.0e5, y: 1.0 }).unwrap().2
+        );
+        assert_eq!(true, QuadTree::from_points(&[]).is_empty());
+    }
+
     #[test]
     fn flake_get_farthest_distance() {
         with_test_dir(|test_dir: &str| {
-            let flake = Flake::new(&format!("{}/test.flake", test_dir));
+            let flake =
+                Flake::new(&format!("{}/test.flake", test_dir)).expect("Unable to open flake");
             let mut tree = QuadTree::from_flake(&flake).expect("Unable to make flake");
 
             tree.add_point(&Point::ZERO);
@@ -634,17 +1785,18 @@ mod tests {
     /*
      * Last recorded performance:
      *
-     * Time to add 100000 points: 17.675888ms
-     * Time per point: 176ns
+     * Time to add 100000 points: 10.217979ms
+     * Time per point: 102ns
      *
-     * Time to query 100000 points: 87.696022ms
-     * Time per point: 876ns
+     * Time to query 100000 points: 43.821694ms
+     * Time per point: 438ns
      */
     #[test]
     #[ignore]
     fn insertion_and_query_100000_perf() {
         with_test_dir(|test_dir: &str| {
-            let flake = Flake::new(&format!("{}/test.flake", test_dir));
+            let flake =
+                Flake::new(&format!("{}/test.flake", test_dir)).expect("Unable to open flake");
             let mut tree = QuadTree::from_flake(&flake).expect("Unable to make flake");
 
             let mut rng = StdRng::seed_from_u64(17);
@@ -688,4 +1840,135 @@ mod tests {
             println!();
         });
     }
+
+    /*
+     * Last recorded performance:
+     *
+     * Time to add 1000000 points one at a time: 197.021305ms
+     * Time per point: 197ns
+     *
+     * Time to bulk load 1000000 points: 118.012722ms
+     * Time per point: 118ns
+     */
+    #[test]
+    #[ignore]
+    fn bulk_load_1000000_perf() {
+        let mut rng = StdRng::seed_from_u64(17);
+
+        let num_points = 1000000;
+        let mut points: Vec<Point> = Vec::new();
+        for _i in 0..num_points {
+            points.push(Point {
+                x: rng.gen_range(-1000.0..1000.0),
+                y: rng.gen_range(-1000.0..1000.0),
+            });
+        }
+
+        let mut tree = QuadTree::new(Point::ZERO, 2000.0);
+        let adding_time = time_func(|| {
+            for point in &points {
+                tree.add_point(point);
+            }
+        });
+        println!(
+            "Time to add {} points one at a time: {:?}",
+            num_points, adding_time
+        );
+        println!("Time per point: {:?}", adding_time / num_points);
+        println!();
+
+        let mut num_loaded = 0;
+        let loading_time = time_func(|| {
+            num_loaded = QuadTree::from_points(&points).len();
+        });
+        assert_eq!(num_points as usize, num_loaded);
+        println!("Time to bulk load {} points: {:?}", num_points, loading_time);
+        println!("Time per point: {:?}", loading_time / num_points);
+        println!();
+    }
+
+    /*
+     * Compares nearest point queries against first checking a distance field,
+     * on points arranged in thin arms like a flake rather than spread evenly.
+     *
+     * Last recorded performance:
+     *
+     * Time to query 100000 points using the tree: 67.017127ms
+     * Time per point: 670ns
+     *
+     * Time to query 100000 points using the distance field: 21.01143ms
+     * Time per point: 210ns
+     * Queries answered by the distance field: 64%
+     */
+    #[test]
+    #[ignore]
+    fn distance_field_query_100000_perf() {
+        with_test_dir(|test_dir: &str| {
+            let flake =
+                Flake::new(&format!("{}/test.flake", test_dir)).expect("Unable to open flake");
+            let mut tree = QuadTree::from_flake(&flake).expect("Unable to make flake");
+            let mut field = DistanceField::new();
+
+            let mut rng = StdRng::seed_from_u64(17);
+
+            let num_arms = 100;
+            let arm_length = 1000;
+            for _i in 0..num_arms {
+                let angle: f64 = rng.gen_range(0.0..std::f64::consts::PI * 2.0);
+                for j in 1..=arm_length {
+                    let point = Point {
+                        x: angle.cos() * j as f64,
+                        y: angle.sin() * j as f64,
+                    };
+                    tree.add_point(&point);
+                    field.add_point(&point);
+                }
+            }
+
+            let num_query_points = 100000;
+            let mut query_points: Vec<Point> = Vec::new();
+            for _i in 0..num_query_points {
+                query_points.push(Point {
+                    x: rng.gen_range(-1000.0..1000.0),
+                    y: rng.gen_range(-1000.0..1000.0),
+                });
+            }
+
+            // A walker only needs the nearest point when it is close to the flake
+            let close = 8.0;
+
+            let tree_time = time_func(|| {
+                for query_point in &query_points {
+                    assert!(tree.get_nearest(query_point).unwrap().1 >= 0.0);
+                }
+            });
+            println!(
+                "Time to query {} points using the tree: {:?}",
+                num_query_points, tree_time
+            );
+            println!("Time per point: {:?}", tree_time / num_query_points);
+            println!();
+
+            let mut answered_by_field = 0;
+            let field_time = time_func(|| {
+                for query_point in &query_points {
+                    if field.get_lower_bound(query_point) > close {
+                        answered_by_field += 1;
+                    } else {
+                        assert!(tree.get_nearest(query_point).unwrap().1 >= 0.0);
+                    }
+                }
+            });
+            println!(
+                "Time to query {} points using the distance field: {:?}",
+                num_query_points, field_time
+            );
+            println!("Time per point: {:?}", field_time / num_query_points);
+            println!(
+                "Queries answered by the distance field: {}%",
+                answered_by_field * 100 / num_query_points
+            );
+            println!();
+        });
+    }
 }
@@ -1,11 +1,57 @@
 use std::f64::INFINITY;
 use std::path::Path;
+use std::thread;
+use std::time::Duration;
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
 
-use crate::{flake::Flake, point::Point};
+use crate::flake::{Flake, Particle};
+use crate::index::Index;
+use crate::point::Point;
+use crate::species::Species;
 
 const BORDER: f64 = 10.0;
+const WHITE: [u8; 3] = [255, 255, 255];
+
+// A region of the flake to render, given by its lower and upper corners.
+pub struct Viewport {
+    min: Point,
+    max: Point,
+}
+
+impl Viewport {
+    // Parse a viewport given as <left>,<bottom>,<right>,<top>.
+    pub fn parse(viewport: &str) -> Result<Viewport> {
+        let values = viewport
+            .split(',')
+            .map(|value| {
+                value
+                    .trim()
+                    .parse::<f64>()
+                    .context(format!("Unable to parse '{}' as a number", value))
+            })
+            .collect::<Result<Vec<f64>>>()?;
+        if values.len() != 4 {
+            return Result::Err(anyhow!(
+                "Expected <left>,<bottom>,<right>,<top> but found '{}'",
+                viewport
+            ));
+        }
+        if values[0] >= values[2] || values[1] >= values[3] {
+            return Result::Err(anyhow!("Viewport '{}' has no area", viewport));
+        }
+        return Result::Ok(Viewport {
+            min: Point {
+                x: values[0],
+                y: values[1],
+            },
+            max: Point {
+                x: values[2],
+                y: values[3],
+            },
+        });
+    }
+}
 
 struct Window {
     left: f64,
@@ -14,56 +60,293 @@ struct Window {
     height: usize,
 }
 
-fn find_bounding_rect(points: Vec<Point>) -> Window {
-    let mut left: f64 = INFINITY;
-    let mut top: f64 = INFINITY;
-    let mut right: f64 = -INFINITY;
-    let mut bottom: f64 = -INFINITY;
-
-    for point in &points {
-        left = left.min(point.x);
-        top = top.min(point.y);
-        right = right.max(point.x);
-        bottom = bottom.max(point.y);
+impl Window {
+    fn from_viewport(viewport: &Viewport) -> Window {
+        return Window {
+            left: viewport.min.x,
+            top: viewport.min.y,
+            width: (viewport.max.x - viewport.min.x).floor() as usize + 1,
+            height: (viewport.max.y - viewport.min.y).floor() as usize + 1,
+        };
     }
-    
-    let left = left - BORDER;
-    let top = top - BORDER;
-    let width = (right - left + BORDER + 1.0).ceil() as usize;
-    let height = (bottom - top + BORDER + 1.0).ceil() as usize;
 
-    return Window { left, top, width, height };
+    // The smallest window holding the given bounds, with a border around them.
+    fn from_bounds(bounds: &Bounds) -> Window {
+        let left = bounds.left - BORDER;
+        let top = bounds.top - BORDER;
+        let width = (bounds.right - left + BORDER + 1.0).ceil() as usize;
+        let height = (bounds.bottom - top + BORDER + 1.0).ceil() as usize;
+        return Window { left, top, width, height };
+    }
+
+    fn contains(&self, point: &Point) -> bool {
+        return point.x >= self.left
+            && point.x < self.left + self.width as f64
+            && point.y >= self.top
+            && point.y < self.top + self.height as f64;
+    }
 }
 
-fn draw_point(point: &Point, window: &Window, buffer: &mut Vec<u8>) {
-    let x = (point.x - window.left) as usize;
-    let y = (point.y - window.top) as usize;
-    let i = (x + y * window.width) * 3;
-    buffer[i] = 255;
-    buffer[i + 1] = 255;
-    buffer[i + 2] = 255;
+// The extent of the particles seen so far.
+struct Bounds {
+    left: f64,
+    top: f64,
+    right: f64,
+    bottom: f64,
 }
 
-pub fn render(flake: &Flake, output_filename: &str) -> Result<()> {
-    let points = flake.get_points().context("Unable to get points for flake")?;
+impl Bounds {
+    fn new() -> Bounds {
+        return Bounds {
+            left: INFINITY,
+            top: INFINITY,
+            right: -INFINITY,
+            bottom: -INFINITY,
+        };
+    }
 
-    let window = find_bounding_rect(points.clone());
+    fn add(&mut self, point: &Point) {
+        self.left = self.left.min(point.x);
+        self.top = self.top.min(point.y);
+        self.right = self.right.max(point.x);
+        self.bottom = self.bottom.max(point.y);
+    }
+}
+
+fn find_bounding_rect(flake: &Flake) -> Result<Window> {
+    let mut bounds = Bounds::new();
+    for point in flake.iter_points().context("Unable to read flake points")? {
+        let point = point.context("Unable to read flake points")?;
+        bounds.add(&point);
+    }
+    return Result::Ok(Window::from_bounds(&bounds));
+}
 
-    let mut buffer: Vec<u8> = vec![0; window.width * window.height * 3];
+// An image of the part of the flake within a window.
+struct Canvas {
+    window: Window,
+    buffer: Vec<u8>,
+}
+
+impl Canvas {
+    fn new(window: Window) -> Canvas {
+        let buffer: Vec<u8> = vec![0; window.width * window.height * 3];
+        return Canvas { window, buffer };
+    }
+
+    // Draw a particle, if it is within the window.
+    fn draw(&mut self, particle: &Particle, species: &[Species]) {
+        if !self.window.contains(&particle.point) {
+            return;
+        }
+        let x = (particle.point.x - self.window.left) as usize;
+        let y = (particle.point.y - self.window.top) as usize;
+        let i = (x + y * self.window.width) * 3;
+        self.buffer[i..i + 3].copy_from_slice(&colour(particle, species));
+    }
 
-    for point in &points {
-        draw_point(point, &window, &mut buffer)
+    // Draw the particles of the flake within the window, using the points in
+    // the flake's index file if it is up to date. Only particles added to the
+    // flake file since the index was saved are read from the flake file. The
+    // index doesn't know the species of each point, so this is only possible
+    // if every particle would be drawn in the same colour. Returns false if
+    // the index wasn't used.
+    fn draw_from_index(&mut self, flake: &Flake, species: &[Species]) -> Result<bool> {
+        if species.iter().any(|species| species.colour != WHITE) {
+            return Result::Ok(false);
+        }
+        let index = match Index::load(flake) {
+            Result::Ok(Option::Some(index)) => index,
+            Result::Ok(Option::None) | Result::Err(_) => return Result::Ok(false),
+        };
+        let tree = index.tree.as_quad_tree().ok_or_else(|| anyhow!("Index is not a quadtree"))?;
+        let min = Point {
+            x: self.window.left,
+            y: self.window.top,
+        };
+        let max = Point {
+            x: self.window.left + self.window.width as f64,
+            y: self.window.top + self.window.height as f64,
+        };
+        for (point, _) in tree.points_in_rect(&min, &max) {
+            self.draw(&Particle::new(point), species);
+        }
+        return Result::Ok(true);
     }
 
-    // Save the buffer as "image.png"
-    image::save_buffer(
-        &Path::new(output_filename),
-        &buffer,
-        window.width as u32,
-        window.height as u32,
-        image::ColorType::Rgb8,
-    )
-    .context("Unable to save image buffer to file")?;
-
-    return Result::Ok(());
+    // Draw every particle in the flake file.
+    fn draw_flake(&mut self, flake: &Flake, species: &[Species]) -> Result<()> {
+        for particle in flake.iter_particles().context("Unable to read flake particles")? {
+            let particle = particle.context("Unable to read flake particles")?;
+            self.draw(&particle, species);
+        }
+        return Result::Ok(());
+    }
+
+    fn save(&self, output_filename: &str) -> Result<()> {
+        image::save_buffer(
+            &Path::new(output_filename),
+            &self.buffer,
+            self.window.width as u32,
+            self.window.height as u32,
+            image::ColorType::Rgb8,
+        )
+        .context("Unable to save image buffer to file")?;
+        return Result::Ok(());
+    }
+}
+
+// Particles are coloured according to their species. Any particle whose
+// species isn't in the given list is drawn in white.
+fn colour(particle: &Particle, species: &[Species]) -> [u8; 3] {
+    return match species.get(particle.species as usize) {
+        Option::Some(species) => species.colour,
+        Option::None => WHITE,
+    };
+}
+
+// Renders the whole flake, or only the particles within the given viewport.
+// Particles are read from the flake file as they are drawn, so only the image
+// needs to fit in memory. A viewport is drawn from the flake's index file
+// instead when possible, which avoids decoding the particles in the flake
+// file from before the index was saved.
+pub fn render(
+    flake: &Flake,
+    species: &[Species],
+    viewport: Option<&Viewport>,
+    output_filename: &str,
+) -> Result<()> {
+    let canvas = match viewport {
+        Option::Some(viewport) => {
+            let mut canvas = Canvas::new(Window::from_viewport(viewport));
+            if !canvas.draw_from_index(flake, species)? {
+                canvas.draw_flake(flake, species)?;
+            }
+            canvas
+        }
+        Option::None => {
+            let mut canvas = Canvas::new(find_bounding_rect(flake)?);
+            canvas.draw_flake(flake, species)?;
+            canvas
+        }
+    };
+    return canvas.save(output_filename);
+}
+
+// Renders the flake as with render, then keeps rendering it again as another
+// process adds to it, checking for new particles at the given interval. Only
+// the new particles are drawn, unless the flake has grown out of the image.
+// Never returns unless there is an error.
+pub fn watch(
+    flake: &Flake,
+    species: &[Species],
+    viewport: Option<&Viewport>,
+    output_filename: &str,
+    interval: Duration,
+) -> Result<()> {
+    let mut follower = flake.follow();
+    let mut bounds = Bounds::new();
+    let mut canvas: Option<Canvas> = Option::None;
+    loop {
+        let particles = follower.read_new().context("Unable to read flake particles")?;
+        // Without a viewport, the size of the image isn't known until there
+        // are particles
+        let waiting = canvas.is_some() || (viewport.is_none() && follower.num_particles() == 0);
+        if particles.is_empty() && waiting {
+            thread::sleep(interval);
+            continue;
+        }
+        for particle in &particles {
+            bounds.add(&particle.point);
+        }
+
+        let outgrown = match (&canvas, viewport) {
+            (Option::None, _) => true,
+            (Option::Some(_), Option::Some(_)) => false,
+            (Option::Some(canvas), Option::None) => {
+                particles.iter().any(|particle| !canvas.window.contains(&particle.point))
+            }
+        };
+        if outgrown {
+            let window = match viewport {
+                Option::Some(viewport) => Window::from_viewport(viewport),
+                Option::None => Window::from_bounds(&bounds),
+            };
+            // Drawing everything again reads the whole file, which may hold
+            // particles that the follower hasn't got to yet. Those are drawn
+            // again when it does, which does no harm.
+            let mut new_canvas = Canvas::new(window);
+            new_canvas.draw_flake(flake, species)?;
+            canvas = Option::Some(new_canvas);
+        }
+        let canvas = canvas.as_mut().unwrap();
+        for particle in &particles {
+            canvas.draw(particle, species);
+        }
+
+        canvas.save(output_filename)?;
+        println!("Rendered {} particles", follower.num_particles());
+        thread::sleep(interval);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use super::{Canvas, Viewport, Window};
+    use crate::flake::Flake;
+    use crate::index::Index;
+    use crate::point::Point;
+    use crate::spatial_index::Backend;
+    use crate::test_utils::test::with_test_dir;
+
+    #[test]
+    fn draw_viewport_from_index() {
+        with_test_dir(|test_dir: &str| {
+            let mut rng = StdRng::seed_from_u64(61);
+            let mut flake =
+                Flake::new(&format!("{}/test.flake", test_dir)).expect("Unable to open flake");
+            let mut add_points = |flake: &mut Flake, num_points: usize| {
+                for _i in 0..num_points {
+                    flake
+                        .add_point(&Point {
+                            x: rng.gen_range(-500.0..500.0),
+                            y: rng.gen_range(-500.0..500.0),
+                        })
+                        .expect("Unable to add point");
+                }
+                flake.flush().expect("Unable to flush");
+            };
+            add_points(&mut flake, 10000);
+            Index::build(&flake, Backend::QuadTree)
+                .expect("Unable to build index")
+                .save(&flake)
+                .expect("Unable to save index");
+            add_points(&mut flake, 100);
+            let viewport = Viewport::parse("-100,-100,100,100").expect("Unable to parse viewport");
+
+            let mut from_flake = Canvas::new(Window::from_viewport(&viewport));
+            from_flake.draw_flake(&flake, &[]).expect("Unable to draw flake");
+            let mut from_index = Canvas::new(Window::from_viewport(&viewport));
+            assert_eq!(true, from_index.draw_from_index(&flake, &[]).expect("Unable to draw"));
+            assert_eq!(from_flake.buffer, from_index.buffer);
+
+            // The particles from before the index was saved aren't read, so
+            // damage to them doesn't stop the viewport being drawn from the
+            // index, but does stop it being drawn from the flake file
+            let mut contents = fs::read(flake.path()).expect("Unable to read flake");
+            let middle = contents.len() / 2;
+            contents[middle] ^= 1;
+            fs::write(flake.path(), &contents).expect("Unable to write flake");
+            let mut from_flake = Canvas::new(Window::from_viewport(&viewport));
+            assert_eq!(true, from_flake.draw_flake(&flake, &[]).is_err());
+            let mut after_damage = Canvas::new(Window::from_viewport(&viewport));
+            assert_eq!(true, after_damage.draw_from_index(&flake, &[]).expect("Unable to draw"));
+            assert_eq!(from_index.buffer, after_damage.buffer);
+        });
+    }
 }
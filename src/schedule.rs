@@ -0,0 +1,264 @@
+use std::fs;
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::generate::POINT_DIAMETER;
+use crate::point::Point;
+
+const DEFAULT_STICKING_PROBABILITY: f64 = 1.0;
+const DEFAULT_ANISOTROPY: f64 = 0.0;
+
+// The quantity a schedule is expressed as a function of.
+#[derive(Clone, Copy)]
+pub enum ScheduleVariable {
+    // The index of the particle being added, counting all particles in the flake.
+    Index,
+    // The distance from the center to the farthest particle in the flake.
+    Radius,
+}
+
+// A piecewise-linear function of one of the schedule variables.
+// Values before the first knot or after the last knot are clamped.
+struct Curve {
+    variable: ScheduleVariable,
+    knots: Vec<(f64, f64)>,
+}
+
+impl Curve {
+    fn evaluate(&self, index: u64, radius: f64) -> f64 {
+        let x = match self.variable {
+            ScheduleVariable::Index => index as f64,
+            ScheduleVariable::Radius => radius,
+        };
+
+        let first = self.knots[0];
+        if x <= first.0 {
+            return first.1;
+        }
+        for window in self.knots.windows(2) {
+            let (x0, y0) = window[0];
+            let (x1, y1) = window[1];
+            if x <= x1 {
+                return y0 + (y1 - y0) * (x - x0) / (x1 - x0);
+            }
+        }
+        return self.knots[self.knots.len() - 1].1;
+    }
+}
+
+// The growth parameters to use for a single particle.
+#[derive(Clone, Copy)]
+pub struct Parameters {
+    // Probability that a walker sticks when it touches the flake.
+    pub sticking_probability: f64,
+    // How strongly sticking is biased towards the symmetry axes, from 0 to 1.
+    pub anisotropy: f64,
+    // Bias added to each step of the walk, per unit of distance walked.
+    pub drift: Point,
+    pub particle_diameter: f64,
+}
+
+// A set of growth parameters that vary over the course of generation.
+// Any parameter without a curve uses its default value.
+#[derive(Default)]
+pub struct Schedule {
+    sticking_probability: Option<Curve>,
+    anisotropy: Option<Curve>,
+    drift_x: Option<Curve>,
+    drift_y: Option<Curve>,
+    particle_diameter: Option<Curve>,
+}
+
+impl Schedule {
+    // Read a schedule from a config file. See parse for the format.
+    pub fn from_file(schedule_file: &str) -> Result<Schedule> {
+        let contents = fs::read_to_string(schedule_file)
+            .context(format!("Unable to read schedule file: {}", schedule_file))?;
+        return Schedule::parse(&contents)
+            .context(format!("Unable to parse schedule file: {}", schedule_file));
+    }
+
+    // Parse a schedule. Each non-empty line that isn't a comment has the form
+    //   <parameter> <index|radius> <x>=<value> <x>=<value> ...
+    // where the x values are strictly increasing.
+    pub fn parse(contents: &str) -> Result<Schedule> {
+        let mut schedule = Schedule::default();
+
+        for (line_number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut parts = line.split_whitespace();
+            let parameter = parts.next().unwrap();
+            let curve = parse_curve(parts)
+                .context(format!("Invalid schedule on line {}", line_number + 1))?;
+
+            let slot = match parameter {
+                "sticking-probability" => &mut schedule.sticking_probability,
+                "anisotropy" => &mut schedule.anisotropy,
+                "drift-x" => &mut schedule.drift_x,
+                "drift-y" => &mut schedule.drift_y,
+                "particle-diameter" => &mut schedule.particle_diameter,
+                _ => {
+                    return Result::Err(anyhow!(
+                        "Unknown parameter '{}' on line {}",
+                        parameter,
+                        line_number + 1
+                    ))
+                }
+            };
+            if slot.is_some() {
+                return Result::Err(anyhow!(
+                    "Parameter '{}' is scheduled more than once",
+                    parameter
+                ));
+            }
+            check_range(parameter, &curve)
+                .context(format!("Invalid schedule on line {}", line_number + 1))?;
+            *slot = Option::Some(curve);
+        }
+
+        return Result::Ok(schedule);
+    }
+
+    // Work out the parameters for the particle with the given index,
+    // when the flake has grown to the given radius.
+    pub fn evaluate(&self, index: u64, radius: f64) -> Parameters {
+        let value = |curve: &Option<Curve>, default: f64| match curve {
+            Option::Some(curve) => curve.evaluate(index, radius),
+            Option::None => default,
+        };
+        return Parameters {
+            sticking_probability: value(&self.sticking_probability, DEFAULT_STICKING_PROBABILITY),
+            anisotropy: value(&self.anisotropy, DEFAULT_ANISOTROPY),
+            drift: Point {
+                x: value(&self.drift_x, 0.0),
+                y: value(&self.drift_y, 0.0),
+            },
+            particle_diameter: value(&self.particle_diameter, POINT_DIAMETER),
+        };
+    }
+}
+
+// Check that every knot of a parameter's curve is in the range the parameter
+// allows. Curves are linear between knots, so the values in between are too.
+fn check_range(parameter: &str, curve: &Curve) -> Result<()> {
+    let (in_range, range): (fn(f64) -> bool, &str) = match parameter {
+        "sticking-probability" => (|y| y > 0.0 && y <= 1.0, "in (0, 1]"),
+        "anisotropy" => (|y| (0.0..1.0).contains(&y), "in [0, 1)"),
+        "particle-diameter" => (|y| y > 0.0, "positive"),
+        _ => (|_| true, ""),
+    };
+    for &(_, y) in curve.knots.iter() {
+        if !in_range(y) {
+            return Result::Err(anyhow!(
+                "Values of '{}' must be {} but found {}",
+                parameter,
+                range,
+                y
+            ));
+        }
+    }
+    return Result::Ok(());
+}
+
+fn parse_curve<'a, I: Iterator<Item = &'a str>>(mut parts: I) -> Result<Curve> {
+    let variable = match parts.next() {
+        Option::Some("index") => ScheduleVariable::Index,
+        Option::Some("radius") => ScheduleVariable::Radius,
+        Option::Some(other) => return Result::Err(anyhow!("Unknown variable '{}'", other)),
+        Option::None => return Result::Err(anyhow!("Missing variable")),
+    };
+
+    let mut knots: Vec<(f64, f64)> = Vec::new();
+    for knot in parts {
+        let (x, y) = knot
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Expected <x>=<value> but found '{}'", knot))?;
+        let x = x
+            .parse::<f64>()
+            .context(format!("Unable to parse '{}' as a number", x))?;
+        let y = y
+            .parse::<f64>()
+            .context(format!("Unable to parse '{}' as a number", y))?;
+        if !x.is_finite() || !y.is_finite() {
+            return Result::Err(anyhow!("Knots must be finite but found '{}'", knot));
+        }
+        if let Option::Some(&(previous_x, _)) = knots.last() {
+            if x <= previous_x {
+                return Result::Err(anyhow!("Knots must be in increasing order"));
+            }
+        }
+        knots.push((x, y));
+    }
+
+    if knots.is_empty() {
+        return Result::Err(anyhow!("At least one knot is required"));
+    }
+
+    return Result::Ok(Curve { variable, knots });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Schedule, DEFAULT_STICKING_PROBABILITY};
+    use crate::generate::POINT_DIAMETER;
+
+    #[test]
+    fn empty_schedule_uses_defaults() {
+        let schedule = Schedule::parse("").expect("Unable to parse schedule");
+        let parameters = schedule.evaluate(100, 10.0);
+        assert_eq!(DEFAULT_STICKING_PROBABILITY, parameters.sticking_probability);
+        assert_eq!(0.0, parameters.anisotropy);
+        assert_eq!(0.0, parameters.drift.x);
+        assert_eq!(0.0, parameters.drift.y);
+        assert_eq!(POINT_DIAMETER, parameters.particle_diameter);
+    }
+
+    #[test]
+    fn interpolates_by_index() {
+        let schedule = Schedule::parse(
+            "# plate core then dendrites\n\
+             sticking-probability index 0=1.0 100=1.0 200=0.5\n",
+        )
+        .expect("Unable to parse schedule");
+
+        assert_eq!(1.0, schedule.evaluate(0, 0.0).sticking_probability);
+        assert_eq!(1.0, schedule.evaluate(100, 0.0).sticking_probability);
+        assert_eq!(0.75, schedule.evaluate(150, 0.0).sticking_probability);
+        assert_eq!(0.5, schedule.evaluate(200, 0.0).sticking_probability);
+        assert_eq!(0.5, schedule.evaluate(1000, 0.0).sticking_probability);
+    }
+
+    #[test]
+    fn interpolates_by_radius() {
+        let schedule = Schedule::parse("anisotropy radius 10=0.0 20=0.8\ndrift-y radius 0=-1.0")
+            .expect("Unable to parse schedule");
+
+        assert_eq!(0.0, schedule.evaluate(0, 5.0).anisotropy);
+        assert_eq!(0.4, schedule.evaluate(0, 15.0).anisotropy);
+        assert_eq!(0.8, schedule.evaluate(0, 50.0).anisotropy);
+        assert_eq!(-1.0, schedule.evaluate(0, 50.0).drift.y);
+    }
+
+    #[test]
+    fn rejects_invalid_schedules() {
+        assert!(Schedule::parse("temperature index 0=1.0").is_err());
+        assert!(Schedule::parse("anisotropy time 0=1.0").is_err());
+        assert!(Schedule::parse("anisotropy index").is_err());
+        assert!(Schedule::parse("anisotropy index 0:1.0").is_err());
+        assert!(Schedule::parse("anisotropy index 5=1.0 5=0.0").is_err());
+        assert!(Schedule::parse("anisotropy index 0=a").is_err());
+        assert!(Schedule::parse("anisotropy index 0=0.5\nanisotropy radius 0=0.5").is_err());
+        assert!(Schedule::parse("drift-x index NaN=1.0").is_err());
+        assert!(Schedule::parse("drift-x index 0=inf").is_err());
+        assert!(Schedule::parse("sticking-probability index 0=1.0 10=0.0").is_err());
+        assert!(Schedule::parse("sticking-probability index 0=1.5").is_err());
+        assert!(Schedule::parse("anisotropy radius 0=1.0").is_err());
+        assert!(Schedule::parse("anisotropy radius 0=-0.1").is_err());
+        assert!(Schedule::parse("particle-diameter radius 0=0").is_err());
+        assert!(Schedule::parse("drift-x radius 0=-2.0").is_ok());
+    }
+}
@@ -0,0 +1,389 @@
+use std::ops::Deref;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+
+use crate::index::Index;
+use crate::point::Point;
+
+// Indexes that can be queried from several threads while another thread adds
+// points to them, without queries waiting for a point to be added. When no
+// queries are using the indexes, points are added to them directly.
+// Otherwise a second copy is kept. Queries use the published copy, while
+// points are added to the other, which is then published in its place. The
+// copy that was replaced catches up with the points it missed the next time
+// it is added to, once the queries still using it have finished.
+//
+// Each query sees the indexes either before or after any given point was
+// added, never part way through. Walkers make many queries for each point
+// they add, so keeping two copies up to date costs little.
+pub struct SharedIndex {
+    // Only locked long enough to take a reference to the published copy or
+    // to replace it, or to add a point to it when no queries are using it.
+    published: Mutex<Arc<Index>>,
+    // The number of points in the published copy, so that holders of a
+    // snapshot can tell cheaply whether it is out of date.
+    published_len: AtomicUsize,
+    // Locked while adding a point, so only one thread adds at a time.
+    spare: Mutex<Spare>,
+    // Signalled whenever a snapshot is dropped, which may leave the spare
+    // copy free to be added to.
+    released: Condvar,
+    released_lock: Mutex<()>,
+}
+
+// The copy of the indexes that isn't published, if one has been needed yet,
+// and the points added to the published copy that it is missing.
+struct Spare {
+    index: Option<Arc<Index>>,
+    missing: Vec<Point>,
+}
+
+// A copy of the indexes that won't change while it is held. Holding a
+// snapshot that is out of date stops points being added, so it should be
+// refreshed or dropped before waiting on anything else.
+pub struct Snapshot<'a> {
+    // Only None while being dropped.
+    index: Option<Arc<Index>>,
+    shared: &'a SharedIndex,
+}
+
+impl SharedIndex {
+    pub fn new(index: Index) -> SharedIndex {
+        let len = index.tree.len();
+        return SharedIndex {
+            published: Mutex::new(Arc::new(index)),
+            published_len: AtomicUsize::new(len),
+            spare: Mutex::new(Spare {
+                index: Option::None,
+                missing: Vec::new(),
+            }),
+            released: Condvar::new(),
+            released_lock: Mutex::new(()),
+        };
+    }
+
+    // Add the point, unless the nearest point to it is one added after the
+    // first seen points, such as by another thread while this one was
+    // deciding where to add it. Returns the index of the point if it was
+    // added.
+    pub fn try_add_point(&self, point: &Point, seen: usize) -> Option<usize> {
+        let mut spare = self.spare.lock().expect("Spatial index lock is poisoned");
+        let mut published = self.published();
+        if let Option::Some(writable) = Arc::get_mut(&mut published) {
+            let len = writable.tree.len();
+            if is_turned_away(writable, point, seen) {
+                return Option::None;
+            }
+            writable.add_point(point);
+            if spare.index.is_some() {
+                spare.missing.push(*point);
+            }
+            self.published_len.store(len + 1, Ordering::Release);
+            return Option::Some(len);
+        }
+
+        // Queries are using the published copy, so add to the spare copy,
+        // making one the first time it is needed
+        let Spare { index, missing } = &mut *spare;
+        let index = index.get_or_insert_with(|| Arc::new(Index::clone(&published)));
+        drop(published);
+
+        // Queries only hold on to a copy until they notice it is out of
+        // date, so this won't wait long for the last of them to finish
+        let mut released = self.released_lock.lock().expect("Spatial index lock is poisoned");
+        while Arc::get_mut(index).is_none() {
+            released = self.released.wait(released).expect("Spatial index lock is poisoned");
+        }
+        drop(released);
+        let writable = Arc::get_mut(index).unwrap();
+        for missing_point in missing.iter() {
+            writable.add_point(missing_point);
+        }
+        missing.clear();
+
+        let len = writable.tree.len();
+        if is_turned_away(writable, point, seen) {
+            return Option::None;
+        }
+        writable.add_point(point);
+        missing.push(*point);
+
+        let mut published = self.published();
+        std::mem::swap(&mut *published, index);
+        self.published_len.store(len + 1, Ordering::Release);
+        return Option::Some(len);
+    }
+
+    // Returns the published copy of the indexes.
+    pub fn snapshot(&self) -> Snapshot<'_> {
+        return Snapshot {
+            index: Option::Some(Arc::clone(&self.published())),
+            shared: self,
+        };
+    }
+
+    // Returns the number of points in the published copy.
+    pub fn len(&self) -> usize {
+        return self.published_len.load(Ordering::Acquire);
+    }
+
+    // A thread can only panic while holding a lock if the index itself
+    // panicked, in which case it may be part way through a change.
+    fn published(&self) -> MutexGuard<'_, Arc<Index>> {
+        return self.published.lock().expect("Spatial index lock is poisoned");
+    }
+}
+
+// Whether the nearest point to the given one was added after the first seen
+// points.
+fn is_turned_away(index: &Index, point: &Point, seen: usize) -> bool {
+    if seen >= index.tree.len() {
+        return false;
+    }
+    return match index.tree.get_nearest(point) {
+        Option::Some((_, _, nearest_index)) => nearest_index >= seen,
+        Option::None => false,
+    };
+}
+
+impl<'a> Snapshot<'a> {
+    // Replace the snapshot with the published copy, if points have been
+    // added since it was taken. Returns whether it was replaced.
+    pub fn refresh(&mut self) -> bool {
+        if self.tree.len() == self.shared.len() {
+            return false;
+        }
+        *self = self.shared.snapshot();
+        return true;
+    }
+}
+
+impl<'a> Deref for Snapshot<'a> {
+    type Target = Index;
+
+    fn deref(&self) -> &Index {
+        return self.index.as_ref().unwrap();
+    }
+}
+
+impl<'a> Drop for Snapshot<'a> {
+    fn drop(&mut self) {
+        // Let go of the copy before waking any thread waiting for it
+        self.index = Option::None;
+        let _released = self.shared.released_lock.lock().expect("Spatial index lock is poisoned");
+        self.shared.released.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use super::SharedIndex;
+    use crate::index::Index;
+    use crate::point::Point;
+    use crate::spatial_index::Backend;
+
+    impl SharedIndex {
+        fn from_points(backend: Backend, points: &[Point]) -> SharedIndex {
+            return SharedIndex::new(Index::from_points(points, backend));
+        }
+
+        fn add_point(&self, point: &Point) {
+            self.try_add_point(point, usize::MAX);
+        }
+
+        // Returns the indexes, once no other threads are using them.
+        fn into_inner(self) -> Index {
+            let published = self.published.into_inner().expect("Spatial index lock is poisoned");
+            return match Arc::try_unwrap(published) {
+                Result::Ok(index) => index,
+                Result::Err(_) => panic!("Spatial index is still in use"),
+            };
+        }
+    }
+
+    // Returns the distance from the given point to the nearest of the given
+    // points, found by checking them all.
+    fn brute_force_distance(points: &[Point], point: &Point) -> f64 {
+        return points
+            .iter()
+            .map(|other| other.distance(point))
+            .fold(f64::INFINITY, f64::min);
+    }
+
+    // Points growing outwards in arms, like a flake.
+    fn arm_points(num_points: usize, rng: &mut StdRng) -> Vec<Point> {
+        let mut points: Vec<Point> = Vec::new();
+        for i in 0..num_points {
+            let angle: f64 = rng.gen_range(0.0..std::f64::consts::PI * 2.0);
+            let radius = (i as f64).sqrt() * 4.0 + rng.gen_range(0.0..2.0);
+            points.push(Point {
+                x: angle.cos() * radius,
+                y: angle.sin() * radius,
+            });
+        }
+        return points;
+    }
+
+    #[test]
+    fn concurrent_queries_and_insertions() {
+        let mut rng = StdRng::seed_from_u64(67);
+        let points = arm_points(4000, &mut rng);
+        let num_initial = 500;
+
+        for backend in Backend::ALL.iter() {
+            let index = SharedIndex::from_points(*backend, &points[..num_initial]);
+            let finished = AtomicBool::new(false);
+
+            thread::scope(|scope| {
+                for thread_index in 0..4 {
+                    let index = &index;
+                    let points = &points;
+                    let finished = &finished;
+                    scope.spawn(move || {
+                        let mut rng = StdRng::seed_from_u64(71 + thread_index);
+                        let mut num_queries = 0;
+                        let mut snapshot = index.snapshot();
+                        // Keep querying until the writer is done, and at least
+                        // a few times in case it finishes first
+                        while !finished.load(Ordering::Acquire) || num_queries < 100 {
+                            let query_point = Point {
+                                x: rng.gen_range(-300.0..300.0),
+                                y: rng.gen_range(-300.0..300.0),
+                            };
+
+                            // Points are only ever added, so the published
+                            // copy must hold at least the points added before
+                            // the snapshot was taken, and a snapshot never
+                            // changes
+                            let added_before = index.len();
+                            snapshot.refresh();
+                            let seen = snapshot.tree.len();
+                            assert!(seen >= added_before);
+                            let (nearest, distance, i) = snapshot.tree.get_nearest(&query_point).unwrap();
+
+                            assert!(i < seen);
+                            assert_eq!(points[i].x, nearest.x);
+                            assert_eq!(points[i].y, nearest.y);
+                            assert_eq!(nearest.distance(&query_point), distance);
+                            assert_eq!(brute_force_distance(&points[..seen], &query_point), distance);
+                            assert!(snapshot.tree.get_distance_lower_bound(&query_point) <= distance);
+                            assert!(snapshot.field.get_lower_bound(&query_point) <= distance);
+                            assert_eq!(seen, snapshot.tree.len());
+                            num_queries += 1;
+                        }
+                    });
+                }
+
+                for point in &points[num_initial..] {
+                    index.add_point(point);
+                }
+                finished.store(true, Ordering::Release);
+            });
+
+            let index = index.into_inner();
+            assert_eq!(points.len(), index.tree.len());
+            for _i in 0..1000 {
+                let query_point = Point {
+                    x: rng.gen_range(-300.0..300.0),
+                    y: rng.gen_range(-300.0..300.0),
+                };
+                assert_eq!(
+                    brute_force_distance(&points, &query_point),
+                    index.tree.get_nearest(&query_point).unwrap().1
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn snapshots_are_unchanged() {
+        let points = [Point::ZERO, Point { x: 1.0, y: 0.0 }, Point { x: 2.0, y: 0.0 }];
+        let index = SharedIndex::from_points(Backend::QuadTree, &points[..1]);
+
+        // Adding a point doesn't wait for a snapshot to be dropped
+        let mut snapshot = index.snapshot();
+        index.add_point(&points[1]);
+        assert_eq!(1, snapshot.tree.len());
+        assert_eq!(2, index.len());
+
+        // The next point waits until the snapshot is up to date, and
+        // refreshing it lets go of the old copy
+        thread::scope(|scope| {
+            scope.spawn(|| index.add_point(&points[2]));
+            assert_eq!(true, snapshot.refresh());
+            assert_eq!(false, snapshot.refresh());
+        });
+        drop(snapshot);
+
+        // The copy that was replaced catches up
+        index.add_point(&Point { x: 3.0, y: 0.0 });
+        let index = index.into_inner();
+        assert_eq!(4, index.tree.len());
+        assert_eq!(2, index.tree.get_nearest(&Point { x: 2.1, y: 0.0 }).unwrap().2);
+    }
+
+    #[test]
+    fn points_added_since_seen_turn_away_nearby_points() {
+        let index = SharedIndex::from_points(Backend::QuadTree, &[Point::ZERO]);
+
+        // A point proposed knowing only about the first can't be added once
+        // another has been added nearer to it
+        assert_eq!(Option::Some(1), index.try_add_point(&Point { x: 5.0, y: 0.0 }, 1));
+        assert_eq!(Option::None, index.try_add_point(&Point { x: 4.0, y: 0.0 }, 1));
+        assert_eq!(Option::Some(2), index.try_add_point(&Point { x: -1.0, y: 0.0 }, 1));
+        assert_eq!(Option::Some(3), index.try_add_point(&Point { x: 4.0, y: 0.0 }, 3));
+        assert_eq!(4, index.len());
+    }
+
+    #[test]
+    fn concurrent_insertions() {
+        let mut rng = StdRng::seed_from_u64(73);
+        let points = arm_points(4000, &mut rng);
+
+        for backend in Backend::ALL.iter() {
+            let index = SharedIndex::from_points(*backend, &[]);
+
+            // Several threads adding points at once, while another checks the
+            // farthest distance only ever grows
+            thread::scope(|scope| {
+                for chunk in points.chunks(1000) {
+                    let index = &index;
+                    scope.spawn(move || {
+                        for point in chunk {
+                            index.add_point(point);
+                        }
+                    });
+                }
+                let index = &index;
+                let num_points = points.len();
+                scope.spawn(move || {
+                    let mut farthest = 0.0;
+                    while index.len() < num_points {
+                        let distance = index.snapshot().tree.get_farthest_distance();
+                        assert!(distance >= farthest);
+                        farthest = distance;
+                    }
+                });
+            });
+
+            // Points are indexed in the order they were added, which
+            // depends on how the threads interleaved
+            let index = index.into_inner();
+            assert_eq!(false, index.tree.is_empty());
+            let mut seen = vec![false; points.len()];
+            for (point, i) in index.tree.iter() {
+                assert_eq!(false, seen[i]);
+                seen[i] = true;
+                assert_eq!(0.0, brute_force_distance(&points, &point));
+            }
+            assert_eq!(true, seen.iter().all(|seen| *seen));
+        }
+    }
+}
@@ -0,0 +1,306 @@
+use anyhow::{anyhow, Result};
+
+use crate::grid::Grid;
+use crate::kd_tree::KdTree;
+use crate::point::Point;
+use crate::quad_tree::QuadTree;
+
+// A set of points that can be searched by position. Each point is identified
+// by its index, which is the order it was added in.
+pub trait SpatialIndex: Send + Sync {
+    fn add_point(&mut self, point: &Point);
+
+    // Returns the nearest point, its distance, and its index in insertion order.
+    fn get_nearest(&self, point: &Point) -> Option<(Point, f64, usize)>;
+
+    // Returns a lower bound on the distance from the given point to the
+    // nearest point, or infinity if there are no points. This should be much
+    // cheaper than get_nearest. By default it only uses the farthest distance.
+    fn get_distance_lower_bound(&self, point: &Point) -> f64 {
+        if self.is_empty() {
+            return f64::INFINITY;
+        }
+        return (point.distance(&Point::ZERO) - self.get_farthest_distance()).max(0.0);
+    }
+
+    // Returns the distance from the origin to the farthest point.
+    fn get_farthest_distance(&self) -> f64;
+
+    // Returns the number of points.
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        return self.len() == 0;
+    }
+
+    // Returns an iterator over all points, each with its index in insertion
+    // order, in no particular order.
+    fn iter(&self) -> Box<dyn Iterator<Item = (Point, usize)> + '_>;
+
+    // Returns this index as a quadtree, if that is what it is.
+    fn as_quad_tree(&self) -> Option<&QuadTree> {
+        return Option::None;
+    }
+
+    // Returns a copy of this index.
+    fn clone_box(&self) -> Box<dyn SpatialIndex>;
+}
+
+// The kinds of spatial index available.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Backend {
+    QuadTree,
+    Grid,
+    KdTree,
+}
+
+impl Backend {
+    pub fn from(name: &str) -> Result<Backend> {
+        return match name {
+            "quadtree" => Result::Ok(Backend::QuadTree),
+            "grid" => Result::Ok(Backend::Grid),
+            "kd-tree" => Result::Ok(Backend::KdTree),
+            _ => Result::Err(anyhow!(
+                "Expected 'quadtree', 'grid' or 'kd-tree' but found '{}'",
+                name
+            )),
+        };
+    }
+
+    // Make an index of this kind holding the given points, indexed in the
+    // order given.
+    pub fn build(&self, points: &[Point]) -> Box<dyn SpatialIndex> {
+        return match self {
+            Backend::QuadTree => Box::new(QuadTree::from_points(points)),
+            Backend::Grid => Box::new(Grid::from_points(points)),
+            Backend::KdTree => Box::new(KdTree::from_points(points)),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use super::Backend;
+    use crate::flake::Flake;
+    use crate::generate::{generate, RunOptions, Symmetry};
+    use crate::point::Point;
+    use crate::schedule::Schedule;
+    use crate::species::Species;
+    use crate::stop::{Combine, StopCondition, StopConditions};
+    use crate::test_utils::test::{time_func, with_test_dir};
+
+    impl Backend {
+        pub const ALL: [Backend; 3] = [Backend::QuadTree, Backend::Grid, Backend::KdTree];
+
+        pub fn name(&self) -> &'static str {
+            return match self {
+                Backend::QuadTree => "quadtree",
+                Backend::Grid => "grid",
+                Backend::KdTree => "kd-tree",
+            };
+        }
+    }
+
+    #[test]
+    fn parse_backend() {
+        for backend in Backend::ALL.iter() {
+            assert_eq!(true, Backend::from(backend.name()).unwrap() == *backend);
+        }
+        assert!(Backend::from("octree").is_err());
+    }
+
+    #[test]
+    fn backends_match_brute_force() {
+        let mut rng = StdRng::seed_from_u64(53);
+
+        // A dense clump, a sparse spread and a far outlier, split between
+        // points loaded up front and points added afterwards
+        let mut points: Vec<Point> = Vec::new();
+        for _i in 0..1000 {
+            points.push(Point {
+                x: rng.gen_range(-10.0..10.0),
+                y: rng.gen_range(-10.0..10.0),
+            });
+        }
+        for _i in 0..1000 {
+            points.push(Point {
+                x: rng.gen_range(-500.0..500.0),
+                y: rng.gen_range(-50.0..50.0),
+            });
+        }
+        points.push(Point { x: 3000.0, y: -2000.0 });
+        let farthest = points
+            .iter()
+            .map(|p| p.distance(&Point::ZERO))
+            .fold(0.0, f64::max);
+
+        for backend in Backend::ALL.iter() {
+            let mut index = backend.build(&points[..1200]);
+            assert_eq!(1200, index.len());
+            for point in &points[1200..] {
+                index.add_point(point);
+            }
+
+            assert_eq!(points.len(), index.len());
+            assert_eq!(false, index.is_empty());
+            assert_eq!(farthest, index.get_farthest_distance());
+
+            let mut seen = vec![false; points.len()];
+            for (point, i) in index.iter() {
+                assert_eq!(points[i].x, point.x);
+                assert_eq!(points[i].y, point.y);
+                seen[i] = true;
+            }
+            assert_eq!(true, seen.iter().all(|seen| *seen));
+
+            for _i in 0..1000 {
+                let query_point = Point {
+                    x: rng.gen_range(-4000.0..4000.0),
+                    y: rng.gen_range(-4000.0..4000.0) * rng.gen_range(0.0..1.0f64).powf(4.0),
+                };
+                let expected = points
+                    .iter()
+                    .map(|p| p.distance(&query_point))
+                    .fold(f64::INFINITY, f64::min);
+                let (point, distance, i) = index.get_nearest(&query_point).unwrap();
+                assert_eq!(expected, distance);
+                assert_eq!(points[i].x, point.x);
+                assert!(index.get_distance_lower_bound(&query_point) <= distance);
+            }
+        }
+    }
+
+    #[test]
+    fn empty_backends() {
+        for backend in Backend::ALL.iter() {
+            let mut index = backend.build(&[]);
+            assert_eq!(true, index.is_empty());
+            assert_eq!(true, index.get_nearest(&Point::ZERO).is_none());
+            assert_eq!(f64::INFINITY, index.get_distance_lower_bound(&Point::ZERO));
+            assert_eq!(0, index.iter().count());
+
+            index.add_point(&Point { x: 1.0, y: 0.0 });
+            assert_eq!(1, index.len());
+            assert_eq!(0, index.get_nearest(&Point::ZERO).unwrap().2);
+        }
+    }
+
+    /*
+     * Last recorded performance:
+     *
+     * Time to generate 100000 particles with the quadtree backend: 1.231648743s
+     * Time to generate 100000 particles with the grid backend: 1.371044125s
+     * Time to generate 100000 particles with the kd-tree backend: 3.848511655s
+     *
+     * quadtree backend:
+     * Time per point to insert: 154ns
+     * Time per point to bulk load: 132ns
+     * Time per query after inserting: 739ns
+     * Time per query after bulk loading: 746ns
+     *
+     * grid backend:
+     * Time per point to insert: 94ns
+     * Time per point to bulk load: 88ns
+     * Time per query after inserting: 1.111µs
+     * Time per query after bulk loading: 1.14µs
+     *
+     * kd-tree backend:
+     * Time per point to insert: 1.421µs
+     * Time per point to bulk load: 200ns
+     * Time per query after inserting: 3.352µs
+     * Time per query after bulk loading: 847ns
+     *
+     * Flakes grow outwards, so k-d trees built by insertion keep having
+     * lopsided subtrees rebuilt, and are still deeper than bulk loaded ones.
+     */
+    #[test]
+    #[ignore]
+    fn dla_workload_perf() {
+        with_test_dir(|test_dir: &str| {
+            let num_particles = 100000;
+            let stop = StopConditions::new(
+                vec![StopCondition::NumParticles(num_particles)],
+                Combine::Any,
+            );
+
+            // Whole generation runs, which mostly query close to the flake
+            for backend in Backend::ALL.iter() {
+                let flake_file = format!("{}/{}.flake", test_dir, backend.name());
+                let mut flake = Flake::new(&flake_file).expect("Unable to open flake");
+                let generate_time = time_func(|| {
+                    generate(
+                        &mut flake,
+                        Symmetry::None,
+                        &Schedule::default(),
+                        &Species::defaults(),
+                        &stop,
+                        &RunOptions {
+                            record_time: false,
+                            backend: *backend,
+                            seed: Option::None,
+                            threads: 1,
+                        },
+                    )
+                    .expect("Unable to generate flake");
+                });
+                println!(
+                    "Time to generate {} particles with the {} backend: {:?}",
+                    num_particles,
+                    backend.name(),
+                    generate_time
+                );
+            }
+            println!();
+
+            // Individual operations on the points of a real flake, which are
+            // dense near the center and sparse between the arms
+            let flake =
+                Flake::new(&format!("{}/quadtree.flake", test_dir)).expect("Unable to open flake");
+            let points = flake.get_points().expect("Unable to get points");
+            let num_points = points.len() as u32;
+            let mut rng = StdRng::seed_from_u64(61);
+            let query_points: Vec<Point> = (0..100000)
+                .map(|_i| {
+                    let near = &points[rng.gen_range(0..points.len())];
+                    Point {
+                        x: near.x + rng.gen_range(-8.0..8.0),
+                        y: near.y + rng.gen_range(-8.0..8.0),
+                    }
+                })
+                .collect();
+
+            for backend in Backend::ALL.iter() {
+                let mut index = backend.build(&[]);
+                let insert_time = time_func(|| {
+                    for point in &points {
+                        index.add_point(point);
+                    }
+                });
+                let mut bulk_index = backend.build(&[]);
+                let build_time = time_func(|| {
+                    bulk_index = backend.build(&points);
+                });
+                let num_queries = query_points.len() as u32;
+                let query_time = time_func(|| {
+                    for query_point in &query_points {
+                        assert!(index.get_nearest(query_point).unwrap().1 >= 0.0);
+                    }
+                });
+                let bulk_query_time = time_func(|| {
+                    for query_point in &query_points {
+                        assert!(bulk_index.get_nearest(query_point).unwrap().1 >= 0.0);
+                    }
+                });
+                println!("{} backend:", backend.name());
+                println!("Time per point to insert: {:?}", insert_time / num_points);
+                println!("Time per point to bulk load: {:?}", build_time / num_points);
+                println!("Time per query after inserting: {:?}", query_time / num_queries);
+                println!("Time per query after bulk loading: {:?}", bulk_query_time / num_queries);
+                println!();
+            }
+        });
+    }
+}
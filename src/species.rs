@@ -0,0 +1,168 @@
+use std::fs;
+
+use anyhow::{anyhow, Context, Result};
+
+// The most species a flake can contain, as each particle records its species in a byte.
+const MAX_SPECIES: usize = 256;
+
+// A kind of particle, with its own growth properties.
+pub struct Species {
+    pub name: String,
+    // Relative rate at which walkers of this species are released.
+    pub rate: f64,
+    // Multiplier on the scheduled sticking probability.
+    pub sticking_probability: f64,
+    // Multiplier on the scheduled particle diameter.
+    pub diameter: f64,
+    pub colour: [u8; 3],
+}
+
+impl Species {
+    fn new(name: &str) -> Species {
+        return Species {
+            name: name.to_string(),
+            rate: 1.0,
+            sticking_probability: 1.0,
+            diameter: 1.0,
+            colour: [255, 255, 255],
+        };
+    }
+
+    // The species used when none are configured: a single white species
+    // that uses the scheduled parameters unchanged.
+    pub fn defaults() -> Vec<Species> {
+        return vec![Species::new("default")];
+    }
+
+    // Read a list of species from a config file. See parse for the format.
+    pub fn from_file(species_file: &str) -> Result<Vec<Species>> {
+        let contents = fs::read_to_string(species_file)
+            .context(format!("Unable to read species file: {}", species_file))?;
+        return Species::parse(&contents)
+            .context(format!("Unable to parse species file: {}", species_file));
+    }
+
+    // Parse a list of species. Each non-empty line that isn't a comment has the form
+    //   <name> [rate=<num>] [sticking-probability=<num>] [diameter=<num>] [colour=#rrggbb]
+    // Species are numbered in the order they appear.
+    pub fn parse(contents: &str) -> Result<Vec<Species>> {
+        let mut species_list: Vec<Species> = Vec::new();
+
+        for (line_number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut parts = line.split_whitespace();
+            let mut species = Species::new(parts.next().unwrap());
+            for part in parts {
+                species
+                    .set_property(part)
+                    .context(format!("Invalid species on line {}", line_number + 1))?;
+            }
+
+            if species_list.iter().any(|other| other.name == species.name) {
+                return Result::Err(anyhow!("Species '{}' is defined more than once", species.name));
+            }
+            species_list.push(species);
+        }
+
+        if species_list.is_empty() {
+            return Result::Err(anyhow!("At least one species is required"));
+        }
+        if species_list.len() > MAX_SPECIES {
+            return Result::Err(anyhow!("At most {} species are supported", MAX_SPECIES));
+        }
+        if species_list.iter().all(|species| species.rate == 0.0) {
+            return Result::Err(anyhow!("At least one species must have a non-zero rate"));
+        }
+
+        return Result::Ok(species_list);
+    }
+
+    fn set_property(&mut self, property: &str) -> Result<()> {
+        let (key, value) = property
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Expected <property>=<value> but found '{}'", property))?;
+
+        if key == "colour" {
+            self.colour = parse_colour(value)?;
+            return Result::Ok(());
+        }
+
+        let number = value
+            .parse::<f64>()
+            .context(format!("Unable to parse '{}' as a number", value))?;
+        if !number.is_finite() || number < 0.0 {
+            return Result::Err(anyhow!("Property '{}' must be a non-negative number", key));
+        }
+        match key {
+            "rate" => self.rate = number,
+            // A walker that can never stick, or that has no size, would walk forever
+            "sticking-probability" | "diameter" if number == 0.0 => {
+                return Result::Err(anyhow!("Property '{}' must be a positive number", key))
+            }
+            "sticking-probability" => self.sticking_probability = number,
+            "diameter" => self.diameter = number,
+            _ => return Result::Err(anyhow!("Unknown property '{}'", key)),
+        };
+        return Result::Ok(());
+    }
+}
+
+fn parse_colour(value: &str) -> Result<[u8; 3]> {
+    let hex = value.strip_prefix('#').unwrap_or(value);
+    if hex.len() != 6 || !hex.is_ascii() {
+        return Result::Err(anyhow!("Expected a colour of the form #rrggbb but found '{}'", value));
+    }
+    let mut colour = [0; 3];
+    for (i, channel) in colour.iter_mut().enumerate() {
+        *channel = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+            .context(format!("Unable to parse colour '{}'", value))?;
+    }
+    return Result::Ok(colour);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Species;
+
+    #[test]
+    fn parse_species() {
+        let species = Species::parse(
+            "# two component aggregation\n\
+             ice\n\
+             soot rate=0.25 sticking-probability=0.5 diameter=2 colour=#ff8000\n",
+        )
+        .expect("Unable to parse species");
+
+        assert_eq!(2, species.len());
+        assert_eq!("ice", species[0].name);
+        assert_eq!(1.0, species[0].rate);
+        assert_eq!([255, 255, 255], species[0].colour);
+
+        assert_eq!("soot", species[1].name);
+        assert_eq!(0.25, species[1].rate);
+        assert_eq!(0.5, species[1].sticking_probability);
+        assert_eq!(2.0, species[1].diameter);
+        assert_eq!([255, 128, 0], species[1].colour);
+    }
+
+    #[test]
+    fn rejects_invalid_species() {
+        assert!(Species::parse("").is_err());
+        assert!(Species::parse("ice\nice").is_err());
+        assert!(Species::parse("ice rate=0").is_err());
+        assert!(Species::parse("ice rate=-1").is_err());
+        assert!(Species::parse("ice rate=NaN").is_err());
+        assert!(Species::parse("ice temperature=1").is_err());
+        assert!(Species::parse("ice colour=#fff").is_err());
+        assert!(Species::parse("ice colour=#gggggg").is_err());
+        assert!(Species::parse("ice diameter").is_err());
+        assert!(Species::parse("ice sticking-probability=0").is_err());
+        assert!(Species::parse("ice diameter=0").is_err());
+        assert!(Species::parse("ice colour=#aébé").is_err());
+        assert!(Species::parse("ice rate=0\nsoot rate=1").is_ok());
+    }
+}
@@ -0,0 +1,220 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::generate::POINT_DIAMETER;
+use crate::point::Point;
+
+// The number of particles added when no stop conditions are given.
+const DEFAULT_NUM_PARTICLES: u32 = 1000;
+
+// Below this many particles the fractal dimension estimate is too noisy to use.
+const MIN_PARTICLES_FOR_DIMENSION: usize = 1000;
+
+pub enum StopCondition {
+    // Stop once this many particles have been added by this run.
+    NumParticles(u32),
+    // Stop once the farthest particle is at least this far from the center.
+    Radius(f64),
+    // Stop once generation has been running for this long.
+    Time(Duration),
+    // Stop once the estimated fractal dimension has fallen to this value.
+    FractalDimension(f64),
+    // Stop once a particle added by this run reaches the edge of a box centred
+    // on the origin with the given half width and half height.
+    Boundary(Point),
+}
+
+// How to combine several stop conditions.
+#[derive(Clone, Copy)]
+pub enum Combine {
+    Any,
+    All,
+}
+
+// The state of generation, used to decide whether to stop.
+pub struct Progress {
+    // The number of particles added by this run.
+    pub particles_added: u32,
+    // The total number of particles in the flake.
+    pub num_particles: usize,
+    pub radius: f64,
+    pub elapsed: Duration,
+    // The largest absolute x and y coordinates of particles in the flake.
+    pub extent: Point,
+}
+
+pub struct StopConditions {
+    conditions: Vec<StopCondition>,
+    combine: Combine,
+}
+
+impl StopCondition {
+    fn is_met(&self, progress: &Progress) -> bool {
+        return match self {
+            StopCondition::NumParticles(n) => progress.particles_added >= *n,
+            StopCondition::Radius(radius) => progress.radius >= *radius,
+            StopCondition::Time(time) => progress.elapsed >= *time,
+            StopCondition::FractalDimension(dimension) => {
+                match estimate_fractal_dimension(progress.num_particles, progress.radius) {
+                    Option::Some(estimate) => estimate <= *dimension,
+                    Option::None => false,
+                }
+            }
+            StopCondition::Boundary(half_size) => {
+                progress.extent.x >= half_size.x || progress.extent.y >= half_size.y
+            }
+        };
+    }
+
+    // Parse a boundary box given as <width>x<height>.
+    pub fn parse_boundary(size: &str) -> Result<StopCondition> {
+        let (width, height) = size
+            .split_once('x')
+            .ok_or_else(|| anyhow!("Expected <width>x<height> but found '{}'", size))?;
+        let width = width
+            .parse::<f64>()
+            .context(format!("Unable to parse '{}' as a number", width))?;
+        let height = height
+            .parse::<f64>()
+            .context(format!("Unable to parse '{}' as a number", height))?;
+        return Result::Ok(StopCondition::Boundary(Point {
+            x: width / 2.0,
+            y: height / 2.0,
+        }));
+    }
+}
+
+impl Combine {
+    pub fn from(name: &str) -> Result<Combine> {
+        return match name {
+            "any" => Result::Ok(Combine::Any),
+            "all" => Result::Ok(Combine::All),
+            _ => Result::Err(anyhow!("Expected 'any' or 'all' but found '{}'", name)),
+        };
+    }
+}
+
+impl StopConditions {
+    // Combine the given conditions. With no conditions, generation stops
+    // after a fixed number of particles.
+    pub fn new(conditions: Vec<StopCondition>, combine: Combine) -> StopConditions {
+        if conditions.is_empty() {
+            return StopConditions {
+                conditions: vec![StopCondition::NumParticles(DEFAULT_NUM_PARTICLES)],
+                combine,
+            };
+        }
+        return StopConditions {
+            conditions,
+            combine,
+        };
+    }
+
+    pub fn should_stop(&self, progress: &Progress) -> bool {
+        return match self.combine {
+            Combine::Any => self.conditions.iter().any(|c| c.is_met(progress)),
+            Combine::All => self.conditions.iter().all(|c| c.is_met(progress)),
+        };
+    }
+}
+
+// Estimate the fractal dimension of a flake from the relationship between
+// its mass and its radius, N ~ (2R / d)^D. A solid disc gives 2.0 and a
+// straight line gives 1.0. Returns None for flakes too small to estimate.
+pub fn estimate_fractal_dimension(num_particles: usize, radius: f64) -> Option<f64> {
+    let scale = 2.0 * radius / POINT_DIAMETER;
+    if num_particles < MIN_PARTICLES_FOR_DIMENSION || scale <= 1.0 {
+        return Option::None;
+    }
+    return Option::Some((num_particles as f64).ln() / scale.ln());
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{estimate_fractal_dimension, Combine, Progress, StopCondition, StopConditions};
+    use crate::point::Point;
+
+    fn progress(particles_added: u32, radius: f64, seconds: u64, extent: f64) -> Progress {
+        return Progress {
+            particles_added,
+            num_particles: particles_added as usize + 1,
+            radius,
+            elapsed: Duration::from_secs(seconds),
+            extent: Point {
+                x: extent,
+                y: 0.0,
+            },
+        };
+    }
+
+    #[test]
+    fn default_is_fixed_count() {
+        let stop = StopConditions::new(Vec::new(), Combine::Any);
+        assert_eq!(false, stop.should_stop(&progress(999, 0.0, 0, 0.0)));
+        assert_eq!(true, stop.should_stop(&progress(1000, 0.0, 0, 0.0)));
+    }
+
+    #[test]
+    fn single_conditions() {
+        let radius = StopConditions::new(vec![StopCondition::Radius(50.0)], Combine::Any);
+        assert_eq!(false, radius.should_stop(&progress(0, 49.0, 0, 0.0)));
+        assert_eq!(true, radius.should_stop(&progress(0, 50.0, 0, 0.0)));
+
+        let time = StopConditions::new(
+            vec![StopCondition::Time(Duration::from_secs(10))],
+            Combine::Any,
+        );
+        assert_eq!(false, time.should_stop(&progress(0, 0.0, 9, 0.0)));
+        assert_eq!(true, time.should_stop(&progress(0, 0.0, 10, 0.0)));
+
+        let boundary = StopConditions::new(
+            vec![StopCondition::parse_boundary("200x100").expect("Unable to parse box")],
+            Combine::Any,
+        );
+        assert_eq!(false, boundary.should_stop(&progress(0, 0.0, 0, 99.0)));
+        assert_eq!(true, boundary.should_stop(&progress(0, 0.0, 0, 100.0)));
+    }
+
+    #[test]
+    fn combined_conditions() {
+        let conditions = || {
+            vec![
+                StopCondition::NumParticles(100),
+                StopCondition::Radius(10.0),
+            ]
+        };
+
+        let any = StopConditions::new(conditions(), Combine::Any);
+        assert_eq!(false, any.should_stop(&progress(50, 5.0, 0, 0.0)));
+        assert_eq!(true, any.should_stop(&progress(100, 5.0, 0, 0.0)));
+        assert_eq!(true, any.should_stop(&progress(50, 10.0, 0, 0.0)));
+
+        let all = StopConditions::new(conditions(), Combine::All);
+        assert_eq!(false, all.should_stop(&progress(100, 5.0, 0, 0.0)));
+        assert_eq!(false, all.should_stop(&progress(50, 10.0, 0, 0.0)));
+        assert_eq!(true, all.should_stop(&progress(100, 10.0, 0, 0.0)));
+    }
+
+    #[test]
+    fn fractal_dimension() {
+        assert_eq!(true, estimate_fractal_dimension(10, 100.0).is_none());
+
+        // A solid disc of radius 100 holds about 40000 particles
+        let disc = estimate_fractal_dimension(40000, 100.0).unwrap();
+        assert!(disc > 1.95 && disc < 2.05);
+
+        let stop = StopConditions::new(vec![StopCondition::FractalDimension(1.8)], Combine::Any);
+        assert_eq!(false, stop.should_stop(&progress(39999, 100.0, 0, 0.0)));
+        assert_eq!(true, stop.should_stop(&progress(4999, 100.0, 0, 0.0)));
+    }
+
+    #[test]
+    fn parse_invalid_boundary() {
+        assert!(StopCondition::parse_boundary("200").is_err());
+        assert!(StopCondition::parse_boundary("200xa").is_err());
+        assert!(Combine::from("some").is_err());
+    }
+}